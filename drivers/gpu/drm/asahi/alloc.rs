@@ -205,6 +205,16 @@ fn garbage(&self) -> (usize, usize) {
     }
     /// Collect garbage for this allocator, up to the given object count. Optional.
     fn collect_garbage(&mut self, _count: usize) {}
+    /// Returns the GPU VA range `(start, end)` spanned by the garbage objects that
+    /// `collect_garbage()` would collect for the given object count, if known. Optional.
+    fn garbage_range(&self, _count: usize) -> Option<(u64, u64)> {
+        None
+    }
+    /// Release backing memory that is no longer in use by any object, returning the number of
+    /// bytes released. Objects themselves are never moved. Optional.
+    fn trim(&mut self) -> usize {
+        0
+    }
 
     /// Allocate a new GpuStruct object. See [`GpuObject::new`].
     #[inline(never)]
@@ -672,8 +682,9 @@ impl RawAllocation for HeapAllocation {
     // SAFETY: This function must always return a valid pointer.
     // Since the HeapAllocation contains a reference to the
     // backing_objects array that contains the object backing this pointer,
-    // and objects are only ever added to it, this pointer is guaranteed to
-    // remain valid for the lifetime of the HeapAllocation.
+    // and objects are only removed from it once no nodes remain within their
+    // range (see trim()), this pointer is guaranteed to remain valid for the
+    // lifetime of the HeapAllocation.
     fn ptr(&self) -> Option<NonNull<u8>> {
         self.0.as_ref().unwrap().ptr
     }
@@ -705,8 +716,10 @@ struct HeapAllocatorInner {
 
 /// A heap allocator which uses the DRM MM range allocator to manage its objects.
 ///
-/// The heap is composed of a series of GEM objects. This implementation only ever grows the heap,
-/// never shrinks it.
+/// The heap is composed of a series of GEM objects. The heap grows on demand, and trailing backing
+/// objects that no longer contain any allocations (including garbage) can be released with
+/// `trim()`. Allocations are never moved, since their GPU addresses are embedded in firmware
+/// structures.
 pub(crate) struct HeapAllocator {
     dev: AsahiDevRef,
     start: u64,
@@ -1062,6 +1075,91 @@ fn collect_garbage(&mut self, count: usize) {
             }
         });
     }
+
+    fn garbage_range(&self, count: usize) -> Option<(u64, u64)> {
+        self.mm.with_inner(|inner| {
+            inner
+                .garbage
+                .as_ref()?
+                .iter()
+                .take(count)
+                .map(|node| (node.start(), node.start() + node.size()))
+                .reduce(|(a_start, a_end), (b_start, b_end)| {
+                    (a_start.min(b_start), a_end.max(b_end))
+                })
+        })
+    }
+
+    fn trim(&mut self) -> usize {
+        let mut released = 0;
+
+        while let Some(size) = self.trim_block() {
+            released += size;
+        }
+
+        if released > 0 {
+            cls_dev_dbg!(
+                MemStats,
+                &self.dev,
+                "{} Heap: shrink to {} bytes\n",
+                &*self.name,
+                self.top - self.start
+            );
+        }
+
+        released
+    }
+}
+
+impl HeapAllocator {
+    /// Release the last backing object if it contains no allocations, returning its size.
+    ///
+    /// The first backing object is always kept, to avoid repeatedly freeing and reallocating it
+    /// when the heap is mostly idle.
+    fn trim_block(&mut self) -> Option<usize> {
+        let (gpu_ptr, size) = self.mm.with_inner(|inner| {
+            if inner.backing_objects.len() > 1 {
+                inner
+                    .backing_objects
+                    .last()
+                    .map(|(obj, gpu_ptr)| (*gpu_ptr, obj.size()))
+            } else {
+                None
+            }
+        })?;
+
+        // If the whole block can be reserved, then no live or garbage nodes remain within it.
+        // Hold the reservation until the block is unmapped.
+        let inner = HeapAllocationInner {
+            dev: self.dev.clone(),
+            ptr: None,
+            real_size: 0,
+        };
+        let _reservation = self.mm.reserve_node(inner, gpu_ptr, size as u64, 0).ok()?;
+
+        mod_dev_dbg!(
+            &self.dev,
+            "HeapAllocator[{}]::trim_block: Releasing {:#x}:{:#x}\n",
+            &*self.name,
+            gpu_ptr,
+            size
+        );
+
+        if self.cpu_maps {
+            // The guard node for this block is the last one, drop it outside the mm lock
+            self.guard_nodes.pop();
+        }
+
+        let (mut obj, _) = self
+            .mm
+            .with_inner(|inner| inner.backing_objects.pop())
+            .expect("Backing object disappeared");
+        obj.drop_vm_mappings(self.vm.id());
+
+        self.top = gpu_ptr;
+
+        Some(size)
+    }
 }
 
 impl Drop for HeapAllocatorInner {
@@ -1080,8 +1178,10 @@ fn drop(&mut self) {
                 self.allocated
             );
         } else {
+            // This happens in bulk when queues and files are torn down, so do not block on a
+            // firmware cache flush for every backing object.
             for mut obj in self.backing_objects.drain(..) {
-                obj.0.drop_vm_mappings(self.vm_id);
+                obj.0.drop_vm_mappings_deferred(self.vm_id);
             }
         }
     }
@@ -4,6 +4,7 @@
 //! Driver for the Apple AGX GPUs found in Apple Silicon SoCs.
 
 mod alloc;
+mod audit;
 mod buffer;
 mod channel;
 mod debug;
@@ -48,5 +49,60 @@
             permissions: 0o644,
             description: "Initial TVB size in blocks",
         },
+        tvb_grow_blocks: usize {
+            default: 1,
+            permissions: 0o644,
+            description: "Minimum number of blocks added per TVB auto-grow event (1-128)",
+        },
+        user_mapped_limit_mb: u64 {
+            default: 0,
+            permissions: 0o644,
+            description: "Limit on total memory mapped into all user GPU VMs (MiB, 0: unlimited)",
+        },
+        mtiles_x: u32 {
+            default: 0,
+            permissions: 0o644,
+            description: "Macrotile columns to use (debug, 0: default, 1-2: override)",
+        },
+        mtiles_y: u32 {
+            default: 0,
+            permissions: 0o644,
+            description: "Macrotile rows to use (debug, 0: default, 1-2: override)",
+        },
+        garbage_flush_delay_ms: u32 {
+            default: 0,
+            permissions: 0o644,
+            description: "Delay before collecting FW allocator garbage past the threshold (ms)",
+        },
+        max_inflight_submissions: u32 {
+            default: 512,
+            permissions: 0o644,
+            description: "Maximum in-flight submissions per client (0: unlimited)",
+        },
+        doorbell_coalesce_us: u32 {
+            default: 0,
+            permissions: 0o644,
+            description: "Delay before handling a firmware doorbell, to batch events (us, 0-1000)",
+        },
+        audit_log: bool {
+            default: false,
+            permissions: 0o644,
+            description: "Record submitted commands in an audit log for privileged readers",
+        },
+        trace_submission: u64 {
+            default: 0,
+            permissions: 0o644,
+            description: "Submission ID to log verbosely through its whole lifecycle (0: disabled)",
+        },
+        fault_isolation: bool {
+            default: false,
+            permissions: 0o644,
+            description: "On GPU faults, only fail work from the faulting VM (experimental)",
+        },
+        max_bound_vms: u32 {
+            default: 0,
+            permissions: 0o644,
+            description: "Maximum user VMs bound to GPU slots at once (0: no limit beyond the 63 slots)",
+        },
     },
 }
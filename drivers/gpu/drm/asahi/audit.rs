@@ -0,0 +1,99 @@
+// SPDX-License-Identifier: GPL-2.0-only OR MIT
+
+//! Submission audit log
+//!
+//! When enabled with the `audit_log` module parameter, every submitted command is recorded into a
+//! bounded ring buffer, along with the client that submitted it and the GPU addresses it
+//! references. Command contents are never recorded. The log can only be read back by privileged
+//! processes, using the AUDIT_READ ioctl.
+//!
+//! The ring buffer is only allocated once the log is first enabled, and the oldest records are
+//! overwritten once it is full. Every record has a sequence number, so readers can tell when they
+//! missed records.
+
+use kernel::{bindings, prelude::*, uapi};
+
+/// Number of records kept in the audit log.
+const AUDIT_LOG_SIZE: usize = 512;
+
+/// Maximum number of GPU addresses recorded per command.
+pub(crate) const MAX_ADDRS: usize = uapi::DRM_ASAHI_AUDIT_MAX_ADDRS as usize;
+
+/// Returns whether submissions should be audited.
+pub(crate) fn enabled() -> bool {
+    let lock = crate::THIS_MODULE.kernel_param_lock();
+    *crate::audit_log.read(&lock)
+}
+
+/// A bounded log of audit records.
+pub(crate) struct AuditLog {
+    /// Ring buffer of records, indexed by sequence number. Empty until first used.
+    records: Vec<uapi::drm_asahi_audit_record>,
+    /// Sequence number of the oldest record still in the log.
+    first_seq: u64,
+    /// Sequence number of the next record.
+    next_seq: u64,
+}
+
+impl AuditLog {
+    /// Create a new, empty audit log. No memory is allocated until the first record.
+    pub(crate) fn new() -> AuditLog {
+        AuditLog {
+            records: Vec::new(),
+            first_seq: 0,
+            next_seq: 0,
+        }
+    }
+
+    /// Append a record to the log, filling in its sequence number and timestamp.
+    ///
+    /// If the ring buffer cannot be allocated, the record is dropped but still consumes a
+    /// sequence number, so readers see it as lost.
+    pub(crate) fn record(&mut self, mut record: uapi::drm_asahi_audit_record) {
+        record.seq = self.next_seq;
+        // SAFETY: ktime_get() is always safe to call.
+        record.timestamp_ns = unsafe { bindings::ktime_get() } as u64;
+        self.next_seq += 1;
+
+        if self.records.is_empty() && self.alloc().is_err() {
+            self.first_seq = self.next_seq;
+            return;
+        }
+
+        self.records[(record.seq as usize) % AUDIT_LOG_SIZE] = record;
+        self.first_seq = self
+            .first_seq
+            .max(self.next_seq.saturating_sub(AUDIT_LOG_SIZE as u64));
+    }
+
+    /// Allocate the ring buffer.
+    fn alloc(&mut self) -> Result {
+        let mut records = Vec::try_with_capacity(AUDIT_LOG_SIZE)?;
+        for _ in 0..AUDIT_LOG_SIZE {
+            records.try_push(Default::default())?;
+        }
+        self.records = records;
+        Ok(())
+    }
+
+    /// Read up to `max` records, starting at sequence number `seq`.
+    ///
+    /// Returns the records, the sequence number to continue reading from, and the number of
+    /// records that were skipped because they had already been overwritten.
+    pub(crate) fn read(
+        &self,
+        seq: u64,
+        max: usize,
+    ) -> Result<(Vec<uapi::drm_asahi_audit_record>, u64, u64)> {
+        let start = seq.clamp(self.first_seq, self.next_seq);
+        let lost = start.saturating_sub(seq);
+        let count = ((self.next_seq - start) as usize).min(max);
+
+        let mut ret = Vec::try_with_capacity(count)?;
+        for s in start..start + count as u64 {
+            ret.try_push(self.records[(s as usize) % AUDIT_LOG_SIZE])?;
+        }
+
+        Ok((ret, start + count as u64, lost))
+    }
+}
@@ -56,6 +56,9 @@
 /// Size of a buffer block.
 pub(crate) const BLOCK_SIZE: usize = PAGE_SIZE * PAGES_PER_BLOCK;
 
+/// Upper bound for the `tvb_grow_blocks` module parameter (16 MiB per grow).
+const MAX_GROW_BLOCKS: usize = 128;
+
 /// Metadata about the tiling configuration for a scene. This is computed in the `render` module.
 /// based on dimensions, tile size, and other info.
 pub(crate) struct TileInfo {
@@ -299,6 +302,67 @@ struct BufferInner {
     num_clusters: usize,
 }
 
+#[versions(AGX)]
+impl BufferInner::ver {
+    /// Ensure that the buffer has at least a certain minimum size in blocks.
+    ///
+    /// This must be called with the `Buffer` lock held for the entire check and grow sequence,
+    /// so that concurrent growth requests are serialized.
+    fn ensure_blocks(&mut self, min_blocks: usize) -> Result<bool> {
+        let cur_count = self.blocks.len();
+        if cur_count >= min_blocks {
+            return Ok(false);
+        }
+        if min_blocks > self.max_blocks {
+            return Err(ENOMEM);
+        }
+
+        let add_blocks = min_blocks - cur_count;
+        let new_count = min_blocks;
+
+        let mut new_blocks: Vec<GpuOnlyArray<u8>> = Vec::new();
+
+        // Allocate the new blocks first, so if it fails they will be dropped
+        let mut ualloc = self.ualloc.lock();
+        for _i in 0..add_blocks {
+            new_blocks.try_push(ualloc.array_gpuonly(BLOCK_SIZE)?)?;
+        }
+        core::mem::drop(ualloc);
+
+        // Then actually commit them
+        self.blocks.try_reserve(add_blocks)?;
+
+        for (i, block) in new_blocks.into_iter().enumerate() {
+            let page_num = (block.gpu_va().get() >> PAGE_SHIFT) as u32;
+
+            self.blocks
+                .try_push(block)
+                .expect("try_push() failed after try_reserve()");
+            self.info.block_list[2 * (cur_count + i)] = page_num;
+            for j in 0..PAGES_PER_BLOCK {
+                self.info.page_list[(cur_count + i) * PAGES_PER_BLOCK + j] = page_num + j as u32;
+            }
+        }
+
+        self.info.block_ctl.with(|raw, _inner| {
+            raw.total.store(new_count as u32, Ordering::SeqCst);
+            raw.wptr.store(new_count as u32, Ordering::SeqCst);
+        });
+
+        /* Only do this update if the buffer manager is idle (which means we own it) */
+        if self.active_scenes == 0 {
+            let page_count = (new_count * PAGES_PER_BLOCK) as u32;
+            self.info.with(|raw, _inner| {
+                raw.page_count.store(page_count, Ordering::Relaxed);
+                raw.block_count.store(new_count as u32, Ordering::Relaxed);
+                raw.last_page.store(page_count - 1, Ordering::Relaxed);
+            });
+        }
+
+        Ok(true)
+    }
+}
+
 /// Locked and reference counted TVB buffer.
 #[versions(AGX)]
 pub(crate) struct Buffer {
@@ -432,8 +496,18 @@ pub(crate) fn size(&self) -> usize {
     }
 
     /// Automatically grow the Buffer based on feedback from the statistics.
+    ///
+    /// Returns whether the buffer actually grew. The statistics check and the growth are done
+    /// under the same lock, so concurrent callers never grow the buffer twice for the same
+    /// feedback.
     pub(crate) fn auto_grow(&self) -> Result<bool> {
-        let inner = self.inner.lock();
+        let grow_blocks = {
+            let lock = crate::THIS_MODULE.kernel_param_lock();
+            *crate::tvb_grow_blocks.read(&lock)
+        }
+        .clamp(1, MAX_GROW_BLOCKS);
+
+        let mut inner = self.inner.lock();
 
         let used_pages = inner.stats.with(|raw, _inner| {
             let used = raw.max_pages.load(Ordering::Relaxed);
@@ -442,87 +516,38 @@ pub(crate) fn auto_grow(&self) -> Result<bool> {
         });
 
         let need_blocks = div_ceil(used_pages * 2, PAGES_PER_BLOCK).min(inner.max_blocks_nomemless);
-        let want_blocks = div_ceil(used_pages * 3, PAGES_PER_BLOCK).min(inner.max_blocks_nomemless);
+        let want_blocks = div_ceil(used_pages * 3, PAGES_PER_BLOCK);
 
         let cur_count = inner.blocks.len();
 
         if need_blocks <= cur_count {
             Ok(false)
         } else {
-            // Grow to 3x requested size (same logic as macOS)
-            core::mem::drop(inner);
-            self.ensure_blocks(want_blocks)?;
-            Ok(true)
+            // Grow to 3x requested size (same logic as macOS), but by at least the configured
+            // increment, so workloads that keep creeping up don't stall on every grow.
+            let target = want_blocks
+                .max(cur_count + grow_blocks)
+                .min(inner.max_blocks_nomemless);
+            inner.ensure_blocks(target)
         }
     }
 
     /// Synchronously grow the Buffer.
     pub(crate) fn sync_grow(&self) {
-        let inner = self.inner.lock();
+        let mut inner = self.inner.lock();
 
         let cur_count = inner.blocks.len();
-        core::mem::drop(inner);
-        if self.ensure_blocks(cur_count + 10).is_err() {
+        if inner.ensure_blocks(cur_count + 10).is_err() {
             pr_err!("BufferManager: Failed to grow buffer synchronously\n");
         }
     }
 
     /// Ensure that the buffer has at least a certain minimum size in blocks.
+    ///
+    /// Returns whether the buffer grew. If another caller already grew the buffer to at least
+    /// `min_blocks`, this returns `false`.
     pub(crate) fn ensure_blocks(&self, min_blocks: usize) -> Result<bool> {
-        let mut inner = self.inner.lock();
-
-        let cur_count = inner.blocks.len();
-        if cur_count >= min_blocks {
-            return Ok(false);
-        }
-        if min_blocks > inner.max_blocks {
-            return Err(ENOMEM);
-        }
-
-        let add_blocks = min_blocks - cur_count;
-        let new_count = min_blocks;
-
-        let mut new_blocks: Vec<GpuOnlyArray<u8>> = Vec::new();
-
-        // Allocate the new blocks first, so if it fails they will be dropped
-        let mut ualloc = inner.ualloc.lock();
-        for _i in 0..add_blocks {
-            new_blocks.try_push(ualloc.array_gpuonly(BLOCK_SIZE)?)?;
-        }
-        core::mem::drop(ualloc);
-
-        // Then actually commit them
-        inner.blocks.try_reserve(add_blocks)?;
-
-        for (i, block) in new_blocks.into_iter().enumerate() {
-            let page_num = (block.gpu_va().get() >> PAGE_SHIFT) as u32;
-
-            inner
-                .blocks
-                .try_push(block)
-                .expect("try_push() failed after try_reserve()");
-            inner.info.block_list[2 * (cur_count + i)] = page_num;
-            for j in 0..PAGES_PER_BLOCK {
-                inner.info.page_list[(cur_count + i) * PAGES_PER_BLOCK + j] = page_num + j as u32;
-            }
-        }
-
-        inner.info.block_ctl.with(|raw, _inner| {
-            raw.total.store(new_count as u32, Ordering::SeqCst);
-            raw.wptr.store(new_count as u32, Ordering::SeqCst);
-        });
-
-        /* Only do this update if the buffer manager is idle (which means we own it) */
-        if inner.active_scenes == 0 {
-            let page_count = (new_count * PAGES_PER_BLOCK) as u32;
-            inner.info.with(|raw, _inner| {
-                raw.page_count.store(page_count, Ordering::Relaxed);
-                raw.block_count.store(new_count as u32, Ordering::Relaxed);
-                raw.last_page.store(page_count - 1, Ordering::Relaxed);
-            });
-        }
-
-        Ok(true)
+        self.inner.lock().ensure_blocks(min_blocks)
     }
 
     /// Create a new [`Scene::ver`] (render pass) using this buffer.
@@ -335,8 +335,27 @@ pub(crate) fn to_raw(&self) -> raw::ChannelRing<ChannelState, RawEventMsg> {
         self.ch.ring.to_raw()
     }
 
+    /// Signals the owners of all the event slots set in `firing`, and clears it.
+    fn signal_events(&self, firing: &mut [u32; 4]) {
+        for (i, flags) in firing.iter_mut().enumerate() {
+            while *flags != 0 {
+                let j = flags.trailing_zeros();
+                *flags &= !(1u32 << j);
+                self.ev_mgr.signal(i as u32 * 32 + j);
+            }
+        }
+    }
+
     /// Polls for new Event messages on this ring.
+    ///
+    /// All pending messages are drained in one go. Consecutive event flag messages are coalesced,
+    /// so each event slot is signaled at most once per batch: signaling checks the current event
+    /// stamp, which covers every completion reported so far. Pending flags are always signaled
+    /// before handling any other message, so completions are still processed in order relative
+    /// to faults and timeouts (which must not fail work that already completed).
     pub(crate) fn poll(&mut self) {
+        let mut firing = [0u32; 4];
+
         while let Some(msg) = self.ch.get(0) {
             let tag = unsafe { msg.raw.0 };
             match tag {
@@ -344,6 +363,9 @@ pub(crate) fn poll(&mut self) {
                     let msg = unsafe { msg.msg };
 
                     cls_dev_dbg!(EventCh, self.dev, "Event: {:?}\n", msg);
+                    if !matches!(msg, EventMsg::Flag { .. }) {
+                        self.signal_events(&mut firing);
+                    }
                     match msg {
                         EventMsg::Fault => match self.gpu.as_ref() {
                             Some(gpu) => gpu.handle_fault(),
@@ -361,13 +383,9 @@ pub(crate) fn poll(&mut self) {
                                 dev_crit!(self.dev, "EventChannel: No GPU manager available!\n")
                             }
                         },
-                        EventMsg::Flag { firing, .. } => {
-                            for (i, flags) in firing.iter().enumerate() {
-                                for j in 0..32 {
-                                    if flags & (1u32 << j) != 0 {
-                                        self.ev_mgr.signal((i * 32 + j) as u32);
-                                    }
-                                }
+                        EventMsg::Flag { firing: new, .. } => {
+                            for (flags, new) in firing.iter_mut().zip(new.iter()) {
+                                *flags |= new;
                             }
                         }
                         EventMsg::GrowTVB {
@@ -396,9 +414,22 @@ pub(crate) fn poll(&mut self) {
                 }
             }
         }
+
+        self.signal_events(&mut firing);
     }
 }
 
+/// Firmware error log messages received so far.
+#[derive(Copy, Clone, Default)]
+pub(crate) struct FwErrors {
+    /// Number of error or critical messages logged by the firmware.
+    pub(crate) count: u64,
+    /// Firmware timestamp of the most recent error message.
+    pub(crate) last_timestamp: u64,
+    /// Most recent error message, NUL-terminated and zero-padded.
+    pub(crate) last_message: Array<0xc8, u8>,
+}
+
 /// Firmware Log channel. This one is pretty special, since it has 6 sub-channels (for different log
 /// levels), and it also uses a side buffer to actually hold the log messages, only passing around
 /// pointers in the main buffer.
@@ -406,11 +437,14 @@ pub(crate) struct FwLogChannel {
     dev: AsahiDevRef,
     ch: RxChannel<FwLogChannelState, RawFwLogMsg>,
     payload_buf: GpuArray<RawFwLogPayloadMsg>,
+    errors: FwErrors,
 }
 
 impl FwLogChannel {
     const RING_SIZE: usize = 0x100;
     const BUF_SIZE: usize = 0x100;
+    /// First sub-channel carrying error messages (firmware assertions and internal errors).
+    const ERROR_SUB_CHANNEL: usize = 4;
 
     /// Allocate a new Firmware Log channel.
     pub(crate) fn new(
@@ -423,6 +457,7 @@ pub(crate) fn new(
             payload_buf: alloc
                 .shared
                 .array_empty(Self::BUF_SIZE * FwLogChannelState::SUB_CHANNELS)?,
+            errors: Default::default(),
         })
     }
 
@@ -436,6 +471,11 @@ pub(crate) fn get_buf(&self) -> GpuWeakPointer<[RawFwLogPayloadMsg]> {
         self.payload_buf.weak_pointer()
     }
 
+    /// Returns the firmware error messages received so far.
+    pub(crate) fn errors(&self) -> FwErrors {
+        self.errors
+    }
+
     /// Polls for new log messages on all sub-rings.
     pub(crate) fn poll(&mut self) {
         for i in 0..=FwLogChannelState::SUB_CHANNELS - 1 {
@@ -476,6 +516,15 @@ pub(crate) fn poll(&mut self) {
                     self.ch.get(i);
                     continue;
                 };
+                if i >= Self::ERROR_SUB_CHANNEL {
+                    self.errors.count += 1;
+                    self.errors.last_timestamp = payload.timestamp.0;
+                    // Copy only up to the NUL we found, since the firmware may rewrite the buffer
+                    // under us. The message is shorter than the array, so it stays terminated.
+                    let bytes = msg.as_bytes();
+                    self.errors.last_message = Default::default();
+                    self.errors.last_message[..bytes.len()].copy_from_slice(bytes);
+                }
                 match i {
                     0 => dev_dbg!(self.dev, "FWLog: {}\n", msg),
                     1 => dev_info!(self.dev, "FWLog: {}\n", msg),
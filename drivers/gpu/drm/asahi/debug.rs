@@ -54,6 +54,12 @@ pub(crate) enum DebugFlags {
     WaitForPowerOff = 38,
     NoGpuRecovery = 39,
     DisableClustering = 40,
+    StrictAddressValidation = 41,
+    DebugFences = 42,
+    DetectUuidReuse = 43,
+    CheckResultSize = 44,
+    DetectLeaks = 45,
+    DumpInitData = 46,
 
     // 48-: Misc
     Debug0 = 48,
@@ -85,6 +91,17 @@ pub(crate) fn debug_enabled(flag: DebugFlags) -> bool {
     DEBUG_FLAGS.load(Ordering::Relaxed) & 1 << (flag as usize) != 0
 }
 
+/// Check whether a submission was selected for lifecycle tracing with the `trace_submission`
+/// module parameter
+pub(crate) fn submission_traced(id: u64) -> bool {
+    let trace_id = {
+        let lock = crate::THIS_MODULE.kernel_param_lock();
+        *crate::trace_submission.read(&lock)
+    };
+
+    trace_id != 0 && trace_id == id
+}
+
 /// Run some code only if debug is enabled for the calling module
 #[macro_export]
 macro_rules! debug {
@@ -130,3 +147,23 @@ macro_rules! cls_dev_dbg (
         }
     )
 );
+
+/// pr_info!() if debug is enabled for the calling module, or if `$trace` is set
+#[macro_export]
+macro_rules! trace_pr_debug (
+    ($trace:expr, $($arg:tt)*) => (
+        if $trace || $crate::debug::debug_enabled(DEBUG_CLASS) {
+            ::kernel::pr_info! ( $($arg)* );
+        }
+    )
+);
+
+/// dev_info!() if debug is enabled for the calling module, or if `$trace` is set
+#[macro_export]
+macro_rules! trace_dev_dbg (
+    ($trace:expr, $($arg:tt)*) => (
+        if $trace || $crate::debug::debug_enabled(DEBUG_CLASS) {
+            ::kernel::dev_info! ( $($arg)* );
+        }
+    )
+);
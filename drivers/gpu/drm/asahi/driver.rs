@@ -3,7 +3,8 @@
 //! Top-level GPU driver implementation.
 
 use kernel::{
-    c_str, device, drm, drm::drv, drm::ioctl, error::Result, of, platform, prelude::*, sync::Arc,
+    c_str, device, drm, drm::drv, drm::ioctl, error::Result, of, platform, prelude::*, shrinker,
+    sync::Arc,
 };
 
 use crate::{debug, file, gem, gpu, hw, regs};
@@ -28,6 +29,8 @@
 pub(crate) struct AsahiData {
     pub(crate) dev: device::Device,
     pub(crate) gpu: Arc<dyn gpu::GpuManager>,
+    /// Shrinker for the pages of purgeable GEM objects.
+    pub(crate) reclaim: Pin<Box<shrinker::Registration<gem::Reclaim>>>,
 }
 
 /// Convenience type alias for the `device::Data` type for this driver.
@@ -67,12 +70,30 @@ impl drv::Driver for AsahiDriver {
             ioctl::AUTH | ioctl::RENDER_ALLOW, file::File::gem_mmap_offset),
         (ASAHI_GEM_BIND,        drm_asahi_gem_bind,
             ioctl::AUTH | ioctl::RENDER_ALLOW, file::File::gem_bind),
+        (ASAHI_GEM_MADVISE,     drm_asahi_gem_madvise,
+            ioctl::AUTH | ioctl::RENDER_ALLOW, file::File::gem_madvise),
         (ASAHI_QUEUE_CREATE,    drm_asahi_queue_create,
             ioctl::AUTH | ioctl::RENDER_ALLOW, file::File::queue_create),
         (ASAHI_QUEUE_DESTROY,   drm_asahi_queue_destroy,
             ioctl::AUTH | ioctl::RENDER_ALLOW, file::File::queue_destroy),
         (ASAHI_SUBMIT,          drm_asahi_submit,
             ioctl::AUTH | ioctl::RENDER_ALLOW, file::File::submit),
+        (ASAHI_QUEUE_PAUSE,     drm_asahi_queue_pause,
+            ioctl::AUTH | ioctl::RENDER_ALLOW, file::File::queue_pause),
+        (ASAHI_QUEUE_SET_TIMEOUT, drm_asahi_queue_set_timeout,
+            ioctl::AUTH | ioctl::RENDER_ALLOW, file::File::queue_set_timeout),
+        (ASAHI_QUEUE_SET_PRIORITY, drm_asahi_queue_set_priority,
+            ioctl::AUTH | ioctl::RENDER_ALLOW, file::File::queue_set_priority),
+        (ASAHI_QUEUE_RESET_TVB_STATS, drm_asahi_queue_reset_tvb_stats,
+            ioctl::AUTH | ioctl::RENDER_ALLOW, file::File::queue_reset_tvb_stats),
+        (ASAHI_SET_THROTTLE_TEMP, drm_asahi_set_throttle_temp,
+            ioctl::AUTH | ioctl::ROOT_ONLY, file::File::set_throttle_temp),
+        (ASAHI_VM_DUMP,         drm_asahi_vm_dump,
+            ioctl::AUTH | ioctl::ROOT_ONLY, file::File::vm_dump),
+        (ASAHI_GET_CLIENTS,     drm_asahi_get_clients,
+            ioctl::AUTH | ioctl::ROOT_ONLY, file::File::get_clients),
+        (ASAHI_AUDIT_READ,      drm_asahi_audit_read,
+            ioctl::AUTH | ioctl::ROOT_ONLY, file::File::audit_read),
     }
 }
 
@@ -153,8 +174,14 @@ fn probe(
             }
         };
 
-        let data =
-            kernel::new_device_data!(reg, res, AsahiData { dev, gpu }, "Asahi::Registrations")?;
+        let reclaim = shrinker::Registration::new(gem::Reclaim::new()?, c_str!("asahi-gem"))?;
+
+        let data = kernel::new_device_data!(
+            reg,
+            res,
+            AsahiData { dev, gpu, reclaim },
+            "Asahi::Registrations"
+        )?;
 
         let data: Arc<DeviceData> = data.into();
 
@@ -170,6 +197,14 @@ fn probe(
         dev_info!(data.dev, "Probed!\n");
         Ok(data)
     }
+
+    /// Device remove function.
+    fn remove(data: &Self::Data) -> Result {
+        // The firmware must be parked before the device data (and with it the UAT and all GPU
+        // memory) is freed, or it could keep accessing freed memory.
+        data.gpu.shutdown();
+        Ok(())
+    }
 }
 
 // Export the OF ID table as a module ID table, to make modpost/autoloading work.
@@ -13,10 +13,10 @@
 use crate::fw::types::*;
 use crate::{gpu, slotalloc, workqueue};
 use core::cmp;
-use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicI64, Ordering};
 use kernel::prelude::*;
 use kernel::sync::Arc;
-use kernel::{c_str, static_lock_class};
+use kernel::{bindings, c_str, static_lock_class};
 
 const DEBUG_CLASS: DebugFlags = DebugFlags::Event;
 
@@ -119,7 +119,9 @@ impl slotalloc::SlotItem for EventInner {
 
     fn release(&mut self, data: &mut Self::Data, slot: u32) {
         mod_pr_debug!("EventManager: Released slot {}\n", slot);
-        data.owners[slot as usize] = None;
+        if data.owners[slot as usize].take().is_some() {
+            data.used -= 1;
+        }
     }
 }
 
@@ -129,11 +131,15 @@ pub(crate) struct EventManagerInner {
     fw_stamps: GpuArray<FwStamp>,
     // Note: Use dyn to avoid having to version this entire module.
     owners: Vec<Option<Arc<dyn workqueue::WorkQueue + Send + Sync>>>,
+    /// Number of slots that currently have an owner.
+    used: u32,
 }
 
 /// Top-level EventManager object.
 pub(crate) struct EventManager {
     alloc: slotalloc::SlotAllocator<EventInner>,
+    /// Time of the last completion signaled by the firmware (`CLOCK_MONOTONIC` ns).
+    last_signal: AtomicI64,
 }
 
 impl EventManager {
@@ -148,6 +154,7 @@ pub(crate) fn new(alloc: &mut gpu::KernelAllocators) -> Result<EventManager> {
             stamps: alloc.shared.array_empty(NUM_EVENTS as usize)?,
             fw_stamps: alloc.private.array_empty(NUM_EVENTS as usize)?,
             owners,
+            used: 0,
         };
 
         Ok(EventManager {
@@ -165,6 +172,7 @@ pub(crate) fn new(alloc: &mut gpu::KernelAllocators) -> Result<EventManager> {
                 static_lock_class!(),
                 static_lock_class!(),
             )?,
+            last_signal: AtomicI64::new(0),
         })
     }
 
@@ -180,12 +188,30 @@ pub(crate) fn get(
                 &*owner,
                 ev.slot()
             );
-            inner.owners[ev.slot() as usize] = Some(owner);
+            if inner.owners[ev.slot() as usize].replace(owner).is_none() {
+                inner.used += 1;
+            }
             Ok(())
         })?;
         Ok(ev)
     }
 
+    /// Returns the total number of event slots.
+    pub(crate) fn num_slots(&self) -> u32 {
+        NUM_EVENTS
+    }
+
+    /// Returns the number of event slots currently owned by a work queue.
+    pub(crate) fn used_slots(&self) -> u32 {
+        self.alloc.with_inner(|inner| inner.used)
+    }
+
+    /// Returns the time at which the firmware last signaled a completion (`CLOCK_MONOTONIC` ns),
+    /// or 0 if it never has.
+    pub(crate) fn last_signal_time(&self) -> i64 {
+        self.last_signal.load(Ordering::Relaxed)
+    }
+
     /// Signals an event by slot, indicating completion (of one or more commands).
     pub(crate) fn signal(&self, slot: u32) {
         match self
@@ -193,6 +219,9 @@ pub(crate) fn signal(&self, slot: u32) {
             .with_inner(|inner| inner.owners[slot as usize].as_ref().cloned())
         {
             Some(owner) => {
+                // SAFETY: ktime_get() is always safe to call.
+                let now = unsafe { bindings::ktime_get() };
+                self.last_signal.store(now, Ordering::Relaxed);
                 owner.signal();
             }
             None => {
@@ -216,6 +245,17 @@ pub(crate) fn mark_error(&self, slot: u32, wait_value: u32, error: workqueue::Wo
         }
     }
 
+    /// Checks whether the owner of an event has pending work up to a given value in a VM slot.
+    pub(crate) fn has_vm_slot(&self, slot: u32, wait_value: u32, vm_slot: u32) -> bool {
+        match self
+            .alloc
+            .with_inner(|inner| inner.owners[slot as usize].as_ref().cloned())
+        {
+            Some(owner) => owner.has_vm_slot(EventValue(wait_value), vm_slot),
+            None => false,
+        }
+    }
+
     /// Fail all commands, used when the GPU crashes.
     pub(crate) fn fail_all(&self, error: workqueue::WorkError) {
         let mut owners: Vec<Arc<dyn workqueue::WorkQueue + Send + Sync>> = Vec::new();
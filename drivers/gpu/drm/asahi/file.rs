@@ -9,8 +9,9 @@
 
 use crate::debug::*;
 use crate::driver::AsahiDevice;
-use crate::{alloc, buffer, driver, gem, mmu, queue};
+use crate::{alloc, buffer, driver, gem, gpu, mmu, queue, workqueue};
 use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use kernel::dma_fence::RawDmaFence;
 use kernel::drm::gem::BaseObject;
 use kernel::io_buffer::{IoBufferReader, IoBufferWriter};
@@ -24,6 +25,11 @@
 const MAX_SYNCS_PER_SUBMISSION: u32 = 64;
 const MAX_COMMANDS_PER_SUBMISSION: u32 = 64;
 pub(crate) const MAX_COMMANDS_IN_FLIGHT: u32 = 1024;
+const MAX_QUEUE_NAME_LEN: u32 = 32;
+/// Maximum initial TVB size that can be requested at queue creation, in blocks.
+const MAX_INITIAL_TVB_BLOCKS: u32 = 1024;
+/// Maximum size of a VM page table dump, to bound the kernel-side buffer.
+const MAX_VM_DUMP_SIZE: u64 = 1 << 20;
 
 /// A client instance of an `mmu::Vm` address space.
 struct Vm {
@@ -31,6 +37,45 @@ struct Vm {
     ualloc_priv: Arc<Mutex<alloc::DefaultAllocator>>,
     vm: mmu::Vm,
     dummy_obj: gem::ObjectRef,
+    sparse: Arc<Mutex<Vec<mmu::SparseMapping>>>,
+    /// Lowest VA userspace may bind in this VM.
+    min_va: u64,
+    /// Highest VA userspace may bind in this VM (inclusive).
+    max_va: u64,
+}
+
+impl Vm {
+    /// Check that a GPU VA range lies within one of the userspace-managed VA ranges, and within
+    /// the VA range this VM was constrained to at creation time.
+    fn check_bind_range(&self, start: u64, range: u64) -> Result {
+        if range == 0 {
+            return Err(EINVAL);
+        }
+        let end = start.checked_add(range - 1).ok_or(EINVAL)?;
+
+        if (VM_SHADER_START..=VM_SHADER_END).contains(&start) {
+            if !(VM_SHADER_START..=VM_SHADER_END).contains(&end) {
+                return Err(EINVAL); // Invalid map range
+            }
+        } else if (VM_USER_START..=VM_USER_END).contains(&start) {
+            if !(VM_USER_START..=VM_USER_END).contains(&end) {
+                return Err(EINVAL); // Invalid map range
+            }
+        } else {
+            return Err(EINVAL); // Invalid map range
+        }
+
+        if start < self.min_va || end > self.max_va {
+            return Err(EINVAL); // Outside of the constrained VA range
+        }
+
+        // Just in case
+        if end >= VM_DRV_GPU_START {
+            return Err(EINVAL);
+        }
+
+        Ok(())
+    }
 }
 
 impl Drop for Vm {
@@ -125,11 +170,79 @@ fn parse_array(file: &DrmFile, ptr: u64, count: u32, out: bool) -> Result<Vec<Sy
     }
 }
 
+/// Counts one submission against its file's in-flight limit, until dropped.
+///
+/// Owned by the submitted job, so it is released when the job is freed after completion, or
+/// right away if the submission fails.
+pub(crate) struct InflightSlot(Arc<AtomicU32>);
+
+impl InflightSlot {
+    /// Takes a slot from `counter`, failing with `EAGAIN` if `limit` slots are taken already.
+    fn acquire(counter: &Arc<AtomicU32>, limit: u32) -> Result<InflightSlot> {
+        counter
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                if limit != 0 && count >= limit {
+                    None
+                } else {
+                    Some(count + 1)
+                }
+            })
+            .map_err(|_| EAGAIN)?;
+
+        Ok(InflightSlot(counter.clone()))
+    }
+}
+
+impl Drop for InflightSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Checks that all GPU memory mapped by a client was released once it is closed.
+///
+/// This must be the last field of `File`, so it is dropped after all of the client's Vms and
+/// queues are gone.
+struct LeakCheck {
+    gpu: Arc<dyn gpu::GpuManager>,
+    stats: Arc<gpu::ClientStats>,
+    inflight: Arc<AtomicU32>,
+}
+
+impl Drop for LeakCheck {
+    fn drop(&mut self) {
+        if !debug_enabled(DebugFlags::DetectLeaks) {
+            return;
+        }
+
+        // Collect completed work first, since that legitimately keeps mappings alive for a while.
+        // Freeing it may tear down allocators and defer their unmaps, so process those after.
+        core::mem::drop(self.gpu.alloc());
+        self.gpu.process_deferred_unmaps();
+
+        let leaked = self.stats.mapped_bytes.load(Ordering::Relaxed);
+        if leaked != 0 {
+            pr_warn!(
+                "[File {}]: {} bytes still mapped after close ({} submissions in flight)\n",
+                self.stats.file_id,
+                leaked,
+                self.inflight.load(Ordering::Relaxed)
+            );
+        }
+    }
+}
+
 /// State associated with a client.
 pub(crate) struct File {
     id: u64,
     vms: xarray::XArray<Box<Vm>>,
     queues: xarray::XArray<Arc<Mutex<Box<dyn queue::Queue>>>>,
+    /// Number of submissions that have not completed yet.
+    inflight: Arc<AtomicU32>,
+    /// Resource accounting, also visible to privileged monitoring tools.
+    stats: Arc<gpu::ClientStats>,
+    /// Must be last, see `LeakCheck`.
+    _leak_check: LeakCheck,
 }
 
 /// Convenience type alias for our DRM `File` type.
@@ -165,12 +278,35 @@ fn open(device: &AsahiDevice) -> Result<Pin<Box<Self>>> {
         let gpu = &device.data().gpu;
         let id = gpu.ids().file.next();
 
-        mod_dev_dbg!(device, "[File {}]: DRM device opened\n", id);
-        Ok(Box::into_pin(Box::try_new(Self {
+        let stats = Arc::try_new(gpu::ClientStats {
+            file_id: id,
+            pid: kernel::current!().group_leader().pid() as u32,
+            mapped_bytes: Arc::try_new(AtomicU64::new(0))?,
+            vms: AtomicU32::new(0),
+            queues: AtomicU32::new(0),
+            closed: AtomicBool::new(false),
+        })?;
+
+        let inflight = Arc::try_new(AtomicU32::new(0))?;
+
+        let file = Box::try_new(Self {
             id,
             vms: xarray::XArray::new(xarray::flags::ALLOC1),
             queues: xarray::XArray::new(xarray::flags::ALLOC1),
-        })?))
+            inflight: inflight.clone(),
+            stats: stats.clone(),
+            _leak_check: LeakCheck {
+                gpu: gpu.clone(),
+                stats: stats.clone(),
+                inflight,
+            },
+        })?;
+
+        // Register last, so that a failed open never leaves a stale client behind.
+        gpu.register_client(stats)?;
+
+        mod_dev_dbg!(device, "[File {}]: DRM device opened\n", id);
+        Ok(Box::into_pin(file))
     }
 }
 
@@ -198,14 +334,93 @@ pub(crate) fn get_params(
 
         let gpu = &device.data().gpu;
 
-        if data.extensions != 0 || data.param_group != 0 || data.pad != 0 {
+        let per_queue = matches!(
+            data.param_group,
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_QUEUE_BLOCKS
+                | uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_QUEUE_TVB
+                | uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_QUEUE_RENDER
+        );
+
+        if data.extensions != 0 || data.pad != 0 || (!per_queue && data.queue_id != 0) {
             return Err(EINVAL);
         }
 
-        if gpu.is_crashed() {
+        // The health group must keep working on a crashed GPU, since that is what it reports.
+        if gpu.is_crashed()
+            && data.param_group != uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_HEALTH
+        {
             return Err(ENODEV);
         }
 
+        match data.param_group {
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_GLOBAL => {
+                Self::get_params_global(gpu, data)
+            }
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_CAPS => {
+                Self::get_params_caps(gpu, data)
+            }
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_TOPOLOGY => {
+                Self::get_params_topology(gpu, data)
+            }
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_QUEUE_BLOCKS => {
+                Self::get_params_queue_blocks(data, file)
+            }
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_QUEUE_TVB => {
+                Self::get_params_queue_tvb(data, file)
+            }
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_QUEUE_RENDER => {
+                Self::get_params_queue_render(data, file)
+            }
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_ENGINE_STATS => {
+                Self::get_params_engine_stats(gpu, data)
+            }
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_FW_STATUS => {
+                Self::get_params_fw_status(gpu, data)
+            }
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_FW_ERRORS => {
+                Self::get_params_fw_errors(gpu, data)
+            }
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_POWER => {
+                Self::get_params_power(gpu, data)
+            }
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_CLOCKS => {
+                Self::get_params_clocks(gpu, data)
+            }
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_COMPUTE => {
+                Self::get_params_compute(gpu, data)
+            }
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_HEALTH => {
+                Self::get_params_health(gpu, data)
+            }
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_THERMAL => {
+                Self::get_params_thermal(gpu, data)
+            }
+            uapi::drm_asahi_param_group_DRM_ASAHI_PARAM_GROUP_EVENTS => {
+                Self::get_params_events(gpu, data)
+            }
+            _ => Err(EINVAL),
+        }
+    }
+
+    /// Copy a parameter struct out to userspace, truncating it to the size userspace requested.
+    fn write_params<T>(data: &uapi::drm_asahi_get_params, params: &T) -> Result<u32> {
+        let size = core::mem::size_of::<T>().min(data.size.try_into()?);
+
+        // SAFETY: We only write to this userptr once, so there are no TOCTOU issues.
+        let mut params_writer =
+            unsafe { UserSlicePtr::new(data.pointer as usize as *mut _, size).writer() };
+
+        // SAFETY: `size` is at most the sizeof of `params`
+        unsafe { params_writer.write_raw(params as *const _ as *const u8, size)? };
+
+        Ok(0)
+    }
+
+    /// Get the global driver/hardware parameters.
+    fn get_params_global(
+        gpu: &Arc<dyn gpu::GpuManager>,
+        data: &uapi::drm_asahi_get_params,
+    ) -> Result<u32> {
         let mut params = uapi::drm_asahi_params_global {
             unstable_uabi_version: uapi::DRM_ASAHI_UNSTABLE_UABI_VERSION,
             pad0: 0,
@@ -247,26 +462,328 @@ pub(crate) fn get_params(
             result_compute_size: core::mem::size_of::<uapi::drm_asahi_result_compute>() as u32,
 
             firmware_version: [0; 4],
+
+            // These always reflect the detected silicon, not any firmware-facing override
+            // (see the T602x special case in initdata).
+            gpu_revision_id: gpu.get_dyncfg().id.gpu_rev_id as u32,
+            firmware_version_len: 0,
         };
 
+        // Like the topology group, fail rather than return a truncated core mask list.
         for (i, mask) in gpu.get_dyncfg().id.core_masks.iter().enumerate() {
-            *(params.core_masks.get_mut(i).ok_or(EIO)?) = (*mask).try_into()?;
+            *(params.core_masks.get_mut(i).ok_or(EINVAL)?) = (*mask).try_into()?;
         }
 
-        for i in 0..3 {
-            params.firmware_version[i] = *gpu.get_dyncfg().firmware_version.get(i).unwrap_or(&0);
+        let firmware_version = &gpu.get_dyncfg().firmware_version;
+        // Don't silently truncate the version, userspace may use it to reject firmware.
+        if firmware_version.len() > params.firmware_version.len() {
+            return Err(EOVERFLOW);
         }
+        params.firmware_version[..firmware_version.len()].copy_from_slice(firmware_version);
+        params.firmware_version_len = firmware_version.len() as u32;
 
-        let size = core::mem::size_of::<uapi::drm_asahi_params_global>().min(data.size.try_into()?);
+        Self::write_params(data, &params)
+    }
 
-        // SAFETY: We only write to this userptr once, so there are no TOCTOU issues.
-        let mut params_writer =
-            unsafe { UserSlicePtr::new(data.pointer as usize as *mut _, size).writer() };
+    /// Get the hardware capability limits.
+    ///
+    /// This block is versioned, since new limits may be appended over time.
+    fn get_params_caps(
+        gpu: &Arc<dyn gpu::GpuManager>,
+        data: &uapi::drm_asahi_get_params,
+    ) -> Result<u32> {
+        let limits = &gpu.get_cfg().limits;
 
-        // SAFETY: `size` is at most the sizeof of `params`
-        unsafe { params_writer.write_raw(&params as *const _ as *const u8, size)? };
+        let params = uapi::drm_asahi_params_caps {
+            caps_version: uapi::DRM_ASAHI_PARAMS_CAPS_VERSION,
+            pad0: 0,
 
-        Ok(0)
+            max_texture_dim: limits.max_texture_dim,
+            max_texture_dim_3d: limits.max_texture_dim_3d,
+            max_array_layers: limits.max_array_layers,
+            max_framebuffer_dim: limits.max_framebuffer_dim,
+            max_samples: limits.max_samples,
+            simd_width: limits.simd_width,
+            max_threads_per_threadgroup: limits.max_threads_per_threadgroup,
+            max_framebuffer_layers: limits.max_framebuffer_layers,
+            pad1: 0,
+        };
+
+        Self::write_params(data, &params)
+    }
+
+    /// Get the GPU core topology.
+    ///
+    /// In addition to the per-cluster core masks, this returns the packed core mask words, which
+    /// are what the firmware uses to select the cores a vertex job can run on.
+    fn get_params_topology(
+        gpu: &Arc<dyn gpu::GpuManager>,
+        data: &uapi::drm_asahi_get_params,
+    ) -> Result<u32> {
+        let id = &gpu.get_dyncfg().id;
+
+        let mut params = uapi::drm_asahi_params_topology {
+            num_dies: gpu.get_cfg().num_dies,
+            num_clusters: id.num_clusters,
+            num_cores_per_cluster: id.num_cores,
+            num_frags_per_cluster: id.num_frags,
+            num_gps_per_cluster: id.num_gps,
+            num_cores_total_active: id.total_active_cores,
+            num_core_masks: id.core_masks.len().try_into()?,
+            num_core_masks_packed: id.core_masks_packed.len().try_into()?,
+            core_masks: [0; uapi::DRM_ASAHI_MAX_CLUSTERS as usize],
+            core_masks_packed: [0; uapi::DRM_ASAHI_MAX_CORE_MASK_WORDS as usize],
+        };
+
+        // A topology that does not fit the UAPI cannot be described to userspace, so fail the
+        // query rather than returning a truncated mask.
+        params
+            .core_masks
+            .get_mut(..id.core_masks.len())
+            .ok_or(EINVAL)?
+            .copy_from_slice(&id.core_masks);
+        params
+            .core_masks_packed
+            .get_mut(..id.core_masks_packed.len())
+            .ok_or(EINVAL)?
+            .copy_from_slice(&id.core_masks_packed);
+
+        Self::write_params(data, &params)
+    }
+
+    /// Get the compute dispatch limits.
+    ///
+    /// The total number of threads per threadgroup is reported in the CAPS group.
+    fn get_params_compute(
+        gpu: &Arc<dyn gpu::GpuManager>,
+        data: &uapi::drm_asahi_get_params,
+    ) -> Result<u32> {
+        let limits = &gpu.get_cfg().limits;
+        let max = limits.max_threads_per_threadgroup;
+
+        let params = uapi::drm_asahi_params_compute {
+            max_threadgroup_size: [max, max, max],
+            threadgroup_memory_size: limits.threadgroup_memory_size,
+        };
+
+        Self::write_params(data, &params)
+    }
+
+    /// Get the firmware's global per-engine statistics.
+    fn get_params_engine_stats(
+        gpu: &Arc<dyn gpu::GpuManager>,
+        data: &uapi::drm_asahi_get_params,
+    ) -> Result<u32> {
+        let stats = gpu.engine_stats();
+
+        let params = uapi::drm_asahi_params_engine_stats {
+            vertex_cmds: stats.vertex_cmds,
+            fragment_cmds: stats.fragment_cmds,
+        };
+
+        Self::write_params(data, &params)
+    }
+
+    /// Get the firmware liveness status.
+    fn get_params_fw_status(
+        gpu: &Arc<dyn gpu::GpuManager>,
+        data: &uapi::drm_asahi_get_params,
+    ) -> Result<u32> {
+        let params = uapi::drm_asahi_params_fw_status {
+            heartbeat: gpu.fw_heartbeat(),
+            uptime_ns: gpu
+                .fw_uptime()
+                .map_or(0, |t| t.as_nanos().try_into().unwrap_or(u64::MAX)),
+            stalled_ns: gpu
+                .fw_stalled_for()
+                .map_or(0, |t| t.as_nanos().try_into().unwrap_or(u64::MAX)),
+        };
+
+        Self::write_params(data, &params)
+    }
+
+    /// Get a snapshot of the overall GPU health, for monitoring tools.
+    ///
+    /// Fields may be appended in later versions; userspace must check `version` and only look at
+    /// the fields it knows about.
+    fn get_params_health(
+        gpu: &Arc<dyn gpu::GpuManager>,
+        data: &uapi::drm_asahi_get_params,
+    ) -> Result<u32> {
+        let health = gpu.health();
+
+        let params = uapi::drm_asahi_params_health {
+            version: uapi::DRM_ASAHI_PARAMS_HEALTH_VERSION,
+            crashed: health.crashed as u32,
+            halted: health.halted as u32,
+            halt_count: health.halt_count,
+            recovery_count: health.recovery_count,
+            actual_pstate: health.actual_pstate,
+            pending_submissions: health.pending_submissions,
+            pad: 0,
+            fw_heartbeat: health.fw_heartbeat,
+            fw_uptime_ns: health
+                .fw_uptime
+                .map_or(0, |t| t.as_nanos().try_into().unwrap_or(u64::MAX)),
+        };
+
+        Self::write_params(data, &params)
+    }
+
+    /// Get the GPU thermal throttling configuration.
+    fn get_params_thermal(
+        gpu: &Arc<dyn gpu::GpuManager>,
+        data: &uapi::drm_asahi_get_params,
+    ) -> Result<u32> {
+        let params = uapi::drm_asahi_params_thermal {
+            throttle_temp_c: gpu.throttle_temp(),
+            max_throttle_temp_c: gpu.get_dyncfg().pwr.fast_die0_release_temp,
+        };
+
+        Self::write_params(data, &params)
+    }
+
+    /// Get the firmware event slot usage.
+    ///
+    /// Each queue subqueue (render or compute) holds an event slot while it has work in flight,
+    /// and submissions block when none are free.
+    fn get_params_events(
+        gpu: &Arc<dyn gpu::GpuManager>,
+        data: &uapi::drm_asahi_get_params,
+    ) -> Result<u32> {
+        let ev_mgr = gpu.event_manager();
+
+        let params = uapi::drm_asahi_params_events {
+            total_slots: ev_mgr.num_slots(),
+            used_slots: ev_mgr.used_slots(),
+        };
+
+        Self::write_params(data, &params)
+    }
+
+    /// Get the firmware error log summary.
+    fn get_params_fw_errors(
+        gpu: &Arc<dyn gpu::GpuManager>,
+        data: &uapi::drm_asahi_get_params,
+    ) -> Result<u32> {
+        let errors = gpu.fw_errors();
+
+        let params = uapi::drm_asahi_params_fw_errors {
+            count: errors.count,
+            last_timestamp: errors.last_timestamp,
+            last_message: *errors.last_message,
+        };
+
+        Self::write_params(data, &params)
+    }
+
+    /// Get the firmware's GPU power estimate.
+    fn get_params_power(
+        gpu: &Arc<dyn gpu::GpuManager>,
+        data: &uapi::drm_asahi_get_params,
+    ) -> Result<u32> {
+        let power_mw = gpu.power_estimate_mw();
+
+        let params = uapi::drm_asahi_params_power {
+            available: power_mw.is_some() as u32,
+            power_mw: power_mw.unwrap_or(0),
+        };
+
+        Self::write_params(data, &params)
+    }
+
+    /// Get the requested and actual GPU performance states.
+    ///
+    /// The frequencies are looked up from the performance state table, since the firmware does not
+    /// report a measured clock.
+    fn get_params_clocks(
+        gpu: &Arc<dyn gpu::GpuManager>,
+        data: &uapi::drm_asahi_get_params,
+    ) -> Result<u32> {
+        let clocks = gpu.clocks();
+
+        let params = uapi::drm_asahi_params_clocks {
+            requested_pstate: clocks.requested_pstate,
+            actual_pstate: clocks.actual_pstate,
+            requested_freq_hz: clocks.requested_freq_hz,
+            actual_freq_hz: clocks.actual_freq_hz,
+        };
+
+        Self::write_params(data, &params)
+    }
+
+    /// Get the scheduler block statistics for a queue, broken down by reason.
+    fn get_params_queue_blocks(data: &uapi::drm_asahi_get_params, file: &DrmFile) -> Result<u32> {
+        let queue: Arc<Mutex<Box<dyn queue::Queue>>> = file
+            .inner()
+            .queues()
+            .get(data.queue_id.try_into()?)
+            .ok_or(ENOENT)?
+            .borrow()
+            .into();
+
+        let params = {
+            let queue = queue.lock();
+            let stats = queue.stats();
+
+            uapi::drm_asahi_params_queue_blocks {
+                blocked_vertex: stats.blocked_vertex.load(Ordering::Relaxed),
+                blocked_fragment: stats.blocked_fragment.load(Ordering::Relaxed),
+                blocked_compute: stats.blocked_compute.load(Ordering::Relaxed),
+            }
+        };
+
+        Self::write_params(data, &params)
+    }
+
+    /// Convert a TVB usage statistics snapshot to its UAPI representation.
+    fn tvb_params(stats: queue::TvbStats) -> uapi::drm_asahi_params_queue_tvb {
+        uapi::drm_asahi_params_queue_tvb {
+            scenes: stats.scenes,
+            usage_avg_bytes: stats.usage_total.checked_div(stats.scenes).unwrap_or(0),
+            usage_peak_bytes: stats.usage_peak,
+            overflows: stats.overflows,
+            partial_renders: stats.partial_renders,
+        }
+    }
+
+    /// Get the TVB usage statistics for a queue.
+    fn get_params_queue_tvb(data: &uapi::drm_asahi_get_params, file: &DrmFile) -> Result<u32> {
+        let queue: Arc<Mutex<Box<dyn queue::Queue>>> = file
+            .inner()
+            .queues()
+            .get(data.queue_id.try_into()?)
+            .ok_or(ENOENT)?
+            .borrow()
+            .into();
+
+        let params = Self::tvb_params(queue.lock().stats().tvb());
+
+        Self::write_params(data, &params)
+    }
+
+    /// Get the render command statistics for a queue.
+    fn get_params_queue_render(data: &uapi::drm_asahi_get_params, file: &DrmFile) -> Result<u32> {
+        let queue: Arc<Mutex<Box<dyn queue::Queue>>> = file
+            .inner()
+            .queues()
+            .get(data.queue_id.try_into()?)
+            .ok_or(ENOENT)?
+            .borrow()
+            .into();
+
+        let params = {
+            let queue = queue.lock();
+            let stats = queue.stats();
+
+            uapi::drm_asahi_params_queue_render {
+                render_cmds: stats.render_cmds.load(Ordering::Relaxed),
+                empty_tiles: stats.empty_tiles.load(Ordering::Relaxed),
+                empty_tiles_unneeded: stats.empty_tiles_unneeded.load(Ordering::Relaxed),
+            }
+        };
+
+        Self::write_params(data, &params)
     }
 
     /// IOCTL: vm_create: Create a new `Vm`.
@@ -279,14 +796,37 @@ pub(crate) fn vm_create(
             return Err(EINVAL);
         }
 
+        // A zero range means the whole userspace-managed VA space.
+        let (min_va, max_va) = if data.min_va == 0 && data.max_va == 0 {
+            (VM_SHADER_START, VM_USER_END)
+        } else {
+            if data.min_va < mmu::IOVA_USER_BASE as u64
+                || data.min_va >= data.max_va
+                || data.max_va >= VM_DRV_GPU_START
+            {
+                return Err(EINVAL);
+            }
+            if (data.min_va | data.max_va.wrapping_add(1)) as usize & mmu::UAT_PGMSK != 0 {
+                return Err(EINVAL); // Must be page aligned
+            }
+            (data.min_va, data.max_va)
+        };
+
         let gpu = &device.data().gpu;
         let file_id = file.inner().id;
-        let vm = gpu.new_vm(file_id)?;
+        let vm = gpu.new_vm(file_id, Some(file.inner().stats.mapped_bytes.clone()))?;
 
         let resv = file.inner().vms().reserve()?;
         let id: u32 = resv.index().try_into()?;
 
-        mod_dev_dbg!(device, "[File {} VM {}]: VM Create\n", file_id, id);
+        mod_dev_dbg!(
+            device,
+            "[File {} VM {}]: VM Create ({:#x}..={:#x})\n",
+            file_id,
+            id,
+            min_va,
+            max_va
+        );
         mod_dev_dbg!(
             device,
             "[File {} VM {}]: Creating allocators\n",
@@ -334,7 +874,11 @@ pub(crate) fn vm_create(
             ualloc_priv,
             vm,
             dummy_obj,
+            sparse: Arc::pin_init(Mutex::new(Vec::new()))?,
+            min_va,
+            max_va,
         })?)?;
+        file.inner().stats.vms.fetch_add(1, Ordering::Relaxed);
 
         data.vm_id = id;
 
@@ -354,6 +898,7 @@ pub(crate) fn vm_destroy(
         if file.inner().vms().remove(data.vm_id as usize).is_none() {
             Err(ENOENT)
         } else {
+            file.inner().stats.vms.fetch_sub(1, Ordering::Relaxed);
             Ok(0)
         }
     }
@@ -430,7 +975,49 @@ pub(crate) fn gem_mmap_offset(
         Ok(0)
     }
 
+    /// IOCTL: gem_madvise: Mark a GEM object's pages as purgeable or needed.
+    ///
+    /// Purgeable objects may lose their pages under memory pressure while they are not mapped
+    /// into any Vm. `retained` reports whether the pages are still present.
+    pub(crate) fn gem_madvise(
+        device: &AsahiDevice,
+        data: &mut uapi::drm_asahi_gem_madvise,
+        file: &DrmFile,
+    ) -> Result<u32> {
+        mod_dev_dbg!(
+            device,
+            "[File {}]: IOCTL: gem_madvise handle={:#x?} madv={}\n",
+            file.inner().id,
+            data.handle,
+            data.madv
+        );
+
+        if data.extensions != 0 || data.pad != 0 {
+            return Err(EINVAL);
+        }
+
+        let dontneed = match data.madv {
+            uapi::ASAHI_MADV_WILLNEED => false,
+            uapi::ASAHI_MADV_DONTNEED => true,
+            _ => return Err(EINVAL),
+        };
+
+        let bo = gem::lookup_handle(file, data.handle)?;
+        let retained = bo.madvise(device.data().reclaim.inner(), dontneed)?;
+        data.retained = retained as u32;
+
+        Ok(0)
+    }
+
     /// IOCTL: gem_bind: Map or unmap a GEM object into a Vm.
+    ///
+    /// To reserve a VA range up front and place buffers inside it later, userspace uses
+    /// `ASAHI_BIND_OP_SPARSE_RESERVE` to reserve it with no backing, then `ASAHI_BIND_OP_SPARSE_BIND`
+    /// and `ASAHI_BIND_OP_SPARSE_UNBIND` to map and unmap (parts of) objects at fixed addresses
+    /// within it, and `ASAHI_BIND_OP_SPARSE_RELEASE` to give the whole range back. Pages in a
+    /// reservation that are not bound have no page table entries, so GPU accesses to them fault.
+    /// A plain `ASAHI_BIND_OP_BIND` never lands inside a reservation, it fails with `ENOSPC` like
+    /// any other overlapping mapping.
     pub(crate) fn gem_bind(
         device: &AsahiDevice,
         data: &mut uapi::drm_asahi_gem_bind,
@@ -459,10 +1046,37 @@ pub(crate) fn gem_bind(
             uapi::drm_asahi_bind_op_ASAHI_BIND_OP_UNBIND_ALL => {
                 Self::do_gem_unbind_all(device, data, file)
             }
+            uapi::drm_asahi_bind_op_ASAHI_BIND_OP_SPARSE_RESERVE => {
+                Self::do_sparse_reserve(device, data, file)
+            }
+            uapi::drm_asahi_bind_op_ASAHI_BIND_OP_SPARSE_BIND => {
+                Self::do_sparse_bind(device, data, file)
+            }
+            uapi::drm_asahi_bind_op_ASAHI_BIND_OP_SPARSE_UNBIND => {
+                Self::do_sparse_unbind(device, data, file)
+            }
+            uapi::drm_asahi_bind_op_ASAHI_BIND_OP_SPARSE_RELEASE => {
+                Self::do_sparse_release(device, data, file)
+            }
             _ => Err(EINVAL),
         }
     }
 
+    /// Convert userspace bind flags into mapping protection flags.
+    fn bind_prot(flags: u32) -> Result<u32> {
+        if flags & uapi::ASAHI_BIND_READ != 0 {
+            if flags & uapi::ASAHI_BIND_WRITE != 0 {
+                Ok(mmu::PROT_GPU_SHARED_RW)
+            } else {
+                Ok(mmu::PROT_GPU_SHARED_RO)
+            }
+        } else if flags & uapi::ASAHI_BIND_WRITE != 0 {
+            Ok(mmu::PROT_GPU_SHARED_WO)
+        } else {
+            Err(EINVAL) // Must specify one of ASAHI_BIND_{READ,WRITE}
+        }
+    }
+
     pub(crate) fn do_gem_bind(
         _device: &AsahiDevice,
         data: &mut uapi::drm_asahi_gem_bind,
@@ -487,48 +1101,22 @@ pub(crate) fn do_gem_bind(
         }
 
         let start = data.addr;
-        let end = data.addr + data.range - 1;
+        let prot = Self::bind_prot(data.flags)?;
 
-        if (VM_SHADER_START..=VM_SHADER_END).contains(&start) {
-            if !(VM_SHADER_START..=VM_SHADER_END).contains(&end) {
-                return Err(EINVAL); // Invalid map range
-            }
-        } else if (VM_USER_START..=VM_USER_END).contains(&start) {
-            if !(VM_USER_START..=VM_USER_END).contains(&end) {
-                return Err(EINVAL); // Invalid map range
-            }
-        } else {
-            return Err(EINVAL); // Invalid map range
-        }
+        // Clone it immediately so we aren't holding the XArray lock
+        let vm = {
+            let guard = file
+                .inner()
+                .vms()
+                .get(data.vm_id.try_into()?)
+                .ok_or(ENOENT)?;
+            let vm = guard.borrow();
 
-        // Just in case
-        if end >= VM_DRV_GPU_START {
-            return Err(EINVAL);
-        }
+            vm.check_bind_range(start, data.range)?;
+            vm.vm.clone()
+        };
 
-        let prot = if data.flags & uapi::ASAHI_BIND_READ != 0 {
-            if data.flags & uapi::ASAHI_BIND_WRITE != 0 {
-                mmu::PROT_GPU_SHARED_RW
-            } else {
-                mmu::PROT_GPU_SHARED_RO
-            }
-        } else if data.flags & uapi::ASAHI_BIND_WRITE != 0 {
-            mmu::PROT_GPU_SHARED_WO
-        } else {
-            return Err(EINVAL); // Must specify one of ASAHI_BIND_{READ,WRITE}
-        };
-
-        // Clone it immediately so we aren't holding the XArray lock
-        let vm = file
-            .inner()
-            .vms()
-            .get(data.vm_id.try_into()?)
-            .ok_or(ENOENT)?
-            .borrow()
-            .vm
-            .clone();
-
-        bo.map_at(&vm, start, prot, true)?;
+        bo.map_user_at(&vm, start, prot)?;
 
         Ok(0)
     }
@@ -561,6 +1149,132 @@ pub(crate) fn do_gem_unbind_all(
         Ok(0)
     }
 
+    /// Look up the `mmu::Vm` and its sparse ranges for a given VM ID.
+    #[allow(clippy::type_complexity)]
+    fn lookup_sparse(
+        file: &DrmFile,
+        vm_id: u32,
+    ) -> Result<(mmu::Vm, Arc<Mutex<Vec<mmu::SparseMapping>>>)> {
+        // Clone them immediately so we aren't holding the XArray lock
+        let guard = file.inner().vms().get(vm_id.try_into()?).ok_or(ENOENT)?;
+        let vm = guard.borrow();
+
+        Ok((vm.vm.clone(), vm.sparse.clone()))
+    }
+
+    pub(crate) fn do_sparse_reserve(
+        _device: &AsahiDevice,
+        data: &mut uapi::drm_asahi_gem_bind,
+        file: &DrmFile,
+    ) -> Result<u32> {
+        if data.handle != 0
+            || data.offset != 0
+            || (data.flags & !(uapi::ASAHI_BIND_READ | uapi::ASAHI_BIND_WRITE)) != 0
+            || (data.addr | data.range) as usize & mmu::UAT_PGMSK != 0
+        {
+            return Err(EINVAL);
+        }
+
+        let prot = Self::bind_prot(data.flags)?;
+
+        let (vm, sparse) = {
+            let guard = file
+                .inner()
+                .vms()
+                .get(data.vm_id.try_into()?)
+                .ok_or(ENOENT)?;
+            let vm = guard.borrow();
+
+            vm.check_bind_range(data.addr, data.range)?;
+            (vm.vm.clone(), vm.sparse.clone())
+        };
+
+        let mut sparse = sparse.lock();
+        sparse.try_reserve(1)?;
+        let mapping = vm.reserve_sparse(data.addr, data.range.try_into()?, prot)?;
+        sparse
+            .try_push(mapping)
+            .expect("try_push() failed after reserve()");
+
+        Ok(0)
+    }
+
+    pub(crate) fn do_sparse_bind(
+        _device: &AsahiDevice,
+        data: &mut uapi::drm_asahi_gem_bind,
+        file: &DrmFile,
+    ) -> Result<u32> {
+        if data.flags != 0 || (data.addr | data.range | data.offset) as usize & mmu::UAT_PGMSK != 0
+        {
+            return Err(EINVAL);
+        }
+
+        let bo = gem::lookup_handle(file, data.handle)?;
+        let (vm, sparse) = Self::lookup_sparse(file, data.vm_id)?;
+
+        let mut sparse = sparse.lock();
+        let mapping = sparse
+            .iter_mut()
+            .find(|m| m.contains(data.addr as usize, data.range as usize))
+            .ok_or(ENOENT)?;
+
+        bo.bind_sparse(&vm, mapping, data.addr, data.offset, data.range)?;
+
+        Ok(0)
+    }
+
+    pub(crate) fn do_sparse_unbind(
+        _device: &AsahiDevice,
+        data: &mut uapi::drm_asahi_gem_bind,
+        file: &DrmFile,
+    ) -> Result<u32> {
+        if data.handle != 0
+            || data.flags != 0
+            || data.offset != 0
+            || (data.addr | data.range) as usize & mmu::UAT_PGMSK != 0
+        {
+            return Err(EINVAL);
+        }
+
+        let (_vm, sparse) = Self::lookup_sparse(file, data.vm_id)?;
+
+        let mut sparse = sparse.lock();
+        let mapping = sparse
+            .iter_mut()
+            .find(|m| m.contains(data.addr as usize, data.range as usize))
+            .ok_or(ENOENT)?;
+
+        mapping.unbind(data.addr.try_into()?, data.range.try_into()?)?;
+
+        Ok(0)
+    }
+
+    pub(crate) fn do_sparse_release(
+        _device: &AsahiDevice,
+        data: &mut uapi::drm_asahi_gem_bind,
+        file: &DrmFile,
+    ) -> Result<u32> {
+        if data.handle != 0 || data.flags != 0 || data.offset != 0 || data.range != 0 {
+            return Err(EINVAL);
+        }
+
+        let (_vm, sparse) = Self::lookup_sparse(file, data.vm_id)?;
+
+        let mapping = {
+            let mut sparse = sparse.lock();
+            let index = sparse
+                .iter()
+                .position(|m| m.iova() as u64 == data.addr)
+                .ok_or(ENOENT)?;
+            sparse.swap_remove(index)
+        };
+
+        // Unmaps all bound pages, outside of the sparse list lock
+        drop(mapping);
+
+        Ok(0)
+    }
+
     /// IOCTL: queue_create: Create a new command submission queue of a given type.
     pub(crate) fn queue_create(
         device: &AsahiDevice,
@@ -571,17 +1285,19 @@ pub(crate) fn queue_create(
 
         mod_dev_dbg!(
             device,
-            "[File {} VM {}]: Creating queue caps={:?} prio={:?} flags={:#x?}\n",
+            "[File {} VM {}]: Creating queue caps={:?} prio={:?} flags={:#x?} tvb={} timeout={}\n",
             file_id,
             data.vm_id,
             data.queue_caps,
             data.priority,
             data.flags,
+            data.initial_tvb_blocks,
+            data.timeout_ms,
         );
 
         if data.extensions != 0
             || data.flags != 0
-            || data.priority > 3
+            || data.priority >= workqueue::NUM_PRIORITIES
             || data.queue_caps == 0
             || (data.queue_caps
                 & !(uapi::drm_asahi_queue_cap_DRM_ASAHI_QUEUE_CAP_RENDER
@@ -592,6 +1308,42 @@ pub(crate) fn queue_create(
             return Err(EINVAL);
         }
 
+        // An initial TVB size only makes sense for queues that can render.
+        let initial_tvb_blocks = if data.initial_tvb_blocks != 0 {
+            if data.queue_caps & uapi::drm_asahi_queue_cap_DRM_ASAHI_QUEUE_CAP_RENDER == 0
+                || data.initial_tvb_blocks > MAX_INITIAL_TVB_BLOCKS
+            {
+                return Err(EINVAL);
+            }
+            Some(data.initial_tvb_blocks as usize)
+        } else {
+            None
+        };
+
+        let name = if data.name != 0 {
+            if data.name_size == 0 || data.name_size > MAX_QUEUE_NAME_LEN {
+                return Err(EINVAL);
+            }
+
+            let size = data.name_size as usize;
+            let mut buf = [0u8; MAX_QUEUE_NAME_LEN as usize + 1];
+
+            // SAFETY: We only read this once, so there are no TOCTOU issues.
+            let mut reader =
+                unsafe { UserSlicePtr::new(data.name as usize as *mut _, size).reader() };
+            reader.read_slice(&mut buf[..size])?;
+
+            // Allow (but do not require) a NUL terminator within the given size
+            let len = buf[..size].iter().position(|&c| c == 0).unwrap_or(size);
+            let name = CStr::from_bytes_with_nul(&buf[..len + 1]).map_err(|_| EINVAL)?;
+            Some(name.to_cstring()?)
+        } else {
+            if data.name_size != 0 {
+                return Err(EINVAL);
+            }
+            None
+        };
+
         let resv = file.inner().queues().reserve()?;
         let file_vm = file
             .inner()
@@ -604,38 +1356,186 @@ pub(crate) fn queue_create(
         // Drop the vms lock eagerly
         core::mem::drop(file_vm);
 
-        let queue =
-            device
-                .data()
-                .gpu
-                .new_queue(vm, ualloc, ualloc_priv, data.priority, data.queue_caps)?;
+        let queue = device.data().gpu.new_queue(
+            vm,
+            ualloc,
+            ualloc_priv,
+            data.priority,
+            data.queue_caps,
+            name,
+            initial_tvb_blocks,
+            data.timeout_ms,
+        )?;
 
         data.queue_id = resv.index().try_into()?;
         resv.store(Arc::pin_init(Mutex::new(queue))?)?;
+        file.inner().stats.queues.fetch_add(1, Ordering::Relaxed);
 
         Ok(0)
     }
 
     /// IOCTL: queue_destroy: Destroy a command submission queue.
+    ///
+    /// With `DRM_ASAHI_QUEUE_DESTROY_KILL`, work that has not reached the firmware yet is
+    /// cancelled instead of being allowed to run.
     pub(crate) fn queue_destroy(
         _device: &AsahiDevice,
         data: &mut uapi::drm_asahi_queue_destroy,
         file: &DrmFile,
     ) -> Result<u32> {
-        if data.extensions != 0 {
+        if data.extensions != 0 || data.flags & !uapi::DRM_ASAHI_QUEUE_DESTROY_KILL != 0 {
             return Err(EINVAL);
         }
 
-        if file
+        match file.inner().queues().remove(data.queue_id as usize) {
+            None => Err(ENOENT),
+            Some(queue) => {
+                if data.flags & uapi::DRM_ASAHI_QUEUE_DESTROY_KILL != 0 {
+                    queue.lock().kill();
+                }
+                file.inner().stats.queues.fetch_sub(1, Ordering::Relaxed);
+                Ok(0)
+            }
+        }
+    }
+
+    /// IOCTL: queue_pause: Pause or resume a command submission queue.
+    ///
+    /// While paused, submitted jobs are held in the DRM scheduler and not pushed to the firmware.
+    /// Work that the firmware already has is not affected.
+    pub(crate) fn queue_pause(
+        device: &AsahiDevice,
+        data: &mut uapi::drm_asahi_queue_pause,
+        file: &DrmFile,
+    ) -> Result<u32> {
+        if data.extensions != 0 || data.flags & !uapi::ASAHI_QUEUE_PAUSE_RESUME != 0 {
+            return Err(EINVAL);
+        }
+
+        let queue: Arc<Mutex<Box<dyn queue::Queue>>> = file
             .inner()
             .queues()
-            .remove(data.queue_id as usize)
-            .is_none()
-        {
-            Err(ENOENT)
+            .get(data.queue_id.try_into()?)
+            .ok_or(ENOENT)?
+            .borrow()
+            .into();
+
+        mod_dev_dbg!(
+            device,
+            "[File {} Queue {}]: IOCTL: queue_pause (flags: {:#x})\n",
+            file.inner().id,
+            data.queue_id,
+            data.flags
+        );
+
+        let queue = queue.lock();
+        if data.flags & uapi::ASAHI_QUEUE_PAUSE_RESUME != 0 {
+            queue.resume()?;
         } else {
-            Ok(0)
+            queue.pause()?;
+        }
+
+        Ok(0)
+    }
+
+    /// IOCTL: queue_set_timeout: Change the hang detection timeout of a queue.
+    ///
+    /// A job that is already running keeps its current deadline.
+    pub(crate) fn queue_set_timeout(
+        device: &AsahiDevice,
+        data: &mut uapi::drm_asahi_queue_set_timeout,
+        file: &DrmFile,
+    ) -> Result<u32> {
+        if data.extensions != 0 {
+            return Err(EINVAL);
+        }
+
+        let queue: Arc<Mutex<Box<dyn queue::Queue>>> = file
+            .inner()
+            .queues()
+            .get(data.queue_id.try_into()?)
+            .ok_or(ENOENT)?
+            .borrow()
+            .into();
+
+        mod_dev_dbg!(
+            device,
+            "[File {} Queue {}]: IOCTL: queue_set_timeout (timeout: {} ms)\n",
+            file.inner().id,
+            data.queue_id,
+            data.timeout_ms
+        );
+
+        queue.lock().set_timeout(data.timeout_ms)?;
+
+        Ok(0)
+    }
+
+    /// IOCTL: queue_set_priority: Change the scheduling priority of a queue.
+    ///
+    /// Only new submissions are affected. Work that is already queued completes at the old
+    /// priority.
+    pub(crate) fn queue_set_priority(
+        device: &AsahiDevice,
+        data: &mut uapi::drm_asahi_queue_set_priority,
+        file: &DrmFile,
+    ) -> Result<u32> {
+        if data.extensions != 0 || data.priority >= workqueue::NUM_PRIORITIES {
+            return Err(EINVAL);
         }
+
+        let queue: Arc<Mutex<Box<dyn queue::Queue>>> = file
+            .inner()
+            .queues()
+            .get(data.queue_id.try_into()?)
+            .ok_or(ENOENT)?
+            .borrow()
+            .into();
+
+        mod_dev_dbg!(
+            device,
+            "[File {} Queue {}]: IOCTL: queue_set_priority (priority: {})\n",
+            file.inner().id,
+            data.queue_id,
+            data.priority
+        );
+
+        queue.lock().set_priority(data.priority)?;
+
+        Ok(0)
+    }
+
+    /// IOCTL: queue_reset_tvb_stats: Read and reset the TVB usage statistics of a queue.
+    ///
+    /// This starts a new measurement window. The statistics of the window that just ended are
+    /// returned, so that no passes are lost between reading and resetting the counters.
+    pub(crate) fn queue_reset_tvb_stats(
+        device: &AsahiDevice,
+        data: &mut uapi::drm_asahi_queue_reset_tvb_stats,
+        file: &DrmFile,
+    ) -> Result<u32> {
+        if data.extensions != 0 || data.pad != 0 {
+            return Err(EINVAL);
+        }
+
+        let queue: Arc<Mutex<Box<dyn queue::Queue>>> = file
+            .inner()
+            .queues()
+            .get(data.queue_id.try_into()?)
+            .ok_or(ENOENT)?
+            .borrow()
+            .into();
+
+        mod_dev_dbg!(
+            device,
+            "[File {} Queue {}]: IOCTL: queue_reset_tvb_stats\n",
+            file.inner().id,
+            data.queue_id
+        );
+
+        data.stats = Self::tvb_params(queue.lock().stats().take_tvb());
+
+        Ok(0)
     }
 
     /// IOCTL: submit: Submit GPU work to a command submission queue.
@@ -645,7 +1545,7 @@ pub(crate) fn submit(
         file: &DrmFile,
     ) -> Result<u32> {
         if data.extensions != 0
-            || data.flags != 0
+            || (data.flags & !uapi::ASAHI_SUBMIT_EARLY_WAKEUP) != 0
             || data.in_sync_count > MAX_SYNCS_PER_SUBMISSION
             || data.out_sync_count > MAX_SYNCS_PER_SUBMISSION
             || data.command_count > MAX_COMMANDS_PER_SUBMISSION
@@ -702,7 +1602,13 @@ pub(crate) fn submit(
                 id,
                 data.result_handle
             );
-            Some(gem::lookup_handle(file, data.result_handle)?)
+            let bo = gem::lookup_handle(file, data.result_handle)?;
+            // Results are written through a kernel mapping, which the exporter of an imported
+            // buffer may not support (or may only support as a copy).
+            if bo.is_imported() {
+                return Err(EINVAL);
+            }
+            Some(bo)
         } else {
             None
         };
@@ -733,9 +1639,27 @@ pub(crate) fn submit(
             commands.try_push(unsafe { cmd.assume_init() })?;
         }
 
-        let ret = queue
-            .lock()
-            .submit(id, in_syncs, out_syncs, result_buf, commands);
+        let limit = {
+            let lock = crate::THIS_MODULE.kernel_param_lock();
+            *crate::max_inflight_submissions.read(&lock)
+        };
+        let inflight = match InflightSlot::acquire(&file.inner().inflight, limit) {
+            Ok(slot) => slot,
+            Err(e) => {
+                mod_dev_dbg!(
+                    device,
+                    "[File {} Queue {}]: IOCTL: submit({}): Too many submissions in flight\n",
+                    file.inner().id,
+                    data.queue_id,
+                    id
+                );
+                return Err(e);
+            }
+        };
+
+        let ret = queue.lock().submit(
+            id, data.flags, in_syncs, out_syncs, result_buf, commands, inflight,
+        );
 
         match ret {
             Err(ERESTARTSYS) => Err(ERESTARTSYS),
@@ -750,8 +1674,170 @@ pub(crate) fn submit(
                 );
                 Err(e)
             }
-            Ok(_) => Ok(0),
+            Ok(_) => {
+                // Same ID as in our debug logs, so userspace can correlate the two.
+                data.submission_id = id;
+                Ok(0)
+            }
+        }
+    }
+
+    /// IOCTL: set_throttle_temp: Set the GPU die temperature at which throttling starts.
+    ///
+    /// This is privileged, since it affects all clients. The temperature is clamped so it can
+    /// only be lowered from the machine's default, and the value actually set is returned.
+    pub(crate) fn set_throttle_temp(
+        device: &AsahiDevice,
+        data: &mut uapi::drm_asahi_set_throttle_temp,
+        file: &DrmFile,
+    ) -> Result<u32> {
+        if data.extensions != 0 || data.pad != 0 {
+            return Err(EINVAL);
+        }
+
+        mod_dev_dbg!(
+            device,
+            "[File {}]: IOCTL: set_throttle_temp {}\n",
+            file.inner().id,
+            data.temp_c
+        );
+
+        data.temp_c = device.data().gpu.set_throttle_temp(data.temp_c)?;
+        Ok(0)
+    }
+
+    /// IOCTL: vm_dump: Dump the page table of a VM in human-readable form.
+    ///
+    /// This is a privileged debugging interface, since it exposes physical addresses. The total
+    /// number of bytes mapped in the VM is also reported, to help track down leaks.
+    pub(crate) fn vm_dump(
+        device: &AsahiDevice,
+        data: &mut uapi::drm_asahi_vm_dump,
+        file: &DrmFile,
+    ) -> Result<u32> {
+        if data.extensions != 0 || data.flags != 0 {
+            return Err(EINVAL);
+        }
+
+        mod_dev_dbg!(
+            device,
+            "[File {} VM {}]: IOCTL: vm_dump\n",
+            file.inner().id,
+            data.vm_id
+        );
+
+        let vm = file
+            .inner()
+            .vms()
+            .get(data.vm_id.try_into()?)
+            .ok_or(ENOENT)?
+            .borrow()
+            .vm
+            .clone();
+
+        let limit = data.size.min(MAX_VM_DUMP_SIZE).try_into()?;
+        let (dump, truncated) = vm.dump_page_table(limit)?;
+
+        // SAFETY: We only write to this userptr once, so there are no TOCTOU issues.
+        let mut writer =
+            unsafe { UserSlicePtr::new(data.buffer as usize as *mut _, dump.len()).writer() };
+        writer.write_slice(&dump)?;
+
+        data.size = dump.len() as u64;
+        data.mapped_bytes = vm.mapped_bytes() as u64;
+        if truncated {
+            data.flags |= uapi::ASAHI_VM_DUMP_TRUNCATED;
         }
+
+        Ok(0)
+    }
+
+    /// IOCTL: get_clients: Report the GPU resource usage of all open clients.
+    ///
+    /// This exposes information about other processes, so it is restricted to privileged users.
+    /// Up to `count` entries are written, and `count` is set to the total number of clients.
+    pub(crate) fn get_clients(
+        device: &AsahiDevice,
+        data: &mut uapi::drm_asahi_get_clients,
+        file: &DrmFile,
+    ) -> Result<u32> {
+        if data.extensions != 0 || data.pad != 0 {
+            return Err(EINVAL);
+        }
+
+        mod_dev_dbg!(device, "[File {}]: IOCTL: get_clients\n", file.inner().id);
+
+        let clients = device.data().gpu.clients()?;
+        let count = clients.len().min(data.count as usize);
+        let size = core::mem::size_of::<uapi::drm_asahi_client_info>();
+
+        // SAFETY: We only write to this userptr once, so there are no TOCTOU issues.
+        let mut writer =
+            unsafe { UserSlicePtr::new(data.clients as usize as *mut _, count * size).writer() };
+
+        for client in clients.iter().take(count) {
+            let info = uapi::drm_asahi_client_info {
+                file_id: client.file_id,
+                mapped_bytes: client.mapped_bytes.load(Ordering::Relaxed),
+                pid: client.pid,
+                vm_count: client.vms.load(Ordering::Relaxed),
+                queue_count: client.queues.load(Ordering::Relaxed),
+                pad: 0,
+            };
+
+            // SAFETY: `info` is a plain UAPI struct of exactly `size` bytes.
+            unsafe { writer.write_raw(&info as *const _ as *const u8, size)? };
+        }
+
+        data.count = clients.len().try_into()?;
+
+        Ok(0)
+    }
+
+    /// IOCTL: audit_read: Read records from the submission audit log.
+    ///
+    /// Up to `count` records starting at sequence number `seq` are returned, and `count` is set to
+    /// the number written. `seq` is advanced past them, and `lost` reports how many records were
+    /// overwritten before they could be read.
+    pub(crate) fn audit_read(
+        device: &AsahiDevice,
+        data: &mut uapi::drm_asahi_audit_read,
+        file: &DrmFile,
+    ) -> Result<u32> {
+        if data.extensions != 0 || data.pad != 0 {
+            return Err(EINVAL);
+        }
+
+        mod_dev_dbg!(
+            device,
+            "[File {}]: IOCTL: audit_read from {}\n",
+            file.inner().id,
+            data.seq
+        );
+
+        let (records, next_seq, lost) = device
+            .data()
+            .gpu
+            .audit_log()
+            .lock()
+            .read(data.seq, data.count as usize)?;
+        let size = core::mem::size_of::<uapi::drm_asahi_audit_record>();
+
+        // SAFETY: We only write to this userptr once, so there are no TOCTOU issues.
+        let mut writer = unsafe {
+            UserSlicePtr::new(data.records as usize as *mut _, records.len() * size).writer()
+        };
+
+        for record in records.iter() {
+            // SAFETY: `record` is a plain UAPI struct of exactly `size` bytes.
+            unsafe { writer.write_raw(record as *const _ as *const u8, size)? };
+        }
+
+        data.count = records.len() as u32;
+        data.seq = next_seq;
+        data.lost = lost;
+
+        Ok(0)
     }
 
     /// Returns the unique file ID for this `File`.
@@ -763,5 +1849,20 @@ pub(crate) fn file_id(&self) -> u64 {
 impl Drop for File {
     fn drop(&mut self) {
         mod_pr_debug!("[File {}]: Closing...\n", self.id);
+        self.stats.closed.store(true, Ordering::Relaxed);
+
+        // Nobody can wait on work that has not been submitted to the firmware yet, other than
+        // through shared fences, so cancel it like `DRM_ASAHI_QUEUE_DESTROY_KILL` does.
+        // SAFETY: `File` is always pinned (see `open()`), so `queues` is not moved until it is
+        // dropped after this.
+        let queues = unsafe { Pin::new_unchecked(&self.queues) };
+        loop {
+            // The range cursor holds the XArray spinlock, so only use it to find the next index.
+            let index = queues.range(0, usize::MAX).next().map(|(index, _)| index);
+            match index.and_then(|index| queues.remove(index)) {
+                Some(queue) => queue.lock().kill(),
+                None => break,
+            }
+        }
     }
 }
@@ -393,7 +393,7 @@ pub(crate) struct HwDataA {
         pub(crate) fast_die0_sensor_mask: U64,
         #[ver(G >= G14X)]
         pub(crate) fast_die1_sensor_mask: U64,
-        pub(crate) fast_die0_release_temp_cc: u32,
+        pub(crate) fast_die0_release_temp_cc: AtomicU32,
         pub(crate) unk_87c: i32,
         pub(crate) unk_880: u32,
         pub(crate) unk_884: u32,
@@ -1092,7 +1092,7 @@ pub(crate) struct Globals {
         pub(crate) unk_89bc_0: Array<0x3c, u8>,
 
         pub(crate) unk_89bc: u32,
-        pub(crate) fast_die0_release_temp: u32,
+        pub(crate) fast_die0_release_temp: AtomicU32,
         pub(crate) unk_89c4: i32,
         pub(crate) fast_die0_prop_tgt_delta: u32,
         pub(crate) fast_die0_kp: F32,
@@ -6,11 +6,16 @@
 //! view of what a GPU buffer object is. It is in charge of keeping track of all mappings for
 //! each GEM object so we can remove them when a client (File) or a Vm are destroyed, as well as
 //! implementing RTKit buffers on top of GEM objects for firmware use.
+//!
+//! Userspace may mark idle objects as purgeable. Their pages are then freed by the shrinker under
+//! memory pressure, unless they are mapped into a `Vm` or by the kernel. Once purged, an object's
+//! contents are gone for good and any further attempt to map it fails.
 
 use kernel::{
     drm::{gem, gem::shmem},
     error::Result,
     prelude::*,
+    shrinker,
     soc::apple::rtkit,
     sync::Mutex,
     uapi,
@@ -18,6 +23,7 @@
 
 use kernel::drm::gem::BaseObject;
 
+use core::ops::Deref;
 use core::sync::atomic::{AtomicU64, Ordering};
 
 use crate::{debug::*, driver::AsahiDevice, file::DrmFile, mmu, util::*};
@@ -36,16 +42,57 @@ pub(crate) struct DriverObject {
     /// Locked list of mapping tuples: (file_id, vm_id, mapping)
     #[pin]
     mappings: Mutex<Vec<(u64, u64, crate::mmu::Mapping)>>,
+    /// Purgeability state of the backing pages.
+    #[pin]
+    purge_state: Mutex<PurgeState>,
     /// ID for debug
     id: u64,
 }
 
+/// Purgeability of an object's pages, as requested by userspace.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Madvise {
+    /// The pages must be kept.
+    WillNeed,
+    /// The pages may be purged when not in use.
+    DontNeed,
+    /// The pages have been purged.
+    Purged,
+}
+
+/// Purge state of an object.
+struct PurgeState {
+    madv: Madvise,
+    /// Number of live users of the pages (GPU mappings and kernel mappings), which block purging.
+    pins: usize,
+}
+
+/// Keeps an object's pages from being purged while it exists.
+pub(crate) struct PurgePin(gem::ObjectRef<Object>);
+
+impl Drop for PurgePin {
+    fn drop(&mut self) {
+        self.0.purge_state.lock().pins -= 1;
+    }
+}
+
+/// A scatter-gather table of an object's pages, which also keeps them from being purged.
+pub(crate) struct SGTable {
+    sgt: shmem::SGTable<DriverObject>,
+    _pin: PurgePin,
+}
+
+impl Deref for SGTable {
+    type Target = shmem::SGTable<DriverObject>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.sgt
+    }
+}
+
 /// Type alias for the shmem GEM object type for this driver.
 pub(crate) type Object = shmem::Object<DriverObject>;
 
-/// Type alias for the SGTable type for this driver.
-pub(crate) type SGTable = shmem::SGTable<DriverObject>;
-
 /// A shared reference to a GEM object for this driver.
 pub(crate) struct ObjectRef {
     /// The underlying GEM object reference
@@ -72,6 +119,16 @@ fn drop_file_mappings(&self, file_id: u64) {
         }
     }
 
+    /// Remove the mapping for a given `Vm` identified by its ID from this object and return it,
+    /// instead of dropping it.
+    fn take_vm_mapping(&self, vm_id: u64) -> Option<crate::mmu::Mapping> {
+        let mut mappings = self.mappings.lock();
+        let index = mappings
+            .iter()
+            .position(|(_mapped_fid, mapped_vmid, _mapping)| *mapped_vmid == vm_id)?;
+        Some(mappings.swap_remove(index).2)
+    }
+
     /// Drop all object mappings for a given VM ID.
     ///
     /// Used on VM destroy.
@@ -118,6 +175,76 @@ pub(crate) fn size(&self) -> usize {
         self.gem.size()
     }
 
+    /// Returns whether this object was imported from another device via dma-buf.
+    pub(crate) fn is_imported(&self) -> bool {
+        self.gem.is_imported()
+    }
+
+    /// Keeps the pages of this object from being purged while the returned pin exists.
+    ///
+    /// Fails with `EINVAL` if the pages have already been purged.
+    pub(crate) fn pin_pages(&self) -> Result<PurgePin> {
+        let mut state = self.gem.purge_state.lock();
+        if state.madv == Madvise::Purged {
+            mod_pr_debug!("DriverObject: Use of purged object id={}\n", self.gem.id);
+            return Err(EINVAL);
+        }
+        state.pins += 1;
+        Ok(PurgePin(self.gem.reference()))
+    }
+
+    /// Returns a scatter-gather table for this object, pinning its pages.
+    fn sg_table(&self) -> Result<SGTable> {
+        // Pin first without holding the lock across the allocation, since the shrinker takes it.
+        let pin = self.pin_pages()?;
+        Ok(SGTable {
+            sgt: self.gem.sg_table()?,
+            _pin: pin,
+        })
+    }
+
+    /// Marks the pages of this object as purgeable (or not) under memory pressure.
+    ///
+    /// Returns whether the pages are still present. Once purged, an object stays purged.
+    pub(crate) fn madvise(&self, reclaim: &Reclaim, dontneed: bool) -> Result<bool> {
+        // The pages of imported objects belong to the exporter, so we can't purge them.
+        if self.gem.kernel || self.is_imported() {
+            return Err(EINVAL);
+        }
+
+        {
+            let mut state = self.gem.purge_state.lock();
+            if state.madv == Madvise::Purged {
+                return Ok(false);
+            }
+            state.madv = if dontneed {
+                Madvise::DontNeed
+            } else {
+                Madvise::WillNeed
+            };
+        }
+
+        // This takes the shmem pages lock, which is held across page allocations that may enter
+        // reclaim, so it must not be called with the purge state locked. The shrinker only purges
+        // objects that both we and the shmem helpers consider purgeable, so a race between two
+        // madvise calls can only keep pages around, never purge them.
+        self.gem.madvise(dontneed as i32);
+
+        mod_pr_debug!(
+            "DriverObject: madvise id={} dontneed={}\n",
+            self.gem.id,
+            dontneed
+        );
+
+        if dontneed {
+            reclaim.add(&self.gem)?;
+        } else {
+            reclaim.remove(self.gem.id);
+        }
+
+        Ok(true)
+    }
+
     /// Maps an object into a given `Vm` at any free address within a given range.
     ///
     /// Returns Err(EBUSY) if there is already a mapping.
@@ -143,7 +270,7 @@ pub(crate) fn map_into_range(
             }
         }
 
-        let sgt = self.gem.sg_table()?;
+        let sgt = self.sg_table()?;
         let new_mapping =
             vm.map_in_range(self.gem.size(), sgt, alignment, start, end, prot, guard)?;
 
@@ -152,6 +279,16 @@ pub(crate) fn map_into_range(
         Ok(iova)
     }
 
+    /// Maps an object into a given `Vm` at a specific address on behalf of userspace, with a
+    /// guard page.
+    ///
+    /// Returns Err(EINVAL) if `prot` is not allowed for user mappings (see
+    /// [`crate::mmu::check_user_prot()`]), and otherwise behaves like [`Self::map_at()`].
+    pub(crate) fn map_user_at(&mut self, vm: &crate::mmu::Vm, addr: u64, prot: u32) -> Result {
+        crate::mmu::check_user_prot(prot)?;
+        self.map_at(vm, addr, prot, true)
+    }
+
     /// Maps an object into a given `Vm` at a specific address.
     ///
     /// Returns Err(EBUSY) if there is already a mapping.
@@ -176,7 +313,7 @@ pub(crate) fn map_at(
             }
         }
 
-        let sgt = self.gem.sg_table()?;
+        let sgt = self.sg_table()?;
         let new_mapping = vm.map_at(addr, self.gem.size(), sgt, prot, guard)?;
 
         let iova = new_mapping.iova();
@@ -185,11 +322,44 @@ pub(crate) fn map_at(
         Ok(())
     }
 
+    /// Binds `size` bytes of this object, starting at byte `offset`, into a sparse range of a given
+    /// `Vm` at `addr`.
+    pub(crate) fn bind_sparse(
+        &self,
+        vm: &crate::mmu::Vm,
+        sparse: &mut crate::mmu::SparseMapping,
+        addr: u64,
+        offset: u64,
+        size: u64,
+    ) -> Result {
+        if self.gem.vm_id.is_some() && self.gem.vm_id != Some(vm.id()) {
+            return Err(EINVAL);
+        }
+
+        if offset
+            .checked_add(size)
+            .map_or(true, |end| end > self.gem.size() as u64)
+        {
+            return Err(EINVAL);
+        }
+
+        let sgt = self.sg_table()?;
+        sparse.bind(addr.try_into()?, sgt, offset.try_into()?, size.try_into()?)
+    }
+
     /// Drop all mappings for this object owned by a given `Vm` identified by its ID.
     pub(crate) fn drop_vm_mappings(&mut self, vm_id: u64) {
         self.gem.drop_vm_mappings(vm_id);
     }
 
+    /// Unmap this object from a given `Vm` identified by its ID without waiting for the firmware
+    /// cache flush. See [`crate::mmu::Mapping::unmap_deferred()`].
+    pub(crate) fn drop_vm_mappings_deferred(&mut self, vm_id: u64) {
+        if let Some(mapping) = self.gem.take_vm_mapping(vm_id) {
+            mapping.unmap_deferred();
+        }
+    }
+
     /// Drop all mappings for this object owned by a given `File` identified by its ID.
     pub(crate) fn drop_file_mappings(&mut self, file_id: u64) {
         self.gem.drop_file_mappings(file_id);
@@ -248,6 +418,10 @@ fn new(_dev: &AsahiDevice, _size: usize) -> Self::Initializer {
             flags: 0,
             vm_id: None,
             mappings <- Mutex::new(Vec::new()),
+            purge_state <- Mutex::new(PurgeState {
+                madv: Madvise::WillNeed,
+                pins: 0,
+            }),
             id,
         })
     }
@@ -261,6 +435,14 @@ fn close(obj: &Object, file: &DrmFile) {
 
 impl shmem::DriverObject for DriverObject {
     type Driver = crate::driver::AsahiDriver;
+
+    /// Callback to stop tracking a GEM object for reclaim before it is freed
+    fn free(obj: &Object) {
+        // Kernel objects are never purgeable, and may be freed before the device data exists.
+        if !obj.kernel {
+            obj.dev().data().reclaim.inner().remove(obj.id);
+        }
+    }
 }
 
 impl rtkit::Buffer for ObjectRef {
@@ -272,3 +454,152 @@ fn buf(&mut self) -> Result<&mut [u8]> {
         Ok(vmap.as_mut_slice())
     }
 }
+
+/// A purgeable object tracked by [`Reclaim`].
+///
+/// This does not hold a reference, so that objects can still be freed while they are tracked.
+/// Objects remove themselves from the list before they are freed, so entries stay valid while
+/// the list lock is held.
+struct ReclaimEntry(*const Object);
+
+impl ReclaimEntry {
+    fn get(&self) -> &Object {
+        // SAFETY: The entry is only reachable with the list lock held, see above.
+        unsafe { &*self.0 }
+    }
+}
+
+// SAFETY: GEM objects are thread-safe, and entries are only dereferenced with the list lock held.
+unsafe impl Send for ReclaimEntry {}
+
+/// Tracks objects marked as purgeable, and frees their pages under memory pressure.
+///
+/// The shrinker only ever trylocks the list and the objects' purge state, since both may be held
+/// by tasks that are waiting on page allocations that entered reclaim.
+pub(crate) struct Reclaim {
+    objects: Pin<Box<Mutex<Vec<ReclaimEntry>>>>,
+}
+
+impl Reclaim {
+    /// Create a new, empty purgeable object tracker.
+    pub(crate) fn new() -> Result<Reclaim> {
+        Ok(Reclaim {
+            objects: Box::pin_init(Mutex::new(Vec::new()))?,
+        })
+    }
+
+    /// Start tracking a purgeable object.
+    ///
+    /// The object must remove itself with [`Reclaim::remove()`] before it is freed. This never
+    /// allocates with the list lock held.
+    fn add(&self, obj: &Object) -> Result {
+        loop {
+            let capacity = {
+                let mut objects = self.objects.lock();
+                if objects.iter().any(|o| o.get().id == obj.id) {
+                    return Ok(());
+                }
+                if objects.len() < objects.capacity() {
+                    objects
+                        .try_push(ReclaimEntry(obj))
+                        .expect("try_push() failed with spare capacity");
+                    return Ok(());
+                }
+                (objects.capacity() * 2).max(16)
+            };
+
+            let mut new = Vec::try_with_capacity(capacity)?;
+            let mut objects = self.objects.lock();
+            if objects.len() < new.capacity() {
+                for o in objects.drain(..) {
+                    new.try_push(o)
+                        .expect("try_push() failed after try_with_capacity()");
+                }
+                *objects = new;
+            }
+        }
+    }
+
+    /// Stop tracking an object, if it is tracked.
+    fn remove(&self, id: u64) {
+        let mut objects = self.objects.lock();
+        if let Some(index) = objects.iter().position(|o| o.get().id == id) {
+            objects.swap_remove(index);
+        }
+    }
+}
+
+impl shrinker::Shrinker for Reclaim {
+    fn count(&self, _sc: &shrinker::ShrinkControl) -> usize {
+        // If the list is busy, report nothing to free for now rather than wait for it.
+        let objects = match self.objects.try_lock() {
+            Some(objects) => objects,
+            None => return 0,
+        };
+
+        objects
+            .iter()
+            .map(|o| o.get())
+            .filter(|o| match o.purge_state.try_lock() {
+                Some(state) => {
+                    state.madv == Madvise::DontNeed && state.pins == 0 && o.is_purgeable()
+                }
+                None => false,
+            })
+            .map(|o| o.size() / kernel::PAGE_SIZE)
+            .sum()
+    }
+
+    fn scan(&self, sc: &mut shrinker::ShrinkControl) -> Option<usize> {
+        let mut objects = self.objects.try_lock()?;
+        let mut scanned = 0;
+        let mut freed = 0;
+        let mut index = 0;
+        let mut busy = false;
+
+        while index < objects.len() && scanned < sc.nr_to_scan() {
+            let obj = objects[index].get();
+            let mut state = match obj.purge_state.try_lock() {
+                Some(state) => state,
+                None => {
+                    busy = true;
+                    break;
+                }
+            };
+
+            // The shrinker counts pages, so every object we look at counts as scanned.
+            scanned += obj.size() / kernel::PAGE_SIZE;
+
+            let drop_entry = match state.madv {
+                Madvise::DontNeed => {
+                    if state.pins == 0 && obj.is_purgeable() && obj.purge() {
+                        mod_pr_debug!("DriverObject: Purged id={}\n", obj.id);
+                        state.madv = Madvise::Purged;
+                        freed += obj.size() / kernel::PAGE_SIZE;
+                        true
+                    } else {
+                        false
+                    }
+                }
+                // Made unpurgeable again since it was added.
+                Madvise::WillNeed | Madvise::Purged => true,
+            };
+            drop(state);
+
+            // This only drops the list entry, never the object itself.
+            if drop_entry {
+                objects.swap_remove(index);
+            } else {
+                index += 1;
+            }
+        }
+
+        // Stop if we could not make progress because an object was busy.
+        if busy && freed == 0 {
+            return None;
+        }
+
+        sc.set_nr_scanned(scanned);
+        Some(freed)
+    }
+}
@@ -12,22 +12,23 @@
 //! itself with version dependence.
 
 use core::any::Any;
-use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, Ordering};
 use core::time::Duration;
 
 use kernel::{
-    c_str,
+    bindings, c_str,
     delay::coarse_sleep,
     device::RawDevice,
     error::code::*,
     macros::versions,
     prelude::*,
     soc::apple::rtkit,
+    str::CString,
     sync::{
         lock::{mutex::MutexBackend, Guard},
         Arc, Mutex, UniqueArc,
     },
-    time::{clock, Now},
+    time::{clock, Instant, Now},
     types::ForeignOwnable,
 };
 
@@ -37,7 +38,8 @@
 use crate::fw::channels::PipeType;
 use crate::fw::types::{U32, U64};
 use crate::{
-    alloc, buffer, channel, event, fw, gem, hw, initdata, mem, mmu, queue, regs, workqueue,
+    alloc, audit, buffer, channel, event, fw, gem, hw, initdata, mem, mmu, queue, regs, util,
+    workqueue,
 };
 
 const DEBUG_CLASS: DebugFlags = DebugFlags::Gpu;
@@ -105,10 +107,111 @@
 /// Timeout for entering the halt state after a fault or request.
 const HALT_ENTER_TIMEOUT: Duration = Duration::from_millis(100);
 
+/// Timeout for the firmware to acknowledge the InitData after boot.
+const INIT_ACK_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Timeout for in-flight work to drain and the GPU to go idle on device removal.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Firmware power status value reported once the GPU is powered off.
+pub(crate) const PWR_STATUS_OFF: u32 = 4;
+
+/// Maximum doorbell coalescing window, in microseconds.
+const MAX_DOORBELL_COALESCE_US: u32 = 1000;
+
+/// Default firmware idle power-off delay, in milliseconds.
+const IDLE_OFF_DELAY_MS: u32 = 2;
+/// Idle power-off delay with the `KeepGpuPowered` debug flag, in milliseconds.
+const IDLE_OFF_DELAY_KEEP_POWERED_MS: u32 = 5000;
+/// Maximum idle power-off delay requested by fence deadlines, in milliseconds.
+///
+/// This bounds how long a bogus far-future deadline can keep the GPU powered.
+const MAX_DEADLINE_IDLE_OFF_DELAY_MS: u32 = 100;
+
+/// Lowest GPU throttle temperature userspace may set, in degrees Celsius.
+const MIN_THROTTLE_TEMP_C: u32 = 40;
+
 /// Maximum amount of firmware-private memory garbage allowed before collection.
 /// Collection flushes the FW cache and is expensive, so this needs to be
 /// reasonably high.
 const MAX_FW_ALLOC_GARBAGE: usize = 16 * 1024 * 1024;
+/// Amount of firmware-private memory garbage past which collection is never deferred.
+const MAX_FW_ALLOC_GARBAGE_HARD: usize = 4 * MAX_FW_ALLOC_GARBAGE;
+
+/// Number of consecutive context invalidation timeouts after which freed contexts are leaked
+/// instead of retried.
+const MAX_CTX_INVALIDATION_TIMEOUTS: u32 = 8;
+
+/// Index of the kernel private allocator in `GpuManager::garbage_since`.
+const GARBAGE_PRIVATE: usize = 0;
+/// Index of the kernel GPU read-only allocator in `GpuManager::garbage_since`.
+const GARBAGE_GPU_RO: usize = 1;
+
+/// Per-engine statistics maintained by the firmware.
+///
+/// Only the command counts at the start of the vertex and fragment statistics are known. The rest
+/// of those structures, and all of the compute one, is opaque, so busy times and compute counts
+/// are not reported.
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct EngineStats {
+    /// Total number of vertex commands processed.
+    pub(crate) vertex_cmds: u32,
+    /// Total number of fragment commands processed.
+    pub(crate) fragment_cmds: u32,
+}
+
+/// GPU performance state and clock information, as reported by the firmware.
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct ClockInfo {
+    /// Performance state requested by the firmware's DVFS logic.
+    pub(crate) requested_pstate: u32,
+    /// Performance state the firmware reports as actually in effect.
+    pub(crate) actual_pstate: u32,
+    /// Frequency of the requested performance state, in hertz.
+    pub(crate) requested_freq_hz: u32,
+    /// Frequency of the performance state actually in effect, in hertz.
+    pub(crate) actual_freq_hz: u32,
+}
+
+/// A snapshot of the overall GPU and firmware health.
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct HealthInfo {
+    /// The firmware has crashed and the GPU is unusable until the driver is reloaded.
+    pub(crate) crashed: bool,
+    /// The firmware is currently halted.
+    pub(crate) halted: bool,
+    /// Number of times the firmware has halted, as counted by the firmware.
+    pub(crate) halt_count: u32,
+    /// Number of times the driver resumed the firmware after a halt.
+    pub(crate) recovery_count: u32,
+    /// Performance state the firmware reports as actually in effect.
+    pub(crate) actual_pstate: u32,
+    /// Number of submissions the firmware has not completed yet.
+    pub(crate) pending_submissions: u32,
+    /// Firmware liveness counter, see `GpuManager::fw_heartbeat()`.
+    pub(crate) fw_heartbeat: u64,
+    /// Time since the firmware was booted, if it has been.
+    pub(crate) fw_uptime: Option<Duration>,
+}
+
+/// Live resource accounting for a single client (an open DRM file).
+///
+/// This is shared between the client's `File` and the `GpuManager`, so that privileged
+/// monitoring tools can enumerate the usage of all clients.
+pub(crate) struct ClientStats {
+    /// Unique ID of the client's `File`.
+    pub(crate) file_id: u64,
+    /// Thread group ID of the process that opened the file.
+    pub(crate) pid: u32,
+    /// Bytes currently mapped into all of the client's Vms.
+    pub(crate) mapped_bytes: Arc<AtomicU64>,
+    /// Number of Vms the client currently has.
+    pub(crate) vms: AtomicU32,
+    /// Number of queues the client currently has.
+    pub(crate) queues: AtomicU32,
+    /// The file has been closed, so this entry may be dropped.
+    pub(crate) closed: AtomicBool,
+}
 
 /// Global allocators used for kernel-half structures.
 pub(crate) struct KernelAllocators {
@@ -220,11 +323,82 @@ pub(crate) struct GpuManager {
     event_manager: Arc<event::EventManager>,
     buffer_mgr: buffer::BufferManager::ver,
     ids: SequenceIDs,
+    power_hints: Arc<PowerHints>,
     #[pin]
     garbage_work: Mutex<Vec<Box<dyn workqueue::GenSubmittedWork>>>,
     #[allow(clippy::vec_box)]
     #[pin]
     garbage_contexts: Mutex<Vec<Box<fw::types::GpuObject<fw::workqueue::GpuContextData>>>>,
+    /// Number of consecutive context invalidations that timed out.
+    ctx_invalidation_timeouts: AtomicU32,
+    /// Time at which each collected kernel allocator first exceeded the garbage threshold.
+    #[pin]
+    garbage_since: Mutex<[Option<Instant<clock::KernelTime>>; 2]>,
+    /// Number of doorbells received from the firmware, as a liveness indicator.
+    fw_heartbeat: AtomicU64,
+    /// Time at which work was last submitted to an idle firmware (`CLOCK_MONOTONIC` ns).
+    work_pending_since: AtomicI64,
+    /// A doorbell arrived that has not been handled by a channel poll yet.
+    doorbell_pending: AtomicBool,
+    /// Some context is currently polling the receive channels for doorbells.
+    doorbell_polling: AtomicBool,
+    /// Number of times the firmware was resumed after halting.
+    recoveries: AtomicU32,
+    /// Time at which the firmware was booted.
+    #[pin]
+    fw_boot_time: Mutex<Option<Instant<clock::KernelTime>>>,
+    /// Accounting for all clients, pruned lazily once they are closed.
+    #[pin]
+    clients: Mutex<Vec<Arc<ClientStats>>>,
+    /// Log of submitted commands, if auditing is enabled.
+    #[pin]
+    audit_log: Mutex<audit::AuditLog>,
+    /// Current GPU throttle temperature in degrees Celsius. The lock also keeps updates of the
+    /// firmware's two copies of the value from interleaving.
+    #[pin]
+    throttle_temp_c: Mutex<u32>,
+}
+
+/// Power management hints from fence consumers, shared by the GPU manager and all job fences.
+///
+/// Fences may outlive the GPU manager, so this is kept separate from it.
+#[derive(Default)]
+pub(crate) struct PowerHints {
+    /// Latest deadline set on any job fence (`CLOCK_MONOTONIC` ns).
+    keep_powered_until: AtomicI64,
+    /// `keep_powered_until` moved since the globals were last updated for it.
+    changed: AtomicBool,
+}
+
+impl PowerHints {
+    /// Ask for the GPU to stay powered until at least `deadline`, so that work submitted to meet
+    /// it does not have to wait for a power-up.
+    pub(crate) fn request_deadline(&self, deadline: i64) {
+        let prev = self
+            .keep_powered_until
+            .fetch_max(deadline, Ordering::Relaxed);
+        if deadline > prev {
+            self.changed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns whether a later deadline was requested since the last call.
+    fn take_changed(&self) -> bool {
+        self.changed.swap(false, Ordering::Relaxed)
+    }
+
+    /// Returns the idle power-off delay needed to stay powered until the latest deadline.
+    fn idle_off_delay_ms(&self) -> u32 {
+        // SAFETY: ktime_get() is always safe to call.
+        let now = unsafe { bindings::ktime_get() };
+        let remain = self.keep_powered_until.load(Ordering::Relaxed) - now;
+        if remain <= 0 {
+            return 0;
+        }
+
+        let ms = util::div_ceil(remain as u64, 1_000_000);
+        ms.min(MAX_DEADLINE_IDLE_OFF_DELAY_MS as u64) as u32
+    }
 }
 
 /// Trait used to abstract the firmware/GPU-dependent variants of the GpuManager.
@@ -242,10 +416,17 @@ pub(crate) trait GpuManager: Send + Sync {
     /// Get a reference to the KernelAllocators.
     fn alloc(&self) -> Guard<'_, KernelAllocators, MutexBackend>;
     /// Create a new `Vm` given a unique `File` ID.
-    fn new_vm(&self, file_id: u64) -> Result<mmu::Vm>;
+    fn new_vm(&self, file_id: u64, client_bytes: Option<Arc<AtomicU64>>) -> Result<mmu::Vm>;
     /// Bind a `Vm` to an available slot and return the `VmBind`.
     fn bind_vm(&self, vm: &mmu::Vm) -> Result<mmu::VmBind>;
+    /// Flush and unmap all user mappings whose unmap was deferred, without waiting for the
+    /// work item to run.
+    fn process_deferred_unmaps(&self);
     /// Create a new user command queue.
+    ///
+    /// If `initial_tvb_blocks` is `None`, render queues start with the TVB size given by the
+    /// `initial_tvb_size` module parameter. A `timeout_ms` of 0 selects the default job timeout.
+    #[allow(clippy::too_many_arguments)]
     fn new_queue(
         &self,
         vm: mmu::Vm,
@@ -253,6 +434,9 @@ fn new_queue(
         ualloc_priv: Arc<Mutex<alloc::DefaultAllocator>>,
         priority: u32,
         caps: u32,
+        name: Option<CString>,
+        initial_tvb_blocks: Option<usize>,
+        timeout_ms: u32,
     ) -> Result<Box<dyn queue::Queue>>;
     /// Return a reference to the global `SequenceIDs` instance.
     fn ids(&self) -> &SequenceIDs;
@@ -273,6 +457,9 @@ fn new_queue(
     fn ack_grow(&self, buffer_slot: u32, vm_slot: u32, counter: u32);
     /// Wait for the GPU to become idle and power off.
     fn wait_for_poweroff(&self, timeout: usize) -> Result;
+    /// Get the current firmware power status without waiting. [`PWR_STATUS_OFF`] means the GPU
+    /// is powered off.
+    fn poweroff_state(&self) -> u32;
     /// Send a firmware control command (secure cache flush).
     fn fwctl(&self, msg: fw::channels::FwCtlMsg) -> Result;
     /// Get the static GPU configuration for this SoC.
@@ -285,6 +472,51 @@ fn new_queue(
     fn free_context(&self, data: Box<fw::types::GpuObject<fw::workqueue::GpuContextData>>);
     /// Check whether the GPU is crashed
     fn is_crashed(&self) -> bool;
+    /// Read the per-engine statistics from the firmware.
+    fn engine_stats(&self) -> EngineStats;
+    /// Returns a counter that advances every time the firmware signals the driver.
+    ///
+    /// The firmware goes to sleep when idle, so this only advances while there is work to do, and
+    /// it cannot tell a hung firmware from an idle one. Use `fw_stalled_for()` for that.
+    fn fw_heartbeat(&self) -> u64;
+    /// Returns for how long the firmware has had work pending without completing any of it, or
+    /// `None` if it has no work.
+    ///
+    /// Progress is measured from the later of the last completion and the moment work was
+    /// submitted to an idle firmware, so a long stall while work is pending indicates a hang.
+    fn fw_stalled_for(&self) -> Option<Duration>;
+    /// Returns the time since the firmware was booted, if it has been.
+    fn fw_uptime(&self) -> Option<Duration>;
+    /// Returns the firmware's current estimate of the GPU power draw, in milliwatts.
+    ///
+    /// Returns `None` if the firmware has not produced an estimate yet.
+    fn power_estimate_mw(&self) -> Option<u32>;
+    /// Returns the requested and actual GPU performance states and their clock frequencies.
+    fn clocks(&self) -> ClockInfo;
+    /// Returns the count and most recent of the error messages logged by the firmware.
+    fn fw_errors(&self) -> channel::FwErrors;
+    /// Returns the die temperature at which the firmware starts throttling, in degrees Celsius.
+    fn throttle_temp(&self) -> u32;
+    /// Sets the die temperature at which the firmware starts throttling, in degrees Celsius.
+    ///
+    /// The value is clamped to a safe range, and the temperature actually set is returned. It is
+    /// not known whether the firmware picks up the new value before its next boot.
+    fn set_throttle_temp(&self, temp_c: u32) -> Result<u32>;
+    /// Returns a snapshot of the overall GPU health.
+    fn health(&self) -> HealthInfo;
+    /// Returns the global event manager.
+    fn event_manager(&self) -> &event::EventManager;
+    /// Register the accounting of a newly opened client.
+    fn register_client(&self, stats: Arc<ClientStats>) -> Result;
+    /// Returns the accounting of all clients that have not been closed.
+    fn clients(&self) -> Result<Vec<Arc<ClientStats>>>;
+    /// Returns the submission audit log.
+    fn audit_log(&self) -> &Mutex<audit::AuditLog>;
+    /// Quiesce the GPU and shut down the firmware, in preparation for device removal.
+    ///
+    /// After this returns, no new work is accepted and the firmware no longer accesses any
+    /// memory owned by the driver, so the UAT and allocators may be freed.
+    fn shutdown(&self);
 }
 
 /// Private generic trait for functions that don't need to escape this module.
@@ -308,12 +540,48 @@ fn recv_message(data: <Self::Data as ForeignOwnable>::Borrowed<'_>, ep: u8, msg:
             return;
         }
 
-        let mut ch = data.rx_channels.lock();
+        data.fw_heartbeat.fetch_add(1, Ordering::Relaxed);
 
-        ch.fw_log.poll();
-        ch.ktrace.poll();
-        ch.stats.poll();
-        ch.event.poll();
+        // If another context is already polling, leave the doorbell for it. It checks the
+        // pending flag after every poll, so it is guaranteed to poll at least once more after
+        // this doorbell and pick up its events.
+        data.doorbell_pending.store(true, Ordering::SeqCst);
+        if data.doorbell_polling.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let window_us = {
+            let lock = crate::THIS_MODULE.kernel_param_lock();
+            *crate::doorbell_coalesce_us.read(&lock)
+        }
+        .min(MAX_DOORBELL_COALESCE_US);
+
+        loop {
+            if window_us != 0 {
+                // Give the firmware a chance to post more events, so a burst of completions
+                // is handled in a single poll.
+                // SAFETY: usleep_range() is safe to call from any sleepable context, and this
+                // callback already sleeps on the channel lock.
+                unsafe { bindings::usleep_range(window_us.into(), (window_us * 2).into()) };
+            }
+
+            data.doorbell_pending.store(false, Ordering::SeqCst);
+            data.poll_rx_channels();
+
+            if data.doorbell_pending.load(Ordering::SeqCst) {
+                continue;
+            }
+
+            data.doorbell_polling.store(false, Ordering::SeqCst);
+
+            // A doorbell may have arrived after the check above but before it could see that we
+            // stopped polling. If so, take over polling again (unless it already did).
+            if !data.doorbell_pending.load(Ordering::SeqCst)
+                || data.doorbell_polling.swap(true, Ordering::SeqCst)
+            {
+                break;
+            }
+        }
     }
 
     fn crashed(data: <Self::Data as ForeignOwnable>::Borrowed<'_>) {
@@ -548,6 +816,8 @@ pub(crate) fn new(
             rxc.event.set_manager(mgr.clone());
         }
 
+        mgr.log_fw_interface();
+
         Ok(mgr)
     }
 
@@ -663,6 +933,8 @@ fn make_mgr(
             device_control: channel::DeviceControlChannel::ver::new(dev, alloc_ref)?,
         }))?;
 
+        let throttle_temp_c = dyncfg.pwr.fast_die0_release_temp;
+
         let x = UniqueArc::pin_init(try_pin_init!(GpuManager::ver {
             dev: dev.into(),
             cfg,
@@ -681,8 +953,20 @@ fn make_mgr(
             pipes,
             buffer_mgr,
             ids: Default::default(),
+            power_hints: Arc::try_new(Default::default())?,
             garbage_work <- Mutex::new_named(Vec::new(), c_str!("garbage_work")),
             garbage_contexts <- Mutex::new_named(Vec::new(), c_str!("garbage_contexts")),
+            ctx_invalidation_timeouts: AtomicU32::new(0),
+            garbage_since <- Mutex::new_named([None; 2], c_str!("garbage_since")),
+            fw_heartbeat: AtomicU64::new(0),
+            work_pending_since: AtomicI64::new(0),
+            doorbell_pending: AtomicBool::new(false),
+            doorbell_polling: AtomicBool::new(false),
+            recoveries: AtomicU32::new(0),
+            fw_boot_time <- Mutex::new_named(None, c_str!("fw_boot_time")),
+            clients <- Mutex::new_named(Vec::new(), c_str!("clients")),
+            audit_log <- Mutex::new_named(audit::AuditLog::new(), c_str!("audit_log")),
+            throttle_temp_c <- Mutex::new_named(throttle_temp_c, c_str!("throttle_temp_c")),
         }))?;
 
         Ok(x)
@@ -781,6 +1065,15 @@ fn make_dyncfg(
             return Err(EIO);
         }
 
+        #[ver(V < V13_0B4)]
+        if pwr_cfg.csafr.is_some() {
+            dev_err!(
+                dev,
+                "CS/AFR power management is not supported by this firmware version\n"
+            );
+            return Err(ENODEV);
+        }
+
         let node = dev.of_node().ok_or(EIO)?;
 
         Ok(Box::try_new(hw::DynConfig {
@@ -791,6 +1084,63 @@ fn make_dyncfg(
         })?)
     }
 
+    /// Flush the firmware cache before collecting garbage from a kernel allocator.
+    ///
+    /// If all the garbage objects to be collected fit within a single ranged flush, only that VA
+    /// range is flushed. Otherwise, this falls back to a full cache flush.
+    fn flush_garbage(&self, alloc: &impl Allocator, count: usize) -> Result {
+        if let Some((start, end)) = alloc.garbage_range(count) {
+            let start = start & !(mmu::UAT_PGMSK as u64);
+            let end = (end + mmu::UAT_PGMSK as u64) & !(mmu::UAT_PGMSK as u64);
+
+            if ((end - start) as usize >> mmu::UAT_PGBIT) < mmu::MAX_FLUSH_PAGES {
+                mod_dev_dbg!(
+                    self.dev,
+                    "Flushing coprocessor data cache range {:#x}:{:#x}\n",
+                    start,
+                    end
+                );
+
+                match self.uat.flush_kernel_range(start, end - start) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => dev_warn!(
+                        self.dev,
+                        "Ranged FW cache flush failed ({:?}), falling back to a full flush\n",
+                        e
+                    ),
+                }
+            }
+        }
+
+        self.flush_fw_cache()
+    }
+
+    /// Decide whether the garbage of a kernel allocator should be collected now.
+    ///
+    /// Once the garbage threshold is crossed, collection may be deferred by up to
+    /// `garbage_flush_delay_ms` so that more garbage is batched into a single flush. Collection
+    /// always happens immediately once the hard garbage limit is reached.
+    fn garbage_collection_due(&self, index: usize, garbage_bytes: usize) -> bool {
+        let mut since = self.garbage_since.lock();
+
+        if garbage_bytes <= MAX_FW_ALLOC_GARBAGE {
+            since[index] = None;
+            return false;
+        }
+
+        let delay_ms = {
+            let lock = crate::THIS_MODULE.kernel_param_lock();
+            *crate::garbage_flush_delay_ms.read(&lock)
+        };
+
+        if delay_ms == 0 || garbage_bytes > MAX_FW_ALLOC_GARBAGE_HARD {
+            return true;
+        }
+
+        let start = since[index].get_or_insert_with(clock::KernelTime::now);
+        start.elapsed() >= Duration::from_millis(delay_ms.into())
+    }
+
     /// Create the global GPU event manager, and return an `Arc<>` to it.
     fn make_event_manager(alloc: &mut KernelAllocators) -> Result<Arc<event::EventManager>> {
         Ok(Arc::try_new(event::EventManager::new(alloc)?)?)
@@ -861,7 +1211,15 @@ fn iomap(
 
     /// Mark work associated with currently in-progress event slots as failed, after a fault or
     /// timeout.
-    fn mark_pending_events(&self, culprit_slot: Option<u32>, error: workqueue::WorkError) {
+    ///
+    /// If `culprit_vm` is set, only events with pending work in that VM slot are failed, and the
+    /// others are left pending.
+    fn mark_pending_events(
+        &self,
+        culprit_slot: Option<u32>,
+        culprit_vm: Option<u32>,
+        error: workqueue::WorkError,
+    ) {
         dev_err!(self.dev, "  Pending events:\n");
 
         self.initdata.globals.with(|raw, _inner| {
@@ -886,6 +1244,12 @@ fn mark_pending_events(&self, culprit_slot: Option<u32>, error: workqueue::WorkE
                         flags,
                         wait_value
                     );
+                    if let Some(vm_slot) = culprit_vm {
+                        if !self.event_manager.has_vm_slot(slot, wait_value, vm_slot) {
+                            dev_err!(self.dev, "      Not in VM slot {}, sparing\n", vm_slot);
+                            continue;
+                        }
+                    }
                     let error = if culprit_slot.is_some() && culprit_slot != Some(slot) {
                         workqueue::WorkError::Killed
                     } else {
@@ -918,6 +1282,52 @@ fn get_fault_info(&self) -> Option<regs::FaultInfo> {
         info
     }
 
+    /// Check that the firmware accepted the InitData after boot.
+    ///
+    /// If the firmware crashes or halts right after boot, the InitData was most likely malformed.
+    /// We also expect the firmware to clear `do_init` once it has processed the InitData, but that
+    /// is only inferred from the field name and has not been confirmed on all firmware versions,
+    /// so if it stays set we only warn.
+    fn verify_init(&self) -> Result {
+        let start = clock::KernelTime::now();
+
+        loop {
+            if self.is_crashed() {
+                dev_err!(self.dev, "GPU firmware crashed during initialization\n");
+                return Err(EIO);
+            }
+
+            let halted = self
+                .initdata
+                .fw_status
+                .with(|raw, _inner| raw.flags.halted.load(Ordering::Relaxed));
+            if halted != 0 {
+                dev_err!(self.dev, "GPU firmware halted during initialization\n");
+                return Err(EIO);
+            }
+
+            let do_init = self.initdata.runtime_pointers.hwdata_b.with(|raw, _inner| {
+                // SAFETY: The firmware writes this field, so it must be read volatile.
+                unsafe { core::ptr::read_volatile(&raw.do_init) }
+            });
+            if do_init == 0 {
+                mod_dev_dbg!(self.dev, "Firmware initialized in {:?}\n", start.elapsed());
+                return Ok(());
+            }
+
+            if start.elapsed() > INIT_ACK_TIMEOUT {
+                dev_warn!(
+                    self.dev,
+                    "GPU firmware did not clear do_init after {:?}, continuing anyway\n",
+                    INIT_ACK_TIMEOUT
+                );
+                return Ok(());
+            }
+
+            coarse_sleep(Duration::from_millis(1));
+        }
+    }
+
     /// Resume the GPU firmware after it halts (due to a timeout, fault, or request).
     fn recover(&self) {
         self.initdata.fw_status.with(|raw, _inner| {
@@ -942,6 +1352,7 @@ fn recover(&self) {
                 dev_crit!(self.dev, "  GPU recovery is disabled, wedging forever!\n");
             } else if halted != 0 {
                 dev_err!(self.dev, "  Attempting recovery...\n");
+                self.recoveries.fetch_add(1, Ordering::Relaxed);
                 raw.flags.halted.store(0, Ordering::SeqCst);
                 raw.flags.resume.store(1, Ordering::SeqCst);
             } else {
@@ -950,6 +1361,76 @@ fn recover(&self) {
         });
     }
 
+    /// Poll all of the firmware->driver channels for new messages.
+    fn poll_rx_channels(&self) {
+        let mut ch = self.rx_channels.lock();
+
+        ch.fw_log.poll();
+        ch.ktrace.poll();
+        ch.stats.poll();
+        ch.event.poll();
+        core::mem::drop(ch);
+
+        // Deadlines are usually set after submission, while the job runs. Apply new ones here
+        // too, before the firmware decides whether to power off once the job completes.
+        if self.power_hints.take_changed() {
+            self.update_globals();
+        }
+    }
+
+    /// Handle a timeout acquiring the UAT handoff lock, which means the firmware is hung.
+    fn handle_handoff_timeout(&self) {
+        dev_err!(
+            self.dev,
+            "** UAT handoff lock timed out, firmware hung? **\n"
+        );
+        self.mark_pending_events(None, None, workqueue::WorkError::Timeout);
+        self.recover();
+    }
+
+    /// Log which of the compiled firmware interface variants is in use, along with the
+    /// version-dependent features it enables, to make bug reports easier to triage.
+    fn log_fw_interface(&self) {
+        #[ver(V == V12_3)]
+        let fw_if = "12.3";
+        #[ver(V == V12_4)]
+        let fw_if = "12.4";
+        #[ver(V == V13_5)]
+        let fw_if = "13.5";
+
+        #[ver(G == G13)]
+        let gpu_if = "G13";
+        #[ver(G == G14)]
+        let gpu_if = "G14";
+        #[ver(G == G14X)]
+        let gpu_if = "G14X";
+
+        dev_info!(
+            self.dev,
+            "Firmware {:?} using the {} interface for {}\n",
+            &*self.dyncfg.firmware_version,
+            fw_if,
+            gpu_if
+        );
+
+        #[ver(V >= V13_0B4)]
+        {
+            let ver_info = self.initdata.with(|raw, _inner| raw.ver_info);
+            dev_info!(
+                self.dev,
+                "  ver_info: {:#x?}, CS/AFR power management: {}\n",
+                &*ver_info,
+                if self.dyncfg.pwr.csafr.is_some() {
+                    "enabled"
+                } else {
+                    "disabled"
+                }
+            );
+        }
+        #[ver(V < V13_0B4)]
+        dev_info!(self.dev, "  CS/AFR power management: unsupported\n");
+    }
+
     /// Return the packed GPU enabled core masks.
     // Only used for some versions
     #[allow(dead_code)]
@@ -978,7 +1459,7 @@ pub(crate) fn run_job(&self, job: workqueue::JobSubmission::ver<'_>) -> Result {
         mod_dev_dbg!(self.dev, "GPU: run_job: ring doorbell\n");
 
         let mut guard = self.rtkit.lock();
-        let rtk = guard.as_mut().unwrap();
+        let rtk = guard.as_mut().ok_or(ENODEV)?;
         rtk.send_message(
             EP_DOORBELL,
             MSG_TX_DOORBELL | pipe_type as u64 | ((index as u64) << 2),
@@ -999,6 +1480,11 @@ pub(crate) fn start_op(self: &Arc<GpuManager::ver>) -> Result<OpGuard> {
             .with(|raw, _inner| raw.pending_submissions.fetch_add(1, Ordering::Acquire));
 
         mod_dev_dbg!(self.dev, "OP start (pending: {})\n", val + 1);
+        if val == 0 {
+            // SAFETY: ktime_get() is always safe to call.
+            let now = unsafe { bindings::ktime_get() };
+            self.work_pending_since.store(now, Ordering::Relaxed);
+        }
         self.kick_firmware()?;
         Ok(OpGuard(self.clone()))
     }
@@ -1049,7 +1535,7 @@ fn invalidate_context(
 
         {
             let mut guard = self.rtkit.lock();
-            let rtk = guard.as_mut().unwrap();
+            let rtk = guard.as_mut().ok_or(ENODEV)?;
             rtk.send_message(EP_DOORBELL, MSG_TX_DOORBELL | DOORBELL_DEVCTRL)?;
         }
 
@@ -1086,9 +1572,10 @@ fn init(&self) -> Result {
 
         let initdata = self.initdata.gpu_va().get();
         let mut guard = self.rtkit.lock();
-        let rtk = guard.as_mut().unwrap();
+        let rtk = guard.as_mut().ok_or(ENODEV)?;
 
         rtk.boot()?;
+        *self.fw_boot_time.lock() = Some(clock::KernelTime::now());
         rtk.start_endpoint(EP_FIRMWARE)?;
         rtk.start_endpoint(EP_DOORBELL)?;
         rtk.send_message(EP_FIRMWARE, MSG_INIT | (initdata & INIT_DATA_MASK))?;
@@ -1096,15 +1583,15 @@ fn init(&self) -> Result {
         core::mem::drop(guard);
 
         self.kick_firmware()?;
-        Ok(())
+        self.verify_init()
     }
 
     fn update_globals(&self) {
-        let mut timeout: u32 = 2;
+        let mut timeout = IDLE_OFF_DELAY_MS.max(self.power_hints.idle_off_delay_ms());
         if debug_enabled(DebugFlags::WaitForPowerOff) {
             timeout = 0;
         } else if debug_enabled(DebugFlags::KeepGpuPowered) {
-            timeout = 5000;
+            timeout = IDLE_OFF_DELAY_KEEP_POWERED_MS;
         }
 
         self.initdata.globals.with(|raw, _inner| {
@@ -1123,57 +1610,116 @@ fn alloc(&self) -> Guard<'_, KernelAllocators, MutexBackend> {
         let mut garbage_ctx = Vec::new();
         core::mem::swap(&mut *self.garbage_contexts.lock(), &mut garbage_ctx);
 
+        let crashed = self.is_crashed();
+        let give_up =
+            self.ctx_invalidation_timeouts.load(Ordering::Relaxed) >= MAX_CTX_INVALIDATION_TIMEOUTS;
+        if give_up && !crashed && !garbage_ctx.is_empty() {
+            dev_err!(
+                self.dev,
+                "GpuContext: Firmware keeps timing out, leaking {} GPU contexts\n",
+                garbage_ctx.len()
+            );
+        }
+
+        let mut timed_out = false;
         for ctx in garbage_ctx {
-            if self.invalidate_context(&ctx).is_err() {
-                dev_err!(self.dev, "GpuContext: Failed to invalidate GPU context!\n");
-                if debug_enabled(DebugFlags::OopsOnGpuCrash) {
-                    panic!("GPU firmware timed out");
+            if crashed {
+                // The firmware is gone for good, so there is nothing to invalidate.
+                continue;
+            } else if give_up {
+                // The firmware may still be using the context, so it must never be freed.
+                core::mem::forget(ctx);
+                continue;
+            } else if timed_out {
+                self.free_context(ctx);
+                continue;
+            }
+            match self.invalidate_context(&ctx) {
+                Ok(()) => self.ctx_invalidation_timeouts.store(0, Ordering::Relaxed),
+                Err(e) => {
+                    dev_err!(self.dev, "GpuContext: Failed to invalidate GPU context!\n");
+                    if debug_enabled(DebugFlags::OopsOnGpuCrash) {
+                        panic!("GPU firmware timed out");
+                    }
+                    // The firmware may still be using the context, so it cannot be freed yet. Put
+                    // it and the rest back on the list, to retry on the next allocation.
+                    if e == ETIMEDOUT {
+                        timed_out = true;
+                        self.ctx_invalidation_timeouts
+                            .fetch_add(1, Ordering::Relaxed);
+                        self.free_context(ctx);
+                    }
                 }
             }
         }
 
         let mut guard = self.alloc.lock();
+
+        // Cache flushes would time out too, so don't even try to collect garbage.
+        if timed_out || give_up {
+            dev_err!(
+                self.dev,
+                "Firmware not responding, skipping garbage collection\n"
+            );
+            return guard;
+        }
+
         let (garbage_count, garbage_bytes) = guard.private.garbage();
-        if garbage_bytes > MAX_FW_ALLOC_GARBAGE {
+        if self.garbage_collection_due(GARBAGE_PRIVATE, garbage_bytes) {
             mod_dev_dbg!(
                 self.dev,
                 "Collecting kalloc/private garbage ({} objects, {} bytes)\n",
                 garbage_count,
                 garbage_bytes
             );
-            if self.flush_fw_cache().is_err() {
+            if self.flush_garbage(&guard.private, garbage_count).is_err() {
                 dev_err!(self.dev, "Failed to flush FW cache\n");
             } else {
                 guard.private.collect_garbage(garbage_count);
+                self.garbage_since.lock()[GARBAGE_PRIVATE] = None;
+                // Collecting garbage may have freed up the tail of the heap
+                guard.private.trim();
             }
         }
 
         let (garbage_count, garbage_bytes) = guard.gpu_ro.garbage();
-        if garbage_bytes > MAX_FW_ALLOC_GARBAGE {
+        if self.garbage_collection_due(GARBAGE_GPU_RO, garbage_bytes) {
             mod_dev_dbg!(
                 self.dev,
                 "Collecting kalloc/gpuro garbage ({} objects, {} bytes)\n",
                 garbage_count,
                 garbage_bytes
             );
-            if self.flush_fw_cache().is_err() {
+            if self.flush_garbage(&guard.gpu_ro, garbage_count).is_err() {
                 dev_err!(self.dev, "Failed to flush FW cache\n");
             } else {
                 guard.gpu_ro.collect_garbage(garbage_count);
+                self.garbage_since.lock()[GARBAGE_GPU_RO] = None;
+                // Collecting garbage may have freed up the tail of the heap
+                guard.gpu_ro.trim();
             }
         }
 
         guard
     }
 
-    fn new_vm(&self, file_id: u64) -> Result<mmu::Vm> {
-        self.uat.new_vm(self.ids.vm.next(), file_id)
+    fn new_vm(&self, file_id: u64, client_bytes: Option<Arc<AtomicU64>>) -> Result<mmu::Vm> {
+        self.uat.new_vm(self.ids.vm.next(), file_id, client_bytes)
     }
 
     fn bind_vm(&self, vm: &mmu::Vm) -> Result<mmu::VmBind> {
-        self.uat.bind(vm)
+        let ret = self.uat.bind(vm);
+        if matches!(ret, Err(e) if e == ETIMEDOUT) {
+            self.handle_handoff_timeout();
+        }
+        ret
+    }
+
+    fn process_deferred_unmaps(&self) {
+        self.uat.process_deferred_unmaps();
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn new_queue(
         &self,
         vm: mmu::Vm,
@@ -1181,6 +1727,9 @@ fn new_queue(
         ualloc_priv: Arc<Mutex<alloc::DefaultAllocator>>,
         priority: u32,
         caps: u32,
+        name: Option<CString>,
+        initial_tvb_blocks: Option<usize>,
+        timeout_ms: u32,
     ) -> Result<Box<dyn queue::Queue>> {
         let mut kalloc = self.alloc();
         let id = self.ids.queue.next();
@@ -1191,10 +1740,14 @@ fn new_queue(
             ualloc,
             ualloc_priv,
             self.event_manager.clone(),
+            self.power_hints.clone(),
             &self.buffer_mgr,
             id,
             priority,
             caps,
+            name,
+            initial_tvb_blocks,
+            timeout_ms,
         )?)?)
     }
 
@@ -1204,7 +1757,7 @@ fn kick_firmware(&self) -> Result {
         }
 
         let mut guard = self.rtkit.lock();
-        let rtk = guard.as_mut().unwrap();
+        let rtk = guard.as_mut().ok_or(ENODEV)?;
         rtk.send_message(EP_DOORBELL, MSG_TX_DOORBELL | DOORBELL_KICKFW)?;
 
         Ok(())
@@ -1245,7 +1798,7 @@ fn flush_fw_cache(&self) -> Result {
         let token = txch.device_control.send(&dc);
         {
             let mut guard = self.rtkit.lock();
-            let rtk = guard.as_mut().unwrap();
+            let rtk = guard.as_mut().ok_or(ENODEV)?;
             rtk.send_message(EP_DOORBELL, MSG_TX_DOORBELL | DOORBELL_DEVCTRL)?;
         }
 
@@ -1273,7 +1826,7 @@ fn handle_timeout(&self, counter: u32, event_slot: i32) {
             Some(info) => workqueue::WorkError::Fault(info),
             None => workqueue::WorkError::Timeout,
         };
-        self.mark_pending_events(event_slot.try_into().ok(), error);
+        self.mark_pending_events(event_slot.try_into().ok(), None, error);
         self.recover();
     }
 
@@ -1285,11 +1838,20 @@ fn handle_fault(&self) {
         dev_err!(self.dev, ".'|  _-_-  |'.\n");
         dev_err!(self.dev, "  |________|  \n");
         dev_err!(self.dev, "GPU fault nya~!!!!!\n");
-        let error = match self.get_fault_info() {
+        let info = self.get_fault_info();
+        let culprit_vm = {
+            let lock = crate::THIS_MODULE.kernel_param_lock();
+            if *crate::fault_isolation.read(&lock) {
+                info.as_ref().map(|info| info.vm_slot)
+            } else {
+                None
+            }
+        };
+        let error = match info {
             Some(info) => workqueue::WorkError::Fault(info),
             None => workqueue::WorkError::Unknown,
         };
-        self.mark_pending_events(None, error);
+        self.mark_pending_events(None, culprit_vm, error);
         self.recover();
     }
 
@@ -1310,26 +1872,31 @@ fn ack_grow(&self, buffer_slot: u32, vm_slot: u32, counter: u32) {
         txch.device_control.send(&dc);
         {
             let mut guard = self.rtkit.lock();
-            let rtk = guard.as_mut().unwrap();
-            if rtk
-                .send_message(EP_DOORBELL, MSG_TX_DOORBELL | DOORBELL_DEVCTRL)
-                .is_err()
-            {
+            let ok = guard.as_mut().map_or(false, |rtk| {
+                rtk.send_message(EP_DOORBELL, MSG_TX_DOORBELL | DOORBELL_DEVCTRL)
+                    .is_ok()
+            });
+            if !ok {
                 dev_err!(self.dev, "Failed to send TVB Grow Ack command\n");
             }
         }
     }
 
     fn wait_for_poweroff(&self, timeout: usize) -> Result {
-        self.initdata.runtime_pointers.hwdata_a.with(|raw, _inner| {
-            for _i in 0..timeout {
-                if raw.pwr_status.load(Ordering::Relaxed) == 4 {
-                    return Ok(());
-                }
-                coarse_sleep(Duration::from_millis(1));
+        for _i in 0..timeout {
+            if self.poweroff_state() == PWR_STATUS_OFF {
+                return Ok(());
             }
-            Err(ETIMEDOUT)
-        })
+            coarse_sleep(Duration::from_millis(1));
+        }
+        Err(ETIMEDOUT)
+    }
+
+    fn poweroff_state(&self) -> u32 {
+        self.initdata
+            .runtime_pointers
+            .hwdata_a
+            .with(|raw, _inner| raw.pwr_status.load(Ordering::Relaxed))
     }
 
     fn fwctl(&self, msg: fw::channels::FwCtlMsg) -> Result {
@@ -1341,7 +1908,7 @@ fn fwctl(&self, msg: fw::channels::FwCtlMsg) -> Result {
         let token = fwctl.send(&msg);
         {
             let mut guard = self.rtkit.lock();
-            let rtk = guard.as_mut().unwrap();
+            let rtk = guard.as_mut().ok_or(ENODEV)?;
             rtk.send_message(EP_DOORBELL, MSG_FWCTL)?;
         }
         fwctl.wait_for(token)?;
@@ -1388,6 +1955,247 @@ fn free_context(&self, ctx: Box<fw::types::GpuObject<fw::workqueue::GpuContextDa
     fn is_crashed(&self) -> bool {
         self.crashed.load(Ordering::Relaxed)
     }
+
+    fn fw_heartbeat(&self) -> u64 {
+        self.fw_heartbeat.load(Ordering::Relaxed)
+    }
+
+    fn fw_stalled_for(&self) -> Option<Duration> {
+        let pending = self
+            .initdata
+            .globals
+            .with(|raw, _inner| raw.pending_submissions.load(Ordering::Relaxed));
+        if pending == 0 {
+            return None;
+        }
+
+        let progress = self
+            .event_manager
+            .last_signal_time()
+            .max(self.work_pending_since.load(Ordering::Relaxed));
+        // SAFETY: ktime_get() is always safe to call.
+        let now = unsafe { bindings::ktime_get() };
+        Some(Duration::from_nanos((now - progress).max(0) as u64))
+    }
+
+    fn fw_uptime(&self) -> Option<Duration> {
+        self.fw_boot_time.lock().map(|t| t.elapsed())
+    }
+
+    fn fw_errors(&self) -> channel::FwErrors {
+        self.rx_channels.lock().fw_log.errors()
+    }
+
+    fn engine_stats(&self) -> EngineStats {
+        let stats = &self.initdata.runtime_pointers.stats;
+
+        // The firmware updates these counters at any time. They are naturally aligned 32-bit
+        // fields, so a volatile read is single-copy atomic and cannot observe a torn value.
+        // SAFETY: The pointers come from valid references to the firmware structures.
+        EngineStats {
+            vertex_cmds: stats
+                .vtx
+                .with(|raw, _inner| unsafe { core::ptr::read_volatile(&raw.total_cmds) }),
+            fragment_cmds: stats
+                .frag
+                .with(|raw, _inner| unsafe { core::ptr::read_volatile(&raw.total_cmds) }),
+        }
+    }
+
+    fn power_estimate_mw(&self) -> Option<u32> {
+        // This is the filtered power value the firmware's power controller works with, updated
+        // on every sample period. It is a naturally aligned 32-bit field, so a volatile read is
+        // single-copy atomic against firmware updates. The firmware leaves it at zero until the
+        // first sample, and a running GPU never draws zero power, so treat that as unavailable.
+        // SAFETY: The pointer comes from a valid reference to the firmware structure.
+        let mw = self
+            .initdata
+            .runtime_pointers
+            .hwdata_a
+            .with(|raw, _inner| unsafe { core::ptr::read_volatile(&raw.avg_power_mw) });
+
+        if mw == 0 {
+            None
+        } else {
+            Some(mw)
+        }
+    }
+
+    fn clocks(&self) -> ClockInfo {
+        // The firmware does not report a measured clock, only the pstate it asked for and the one
+        // it actually switched to (which lags behind or stays lower under power/thermal limits).
+        // These are naturally aligned 32-bit fields, so volatile reads are single-copy atomic.
+        // SAFETY: The pointers come from a valid reference to the firmware structure.
+        let (requested_pstate, actual_pstate) =
+            self.initdata
+                .runtime_pointers
+                .hwdata_a
+                .with(|raw, _inner| unsafe {
+                    (
+                        core::ptr::read_volatile(&raw.tgt_pstate),
+                        core::ptr::read_volatile(&raw.actual_pstate),
+                    )
+                });
+
+        let freq = |ps: u32| {
+            self.dyncfg
+                .pwr
+                .perf_states
+                .get(ps as usize)
+                .map_or(0, |ps| ps.freq_hz)
+        };
+
+        ClockInfo {
+            requested_pstate,
+            actual_pstate,
+            requested_freq_hz: freq(requested_pstate),
+            actual_freq_hz: freq(actual_pstate),
+        }
+    }
+
+    fn event_manager(&self) -> &event::EventManager {
+        &self.event_manager
+    }
+
+    fn register_client(&self, stats: Arc<ClientStats>) -> Result {
+        let mut clients = self.clients.lock();
+
+        clients.retain(|c| !c.closed.load(Ordering::Relaxed));
+        clients.try_push(stats)?;
+        Ok(())
+    }
+
+    fn clients(&self) -> Result<Vec<Arc<ClientStats>>> {
+        let mut clients = self.clients.lock();
+
+        clients.retain(|c| !c.closed.load(Ordering::Relaxed));
+        let mut ret = Vec::try_with_capacity(clients.len())?;
+        for client in clients.iter() {
+            ret.try_push(client.clone())?;
+        }
+        Ok(ret)
+    }
+
+    fn audit_log(&self) -> &Mutex<audit::AuditLog> {
+        &self.audit_log
+    }
+
+    fn throttle_temp(&self) -> u32 {
+        *self.throttle_temp_c.lock()
+    }
+
+    fn set_throttle_temp(&self, temp_c: u32) -> Result<u32> {
+        if self.is_crashed() {
+            return Err(ENODEV);
+        }
+
+        // Never allow raising the throttle point above what the device tree specifies for this
+        // machine, since that could damage the hardware. Lowering it is always safe.
+        let max = self.dyncfg.pwr.fast_die0_release_temp;
+        let temp_c = temp_c.clamp(MIN_THROTTLE_TEMP_C.min(max), max);
+        let temp_cc = 100 * temp_c;
+
+        let mut cur_temp_c = self.throttle_temp_c.lock();
+
+        dev_info!(
+            self.dev,
+            "Setting GPU throttle temperature to {} C\n",
+            temp_c
+        );
+
+        // The firmware keeps the value in two places, so update both. Holding the lock makes sure
+        // concurrent callers cannot leave them disagreeing.
+        self.initdata.runtime_pointers.hwdata_a.with(|raw, _inner| {
+            raw.fast_die0_release_temp_cc
+                .store(temp_cc, Ordering::Relaxed)
+        });
+        self.initdata
+            .globals
+            .with(|raw, _inner| raw.fast_die0_release_temp.store(temp_cc, Ordering::Relaxed));
+
+        *cur_temp_c = temp_c;
+
+        // Make sure the firmware does not keep using a stale cached copy.
+        self.flush_fw_cache()?;
+
+        Ok(temp_c)
+    }
+
+    fn health(&self) -> HealthInfo {
+        // The values come from different places and cannot be read atomically as a whole, so read
+        // them back to back to keep the window in which they can change as small as possible.
+        let (halted, halt_count) = self.initdata.fw_status.with(|raw, _inner| {
+            (
+                raw.flags.halted.load(Ordering::Relaxed) != 0,
+                raw.flags.halt_count.load(Ordering::Relaxed),
+            )
+        });
+        let pending_submissions = self
+            .initdata
+            .globals
+            .with(|raw, _inner| raw.pending_submissions.load(Ordering::Acquire));
+
+        HealthInfo {
+            crashed: self.is_crashed(),
+            halted,
+            halt_count,
+            recovery_count: self.recoveries.load(Ordering::Relaxed),
+            actual_pstate: self.clocks().actual_pstate,
+            pending_submissions,
+            fw_heartbeat: self.fw_heartbeat(),
+            fw_uptime: self.fw_uptime(),
+        }
+    }
+
+    fn shutdown(&self) {
+        dev_info!(self.dev, "Shutting down GPU...\n");
+
+        // Refuse any new work. Every submission path checks this before touching the firmware.
+        let crashed = self.crashed.swap(true, Ordering::SeqCst);
+
+        if !crashed {
+            // Give in-flight work a chance to complete before we pull the rug out.
+            let start = clock::KernelTime::now();
+            loop {
+                let pending = self
+                    .initdata
+                    .globals
+                    .with(|raw, _inner| raw.pending_submissions.load(Ordering::Acquire));
+                if pending == 0 {
+                    break;
+                }
+                if start.elapsed() > SHUTDOWN_TIMEOUT {
+                    dev_warn!(
+                        self.dev,
+                        "Timed out waiting for {} pending submissions, failing them\n",
+                        pending
+                    );
+                    break;
+                }
+                coarse_sleep(Duration::from_millis(1));
+            }
+
+            let timeout = SHUTDOWN_TIMEOUT.as_millis() as usize;
+            if self.wait_for_poweroff(timeout).is_err() {
+                dev_warn!(self.dev, "GPU did not go idle before shutdown\n");
+            }
+        }
+
+        // Anything still outstanding will never complete now.
+        self.event_manager.fail_all(workqueue::WorkError::NoDevice);
+
+        // Park the coprocessor, then free the RTKit instance. This stops all mailbox callbacks
+        // and drops the reference RTKit holds on us, so the UAT, allocators and channels can be
+        // freed once the device data goes away, without the firmware accessing them.
+        let rtkit = self.rtkit.lock().take();
+        if let Some(mut rtk) = rtkit {
+            if rtk.shutdown().is_err() {
+                dev_err!(self.dev, "Failed to shut down GPU firmware\n");
+            }
+        }
+
+        dev_info!(self.dev, "GPU shut down\n");
+    }
 }
 
 #[versions(AGX)]
@@ -12,6 +12,7 @@
 use kernel::prelude::*;
 
 const MAX_POWERZONES: usize = 5;
+const MAX_CSAFR_PSTATES: usize = 16;
 
 pub(crate) mod t600x;
 pub(crate) mod t602x;
@@ -194,6 +195,46 @@ pub(crate) struct HwRenderConfig {
     pub(crate) tiling_control: u32,
 }
 
+/// Hardware limits reported to userspace, which vary by GPU generation.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct HwLimits {
+    /// Maximum 1D/2D texture dimension in pixels.
+    pub(crate) max_texture_dim: u32,
+    /// Maximum 3D texture dimension in pixels.
+    pub(crate) max_texture_dim_3d: u32,
+    /// Maximum number of texture array layers.
+    pub(crate) max_array_layers: u32,
+    /// Maximum render target width and height in pixels.
+    pub(crate) max_framebuffer_dim: u32,
+    /// Maximum number of layers for layered rendering.
+    pub(crate) max_framebuffer_layers: u32,
+    /// Maximum supported MSAA sample count.
+    pub(crate) max_samples: u32,
+    /// Number of threads per SIMD group.
+    pub(crate) simd_width: u32,
+    /// Maximum number of threads per compute threadgroup.
+    pub(crate) max_threads_per_threadgroup: u32,
+    /// Threadgroup (shared) memory available to a single threadgroup, in bytes.
+    pub(crate) threadgroup_memory_size: u32,
+}
+
+/// Hardware limits for all supported GPUs (G13 and G14).
+///
+/// These are the limits Apple's Metal feature set tables list for the Apple7 (G13) and Apple8
+/// (G14) GPU families, which are identical for every value here. Add a separate table if a
+/// generation ever differs.
+pub(crate) const HW_LIMITS: HwLimits = HwLimits {
+    max_texture_dim: 16384,
+    max_texture_dim_3d: 2048,
+    max_array_layers: 2048,
+    max_framebuffer_dim: 16384,
+    max_framebuffer_layers: 2048,
+    max_samples: 4,
+    simd_width: 32,
+    max_threads_per_threadgroup: 1024,
+    threadgroup_memory_size: 32768,
+};
+
 #[derive(Debug)]
 pub(crate) struct HwConfigShared2Curves {
     pub(crate) t1_coef: u32,
@@ -257,6 +298,8 @@ pub(crate) struct HwConfig {
 
     /// Rendering-relevant configuration.
     pub(crate) render: HwRenderConfig,
+    /// Hardware limits reported to userspace.
+    pub(crate) limits: HwLimits,
 
     /// Misc HWDataA field values.
     pub(crate) da: HwConfigA,
@@ -580,14 +623,41 @@ macro_rules! prop {
         }
 
         let csafr = if cfg.has_csafr {
-            Some(CsAfrPwrConfig {
+            let csafr = CsAfrPwrConfig {
                 perf_states_cs: Self::load_opp(dev, c_str!("apple,cs-opp"), cfg, false)?,
                 perf_states_afr: Self::load_opp(dev, c_str!("apple,afr-opp"), cfg, false)?,
                 leak_coef_cs: prop!("apple,cs-leak-coef"),
                 leak_coef_afr: prop!("apple,afr-leak-coef"),
                 min_sram_microvolt: prop!("apple,csafr-min-sram-microvolt"),
-            })
+            };
+
+            if csafr.perf_states_cs.len() > MAX_CSAFR_PSTATES {
+                dev_err!(dev, "Too many apple,cs-opp states\n");
+                return Err(EINVAL);
+            }
+            if csafr.perf_states_afr.len() > MAX_CSAFR_PSTATES {
+                dev_err!(dev, "Too many apple,afr-opp states\n");
+                return Err(EINVAL);
+            }
+            if csafr.leak_coef_cs.len() != cfg.num_dies as usize {
+                dev_err!(dev, "Invalid apple,cs-leak-coef\n");
+                return Err(EINVAL);
+            }
+            if csafr.leak_coef_afr.len() != cfg.num_dies as usize {
+                dev_err!(dev, "Invalid apple,afr-leak-coef\n");
+                return Err(EINVAL);
+            }
+
+            Some(csafr)
         } else {
+            if node.find_property(c_str!("apple,cs-opp")).is_some()
+                || node.find_property(c_str!("apple,afr-opp")).is_some()
+            {
+                dev_warn!(
+                    dev,
+                    "Ignoring CS/AFR power configuration on a GPU without CS/AFR support\n"
+                );
+            }
             None
         };
 
@@ -74,6 +74,7 @@
     render: HwRenderConfig {
         tiling_control: 0xa540,
     },
+    limits: HW_LIMITS,
 
     da: HwConfigA {
         unk_87c: 900,
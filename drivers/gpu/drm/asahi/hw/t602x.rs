@@ -77,6 +77,7 @@
     render: HwRenderConfig {
         tiling_control: 0x180340,
     },
+    limits: HW_LIMITS,
 
     da: HwConfigA {
         unk_87c: 500,
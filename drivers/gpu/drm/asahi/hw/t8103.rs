@@ -32,6 +32,7 @@
         // bit 0: disable clustering (always)
         tiling_control: 0xa041,
     },
+    limits: HW_LIMITS,
 
     da: HwConfigA {
         unk_87c: -220,
@@ -32,6 +32,7 @@
         // TODO: this is unused here, may be present in newer FW
         tiling_control: 0xa041,
     },
+    limits: HW_LIMITS,
 
     da: HwConfigA {
         unk_87c: 900,
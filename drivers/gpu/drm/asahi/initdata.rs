@@ -11,6 +11,7 @@
 //! Many of these structures are poorly understood, so there are lots of hardcoded unknown values
 //! derived from observing the InitData structures that macOS generates.
 
+use crate::debug::*;
 use crate::f32;
 use crate::fw::initdata::*;
 use crate::fw::types::*;
@@ -308,7 +309,7 @@ fn hwdata_a(&mut self) -> Result<GpuObject<HwDataA::ver>> {
                     fast_die0_sensor_mask: U64(cfg.fast_sensor_mask[0]),
                     #[ver(G >= G14X)]
                     fast_die1_sensor_mask: U64(cfg.fast_sensor_mask[1]),
-                    fast_die0_release_temp_cc: 100 * pwr.fast_die0_release_temp,
+                    fast_die0_release_temp_cc: AtomicU32::new(100 * pwr.fast_die0_release_temp),
                     unk_87c: cfg.da.unk_87c,
                     unk_880: 0x4,
                     unk_894: f32!(1.0),
@@ -678,7 +679,7 @@ fn globals(&mut self) -> Result<GpuObject<Globals::ver>> {
                     avg_power_min_duty_cycle: pwr.avg_power_min_duty_cycle,
                     avg_power_target_filter_tc: pwr.avg_power_target_filter_tc,
                     unk_89bc: cfg.da.unk_8cc,
-                    fast_die0_release_temp: 100 * pwr.fast_die0_release_temp,
+                    fast_die0_release_temp: AtomicU32::new(100 * pwr.fast_die0_release_temp),
                     unk_89c4: cfg.da.unk_87c,
                     fast_die0_prop_tgt_delta: 100 * pwr.fast_die0_prop_tgt_delta,
                     fast_die0_kp: pwr.fast_die0_proportional_gain,
@@ -914,6 +915,16 @@ pub(crate) fn build(&mut self) -> Result<Box<GpuObject<InitData::ver>>> {
                 })
             },
         )?;
+
+        if debug_enabled(DebugFlags::DumpInitData) {
+            obj.hexdump("InitData");
+            obj.runtime_pointers.hexdump("RuntimePointers");
+            obj.runtime_pointers.hwdata_a.hexdump("HwDataA");
+            obj.runtime_pointers.hwdata_b.hexdump("HwDataB");
+            obj.globals.hexdump("Globals");
+            obj.fw_status.hexdump("FwStatus");
+        }
+
         Ok(Box::try_new(obj)?)
     }
 }
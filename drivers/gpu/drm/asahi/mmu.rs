@@ -13,7 +13,9 @@
 use core::fmt::Debug;
 use core::mem::size_of;
 use core::ptr::NonNull;
-use core::sync::atomic::{fence, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use core::sync::atomic::{
+    fence, AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering,
+};
 use core::time::Duration;
 
 use kernel::{
@@ -30,6 +32,7 @@
     },
     time::{clock, Now},
     types::ForeignOwnable,
+    workqueue::{Work, WorkItem},
 };
 
 use crate::debug::*;
@@ -48,6 +51,9 @@
 /// Number of available user contexts
 const UAT_USER_CTX: usize = UAT_NUM_CTX - UAT_USER_CTX_START;
 
+/// Maximum number of pages that can be flushed from the coprocessor cache in one command.
+pub(crate) const MAX_FLUSH_PAGES: usize = 0x10000;
+
 /// Number of bits in a page offset.
 pub(crate) const UAT_PGBIT: usize = 14;
 /// UAT page size.
@@ -66,7 +72,7 @@
 pub(crate) const UAT_IAS_KERN: usize = 36;
 
 /// Lower/user base VA
-const IOVA_USER_BASE: usize = UAT_PGSZ;
+pub(crate) const IOVA_USER_BASE: usize = UAT_PGSZ;
 /// Lower/user top VA
 const IOVA_USER_TOP: usize = (1 << UAT_IAS) - 1;
 /// Upper/kernel base VA
@@ -80,6 +86,133 @@
 const TTBR_ASID_SHIFT: usize = 48;
 
 const PTE_TABLE: u64 = 0x3; // BIT(0) | BIT(1)
+const PTE_VALID: u64 = 0x1; // BIT(0)
+/// Output address field of a PTE (bits 47:14 for the 16K granule).
+const PTE_OA_MASK: u64 = 0x0000_ffff_ffff_c000;
+
+/// Number of page table levels for user Vms.
+const UAT_LEVELS: usize = 3;
+/// Number of IOVA bits translated by each page table level.
+const UAT_LEVEL_BITS: usize = UAT_PGBIT - 3;
+
+/// Human-readable page table dump writer, used by [`Vm::dump_page_table()`].
+///
+/// Physically and virtually contiguous leaf entries with the same attributes are coalesced into a
+/// single line. Output is silently truncated at the size limit.
+struct PtDump {
+    out: Vec<u8>,
+    limit: usize,
+    truncated: bool,
+    /// Pending run of leaf entries: (IOVA start, IOVA end, physical start, attributes)
+    run: Option<(usize, usize, u64, u64)>,
+}
+
+impl core::fmt::Write for PtDump {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if self.truncated || self.out.len() + s.len() > self.limit {
+            self.truncated = true;
+            return Ok(());
+        }
+        self.out
+            .try_extend_from_slice(s.as_bytes())
+            .map_err(|_| core::fmt::Error)
+    }
+}
+
+impl PtDump {
+    /// Emit the pending run of leaf entries, if any.
+    fn flush(&mut self) -> core::fmt::Result {
+        use core::fmt::Write;
+
+        if let Some((start, end, pa, attrs)) = self.run.take() {
+            writeln!(
+                self,
+                "{:#013x}-{:#013x} -> {:#013x} attrs {:#x}",
+                start,
+                end - 1,
+                pa,
+                attrs
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Record a valid leaf entry mapping one page.
+    fn leaf(&mut self, iova: usize, pte: u64) -> core::fmt::Result {
+        let pa = pte & PTE_OA_MASK;
+        let attrs = pte & !PTE_OA_MASK;
+
+        if let Some((start, end, run_pa, run_attrs)) = self.run.as_mut() {
+            if *end == iova && *run_pa + (*end - *start) as u64 == pa && *run_attrs == attrs {
+                *end += UAT_PGSZ;
+                return Ok(());
+            }
+        }
+
+        self.flush()?;
+        self.run = Some((iova, iova + UAT_PGSZ, pa, attrs));
+        Ok(())
+    }
+
+    /// Walk one page table page at the given level, covering IOVAs starting at `base`.
+    ///
+    /// The caller must hold the Vm lock, so that no page table pages can be freed under us.
+    fn walk(&mut self, table: u64, level: usize, base: usize) -> Result {
+        use core::fmt::Write;
+
+        let shift = UAT_PGBIT + UAT_LEVEL_BITS * (UAT_LEVELS - 1 - level);
+        // The top level only covers what is left of the input address space.
+        let entries = if level == 0 {
+            1 << (UAT_IAS - shift)
+        } else {
+            UAT_NPTE
+        };
+
+        // Page table pages are regular RAM allocated by io_pgtable, so this just returns their
+        // linear map address.
+        // SAFETY: The table address comes from a valid PTE (or the TTBR) of a live page table.
+        let map = unsafe {
+            bindings::memremap(
+                table as bindings::resource_size_t,
+                UAT_PGSZ,
+                bindings::MEMREMAP_WB.into(),
+            )
+        };
+        if map.is_null() {
+            return Err(ENOMEM);
+        }
+
+        let mut ret = Ok(());
+        for i in 0..entries {
+            // SAFETY: The mapping covers a whole page table page of UAT_NPTE entries.
+            let pte = unsafe { &*(map as *const Pte).add(i) }.load(Ordering::Relaxed);
+            let iova = base | (i << shift);
+
+            if pte & PTE_VALID == 0 {
+                continue;
+            }
+
+            ret = if level == UAT_LEVELS - 1 {
+                self.leaf(iova, pte).map_err(|_| ENOMEM)
+            } else if pte & PTE_TABLE != PTE_TABLE {
+                // io_pgtable never creates block mappings for the UAT.
+                self.flush()
+                    .and_then(|_| writeln!(self, "L{} {:#013x}: bad entry {:#x}", level, iova, pte))
+                    .map_err(|_| ENOMEM)
+            } else {
+                self.walk(pte & PTE_OA_MASK, level + 1, iova)
+            };
+
+            if ret.is_err() || self.truncated {
+                break;
+            }
+        }
+
+        // SAFETY: `map` was returned by memremap() above.
+        unsafe { bindings::memunmap(map) };
+        ret
+    }
+}
 
 // Mapping protection types
 
@@ -121,6 +254,26 @@
 pub(crate) const PROT_GPU_PRIV_RO: u32 = prot::READ | prot::NOEXEC;
 */
 
+/// Protections that userspace-driven GEM mappings may use.
+///
+/// These are all coherent (`prot::CACHE`, so uncached on the UAT side), since the driver never
+/// does the cache maintenance that noncoherent user mappings would need. The CPU sees GPU writes,
+/// and the GPU sees CPU writes, without any explicit flushes.
+///
+/// `PROT_GPU_SHARED_RO` suits upload buffers that the CPU writes and the GPU only reads, and turns
+/// accidental GPU writes into faults. `PROT_GPU_SHARED_WO` does the same for GPU reads of
+/// output-only buffers.
+const PROT_USER_ALLOWED: [u32; 3] = [PROT_GPU_SHARED_RW, PROT_GPU_SHARED_RO, PROT_GPU_SHARED_WO];
+
+/// Check that a protection is one that userspace-driven GEM mappings may use.
+pub(crate) fn check_user_prot(prot: u32) -> Result {
+    if PROT_USER_ALLOWED.contains(&prot) {
+        Ok(())
+    } else {
+        Err(EINVAL)
+    }
+}
+
 type PhysAddr = bindings::phys_addr_t;
 
 /// A pre-allocated memory region for UAT management
@@ -162,6 +315,10 @@ struct Handoff {
 
 const HANDOFF_SIZE: usize = size_of::<Handoff>();
 
+/// Maximum time to wait for the firmware to release the handoff lock. The firmware only holds it
+/// for very short critical sections, so hitting this means it is wedged.
+const HANDOFF_LOCK_TIMEOUT: Duration = Duration::from_millis(1000);
+
 /// One VM slot in the TTBAT
 #[repr(C)]
 struct SlotTTBS {
@@ -178,8 +335,12 @@ struct SlotTTBS {
 struct VmInner {
     dev: driver::AsahiDevRef,
     is_kernel: bool,
+    /// Per-client counter of mapped bytes, charged along with the global one.
+    client_bytes: Option<Arc<AtomicU64>>,
     min_va: usize,
     max_va: usize,
+    /// Bytes currently mapped in the page table, including mappings owned by the driver.
+    mapped_bytes: usize,
     page_table: AppleUAT<Uat>,
     mm: mm::Allocator<(), MappingInner>,
     uat_inner: Arc<UatInner>,
@@ -242,6 +403,7 @@ fn map_pages(
                 .page_table
                 .map_pages(mapped_iova, paddr, pgsize, left, prot)?;
             assert!(mapped <= left * pgsize);
+            self.mapped_bytes += mapped;
 
             left -= mapped / pgsize;
             paddr += mapped;
@@ -257,6 +419,7 @@ fn unmap_pages(&mut self, mut iova: usize, pgsize: usize, pgcount: usize) -> Res
             let mapped_iova = self.map_iova(iova, pgsize * left)?;
             let unmapped = self.page_table.unmap_pages(mapped_iova, pgsize, left);
             assert!(unmapped <= left * pgsize);
+            self.mapped_bytes = self.mapped_bytes.saturating_sub(unmapped);
 
             left -= unmapped / pgsize;
             iova += unmapped;
@@ -265,6 +428,20 @@ fn unmap_pages(&mut self, mut iova: usize, pgsize: usize, pgcount: usize) -> Res
         Ok(pgcount * pgsize)
     }
 
+    /// Charge `size` bytes of new mappings in this Vm against the global user mapping limit.
+    ///
+    /// Kernel Vms are never charged.
+    fn charge(&self, size: usize) -> Result<MapCharge> {
+        let mut charge = MapCharge {
+            size: 0,
+            client_bytes: self.client_bytes.clone(),
+        };
+        if !self.is_kernel {
+            charge.add(size)?;
+        }
+        Ok(charge)
+    }
+
     /// Map an `mm::Node` representing an mapping in VA space.
     fn map_node(&mut self, node: &mm::Node<(), MappingInner>, prot: u32) -> Result {
         let mut iova = node.start() as usize;
@@ -299,6 +476,86 @@ fn map_node(&mut self, node: &mm::Node<(), MappingInner>, prot: u32) -> Result {
         }
         Ok(())
     }
+
+    /// Map `size` bytes of an `SGTable`, starting at byte `offset` into it, at a given IOVA.
+    ///
+    /// On failure, any pages mapped by this call are unmapped again.
+    fn map_sgt_range(
+        &mut self,
+        iova: usize,
+        sgt: &gem::SGTable,
+        mut offset: usize,
+        size: usize,
+        prot: u32,
+    ) -> Result {
+        if (iova | offset | size) & UAT_PGMSK != 0 {
+            return Err(EINVAL);
+        }
+
+        let mut mapped = 0;
+        let mut ret = Ok(());
+
+        for range in sgt.iter() {
+            if mapped == size {
+                break;
+            }
+
+            let addr = range.dma_address();
+            let len = range.dma_len();
+
+            if offset >= len {
+                offset -= len;
+                continue;
+            }
+
+            if (addr | len) & UAT_PGMSK != 0 {
+                dev_err!(
+                    self.dev,
+                    "MMU: Mapping {:#x}:{:#x} -> {:#x} is not page-aligned\n",
+                    addr,
+                    len,
+                    iova + mapped
+                );
+                ret = Err(EINVAL);
+                break;
+            }
+
+            let chunk = (len - offset).min(size - mapped);
+
+            mod_dev_dbg!(
+                self.dev,
+                "MMU: map range: {:#x}:{:#x} -> {:#x}\n",
+                addr + offset,
+                chunk,
+                iova + mapped
+            );
+
+            if let Err(e) = self.map_pages(
+                iova + mapped,
+                addr + offset,
+                UAT_PGSZ,
+                chunk >> UAT_PGBIT,
+                prot,
+            ) {
+                ret = Err(e);
+                break;
+            }
+
+            mapped += chunk;
+            offset = 0;
+        }
+
+        if ret.is_ok() && mapped != size {
+            // The object is smaller than the requested range
+            ret = Err(EINVAL);
+        }
+
+        if ret.is_err() && mapped > 0 {
+            let _ = self.unmap_pages(iova, UAT_PGSZ, mapped >> UAT_PGBIT);
+        }
+
+        ret
+    }
 }
 
 /// Shared reference to a virtual memory address space ([`Vm`]).
@@ -320,6 +577,13 @@ impl slotalloc::SlotItem for SlotInner {
 /// Represents a single user of a binding of a [`Vm`] to a slot.
 ///
 /// The number of users is counted, and the slot will be freed when it drops to 0.
+///
+/// While any `VmBind` for a `Vm` is alive, its slot cannot change: the slot guard is only dropped
+/// (and a new slot only allocated) once the count reaches 0, and [`VmBind::slot()`] always returns
+/// the slot captured at bind time. Anything that bakes the slot into firmware structures must keep
+/// a `VmBind` alive for as long as the firmware may use them. Submissions do this by holding one
+/// in the job and in every firmware command object, which are only freed once the command has
+/// completed.
 #[derive(Debug)]
 pub(crate) struct VmBind(Vm, u32);
 
@@ -339,6 +603,7 @@ fn drop(&mut self) {
         mod_pr_debug!("MMU: slot {} active users {}\n", self.1, inner.active_users);
         if inner.active_users == 0 {
             inner.binding = None;
+            inner.uat_inner.bound_vms.fetch_sub(1, Ordering::Relaxed);
         }
     }
 }
@@ -353,6 +618,66 @@ fn clone(&self) -> VmBind {
     }
 }
 
+/// Total bytes currently mapped into all user Vms.
+///
+/// Every mapping is counted, so an object mapped into several Vms (or several times into the same
+/// Vm) is charged once per mapping. This is what the page tables and the GPU actually see, and it
+/// keeps the accounting local to each mapping.
+static USER_MAPPED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// A charge of some number of bytes against the global user mapping limit.
+///
+/// The charge is returned when this is dropped.
+#[derive(Default)]
+struct MapCharge {
+    size: usize,
+    /// Per-client counter that is charged along with the global one, if any.
+    client_bytes: Option<Arc<AtomicU64>>,
+}
+
+impl MapCharge {
+    /// Add `size` bytes to this charge, failing with `ENOMEM` if that would exceed the limit.
+    fn add(&mut self, size: usize) -> Result {
+        let limit = {
+            let lock = crate::THIS_MODULE.kernel_param_lock();
+            *crate::user_mapped_limit_mb.read(&lock) << 20
+        };
+
+        USER_MAPPED_BYTES
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |cur| {
+                let new = cur.checked_add(size as u64)?;
+                if limit != 0 && new > limit {
+                    None
+                } else {
+                    Some(new)
+                }
+            })
+            .map_err(|_| ENOMEM)?;
+
+        if let Some(client_bytes) = self.client_bytes.as_ref() {
+            client_bytes.fetch_add(size as u64, Ordering::Relaxed);
+        }
+        self.size += size;
+        Ok(())
+    }
+
+    /// Return `size` bytes of this charge.
+    fn release(&mut self, size: usize) {
+        let size = size.min(self.size);
+        USER_MAPPED_BYTES.fetch_sub(size as u64, Ordering::Relaxed);
+        if let Some(client_bytes) = self.client_bytes.as_ref() {
+            client_bytes.fetch_sub(size as u64, Ordering::Relaxed);
+        }
+        self.size -= size;
+    }
+}
+
+impl Drop for MapCharge {
+    fn drop(&mut self) {
+        self.release(self.size);
+    }
+}
+
 /// Inner data required for an object mapping into a [`Vm`].
 pub(crate) struct MappingInner {
     owner: Arc<Mutex<VmInner>>,
@@ -360,6 +685,8 @@ pub(crate) struct MappingInner {
     prot: u32,
     mapped_size: usize,
     sgt: Option<gem::SGTable>,
+    /// Charge against the user mapping limit, returned once the node is gone.
+    _charge: MapCharge,
 }
 
 /// An object mapping into a [`Vm`], which reserves the address range from use by other mappings.
@@ -427,7 +754,10 @@ fn remap_uncached_and_flush(&mut self) {
             let ttb = owner.ttb() | TTBR_VALID | (slot as u64) << TTBR_ASID_SHIFT;
 
             let uat_inner = self.0.uat_inner.lock();
-            uat_inner.handoff().lock();
+            if uat_inner.handoff().lock().is_err() {
+                // The firmware is wedged, so it is not going to use this slot anyway.
+                return;
+            }
             let cur_slot = uat_inner.handoff().current_slot();
             let ttb_cur = uat_inner.ttbs()[slot as usize].ttb0.load(Ordering::Relaxed);
             uat_inner.handoff().unlock();
@@ -474,7 +804,7 @@ fn remap_uncached_and_flush(&mut self) {
         let flush = self.0.uat_inner.lock_flush(flush_slot);
         let pages = self.size() >> UAT_PGBIT;
         flush.begin_flush(self.iova() as u64, self.size() as u64);
-        if pages >= 0x10000 {
+        if pages >= MAX_FLUSH_PAGES {
             dev_err!(owner.dev, "MMU: Flush too big ({:#x} pages))\n", pages);
         }
 
@@ -504,6 +834,50 @@ fn remap_uncached_and_flush(&mut self) {
     }
 }
 
+impl Mapping {
+    /// Unmap this mapping later, instead of blocking on the firmware cache flush now.
+    ///
+    /// This is meant for bulk teardown paths that do not need the memory back right away. The
+    /// mapping is queued and dropped from a work item on the system work queue. Until then it
+    /// keeps both its VA range reserved in the `Vm` and its backing pages alive, so neither can be
+    /// reused before the flush has happened.
+    ///
+    /// Uncached mappings need no flush, so they are unmapped immediately. So are kernel `Vm`
+    /// mappings, which are only torn down along with the `Uat` itself, and any mappings unmapped
+    /// after the `Uat` is gone.
+    pub(crate) fn unmap_deferred(self) {
+        // prot::CACHE means "cache coherent" which means *uncached* here.
+        if self.0.prot & prot::CACHE != 0 || self.0.owner.lock().is_kernel {
+            return;
+        }
+
+        let uat_inner = self.0.uat_inner.clone();
+        let work = &uat_inner.deferred_unmaps;
+        let mut deferred = work.inner().mappings.lock();
+        if work.inner().closed.load(Ordering::Relaxed) {
+            // Drop synchronously, outside of the lock
+            drop(deferred);
+            return;
+        }
+        if let Err(e) = deferred.try_reserve(1) {
+            dev_warn!(
+                self.0.owner.lock().dev,
+                "MMU: Failed to defer unmap (err: {:?}), unmapping now\n",
+                e
+            );
+            // Drop (and flush) synchronously, outside of the lock
+            drop(deferred);
+            return;
+        }
+        deferred
+            .try_push(self)
+            .expect("try_push() failed after reserve()");
+        drop(deferred);
+
+        work.queue();
+    }
+}
+
 impl Drop for Mapping {
     fn drop(&mut self) {
         // This is the main unmap function for UAT mappings.
@@ -565,6 +939,220 @@ fn drop(&mut self) {
     }
 }
 
+/// A range of pages bound into a [`SparseMapping`].
+struct SparseBinding {
+    /// IOVA of the first bound page.
+    iova: usize,
+    /// Size of the binding in bytes.
+    size: usize,
+    /// Byte offset of the first bound page within the backing object.
+    offset: usize,
+    /// Backing object pages. This is shared when an unbind splits a binding.
+    sgt: Arc<gem::SGTable>,
+}
+
+/// A reserved VA range in a [`Vm`] with individually bound pages, for sparse residency.
+///
+/// Pages that are not bound have no page table entries, so GPU accesses to them always fault.
+/// Only coherent (`prot::CACHE`) mappings are supported, so unbinding never requires a firmware
+/// cache flush.
+pub(crate) struct SparseMapping {
+    node: mm::Node<(), MappingInner>,
+    /// Bound page ranges, sorted by IOVA and never overlapping.
+    bindings: Vec<SparseBinding>,
+    /// Charge against the user mapping limit for all bound pages.
+    charge: MapCharge,
+}
+
+impl SparseMapping {
+    /// Returns the IOVA base of this sparse range
+    pub(crate) fn iova(&self) -> usize {
+        self.node.start() as usize
+    }
+
+    /// Returns the size of this sparse range in bytes
+    pub(crate) fn size(&self) -> usize {
+        self.node.mapped_size
+    }
+
+    /// Returns whether the given IOVA range lies entirely within this sparse range.
+    pub(crate) fn contains(&self, iova: usize, size: usize) -> bool {
+        iova >= self.iova()
+            && iova
+                .checked_add(size)
+                .map_or(false, |end| end <= self.iova() + self.size())
+    }
+
+    /// Bind `size` bytes of an object's pages, starting at byte `offset` into it, at `iova`.
+    ///
+    /// Returns Err(EBUSY) if any page in the range is already bound.
+    pub(crate) fn bind(
+        &mut self,
+        iova: usize,
+        sgt: gem::SGTable,
+        offset: usize,
+        size: usize,
+    ) -> Result {
+        if size == 0 || (iova | offset | size) & UAT_PGMSK != 0 || !self.contains(iova, size) {
+            return Err(EINVAL);
+        }
+
+        if self
+            .bindings
+            .iter()
+            .any(|b| b.iova < iova + size && iova < b.iova + b.size)
+        {
+            return Err(EBUSY);
+        }
+
+        self.bindings.try_reserve(1)?;
+        let sgt = Arc::try_new(sgt)?;
+
+        let mut owner = self.node.owner.lock();
+        mod_dev_dbg!(
+            owner.dev,
+            "MMU: sparse bind {:#x}:{:#x} (offset {:#x})\n",
+            iova,
+            size,
+            offset
+        );
+        if !owner.is_kernel {
+            self.charge.add(size)?;
+        }
+        if let Err(e) = owner.map_sgt_range(iova, &sgt, offset, size, self.node.prot) {
+            self.charge.release(size);
+            return Err(e);
+        }
+
+        self.bindings
+            .try_push(SparseBinding {
+                iova,
+                size,
+                offset,
+                sgt,
+            })
+            .expect("try_push() failed after reserve()");
+        self.bindings.sort_unstable_by_key(|b| b.iova);
+
+        Ok(())
+    }
+
+    /// Unbind all bound pages within the given IOVA range.
+    ///
+    /// Bindings that only partially overlap the range are split, so the pages outside of it stay
+    /// bound.
+    pub(crate) fn unbind(&mut self, iova: usize, size: usize) -> Result {
+        if size == 0 || (iova | size) & UAT_PGMSK != 0 || !self.contains(iova, size) {
+            return Err(EINVAL);
+        }
+        let end = iova + size;
+
+        // Worst case, one binding is split in two
+        let mut bindings = Vec::new();
+        bindings.try_reserve(self.bindings.len() + 1)?;
+
+        let mut owner = self.node.owner.lock();
+        mod_dev_dbg!(owner.dev, "MMU: sparse unbind {:#x}:{:#x}\n", iova, size);
+
+        for b in self.bindings.iter() {
+            let b_end = b.iova + b.size;
+
+            if b_end <= iova || b.iova >= end {
+                bindings
+                    .try_push(SparseBinding {
+                        sgt: b.sgt.clone(),
+                        ..*b
+                    })
+                    .expect("try_push() failed after reserve()");
+                continue;
+            }
+
+            let start = b.iova.max(iova);
+            let stop = b_end.min(end);
+            self.charge.release(stop - start);
+            if owner
+                .unmap_pages(start, UAT_PGSZ, (stop - start) >> UAT_PGBIT)
+                .is_err()
+            {
+                dev_err!(
+                    owner.dev,
+                    "MMU: sparse unmap {:#x}:{:#x} failed\n",
+                    start,
+                    stop - start
+                );
+            }
+
+            if b.iova < start {
+                bindings
+                    .try_push(SparseBinding {
+                        iova: b.iova,
+                        size: start - b.iova,
+                        offset: b.offset,
+                        sgt: b.sgt.clone(),
+                    })
+                    .expect("try_push() failed after reserve()");
+            }
+            if stop < b_end {
+                bindings
+                    .try_push(SparseBinding {
+                        iova: stop,
+                        size: b_end - stop,
+                        offset: b.offset + (stop - b.iova),
+                        sgt: b.sgt.clone(),
+                    })
+                    .expect("try_push() failed after reserve()");
+            }
+        }
+
+        if let Some(asid) = owner.slot() {
+            mem::tlbi_range(asid as u8, iova, size);
+            mem::sync();
+        }
+
+        // The old bindings may hold the last references to their objects, which could try to
+        // take the VM lock to drop their own mappings. Drop them after the TLB flush, unlocked.
+        drop(owner);
+        self.bindings = bindings;
+        Ok(())
+    }
+}
+
+impl Drop for SparseMapping {
+    fn drop(&mut self) {
+        let mut owner = self.node.owner.lock();
+        mod_dev_dbg!(
+            owner.dev,
+            "MMU: sparse release {:#x}:{:#x}\n",
+            self.iova(),
+            self.size()
+        );
+
+        for b in self.bindings.iter() {
+            if owner
+                .unmap_pages(b.iova, UAT_PGSZ, b.size >> UAT_PGBIT)
+                .is_err()
+            {
+                dev_err!(
+                    owner.dev,
+                    "MMU: sparse unmap {:#x}:{:#x} failed\n",
+                    b.iova,
+                    b.size
+                );
+            }
+        }
+
+        if !self.bindings.is_empty() {
+            if let Some(asid) = owner.slot() {
+                mem::tlbi_range(asid as u8, self.iova(), self.size());
+                mem::sync();
+            }
+        }
+
+        // The bindings are dropped after this, once the pages are no longer mapped and the
+        // VM lock has been released.
+    }
+}
+
 /// Shared UAT global data structures
 struct UatShared {
     kernel_ttb1: u64,
@@ -590,6 +1178,32 @@ fn handoff(&self) -> &Handoff {
 // SAFETY: Nothing here is unsafe to send across threads.
 unsafe impl Send for UatShared {}
 
+/// Cached mappings waiting to be flushed and unmapped, see [`Mapping::unmap_deferred()`].
+#[pin_data]
+struct DeferredUnmaps {
+    #[pin]
+    mappings: Mutex<Vec<Mapping>>,
+    /// Set once the `Uat` is torn down. From then on mappings are unmapped synchronously, so the
+    /// work never drops the last reference to the `UatInner` that owns it.
+    closed: AtomicBool,
+}
+
+impl DeferredUnmaps {
+    /// Flush and unmap all queued mappings, returning how many there were.
+    fn process(&self) -> usize {
+        let mut deferred = Vec::new();
+        core::mem::swap(&mut *self.mappings.lock(), &mut deferred);
+        deferred.len()
+        // Dropped here, outside the lock
+    }
+}
+
+impl WorkItem for DeferredUnmaps {
+    fn run(&self) {
+        self.process();
+    }
+}
+
 /// Inner data for the top-level UAT instance.
 #[pin_data]
 struct UatInner {
@@ -597,6 +1211,13 @@ struct UatInner {
     shared: Mutex<UatShared>,
     #[pin]
     handoff_flush: [Mutex<HandoffFlush>; UAT_NUM_CTX + 1],
+    /// Work item that flushes and unmaps deferred mappings.
+    #[pin]
+    deferred_unmaps: Work<DeferredUnmaps>,
+    /// Number of user Vms currently holding a slot.
+    bound_vms: AtomicUsize,
+    /// Whether the `max_bound_vms` limit has been reported as reached.
+    bound_vms_warned: AtomicBool,
 }
 
 impl UatInner {
@@ -633,19 +1254,35 @@ fn drop(&mut self) {
 
 impl Handoff {
     /// Lock the handoff region from firmware access
-    fn lock(&self) {
+    ///
+    /// Returns `ETIMEDOUT` if the firmware does not release the lock within
+    /// `HANDOFF_LOCK_TIMEOUT`, in which case the lock is not held.
+    fn lock(&self) -> Result {
+        let start = clock::KernelTime::now();
+
         self.lock_ap.store(1, Ordering::Relaxed);
         fence(Ordering::SeqCst);
 
         while self.lock_fw.load(Ordering::Relaxed) != 0 {
             if self.turn.load(Ordering::Relaxed) != 0 {
                 self.lock_ap.store(0, Ordering::Relaxed);
-                while self.turn.load(Ordering::Relaxed) != 0 {}
+                while self.turn.load(Ordering::Relaxed) != 0 {
+                    if start.elapsed() > HANDOFF_LOCK_TIMEOUT {
+                        pr_err!("Handoff: Timed out waiting for our turn (firmware hung?)\n");
+                        return Err(ETIMEDOUT);
+                    }
+                }
                 self.lock_ap.store(1, Ordering::Relaxed);
                 fence(Ordering::SeqCst);
             }
+            if start.elapsed() > HANDOFF_LOCK_TIMEOUT {
+                self.lock_ap.store(0, Ordering::Release);
+                pr_err!("Handoff: Timed out waiting for the firmware lock (firmware hung?)\n");
+                return Err(ETIMEDOUT);
+            }
         }
         fence(Ordering::Acquire);
+        Ok(())
     }
 
     /// Unlock the handoff region, allowing firmware access
@@ -674,14 +1311,14 @@ fn init(&self) -> Result {
         let start = clock::KernelTime::now();
         const TIMEOUT: Duration = Duration::from_millis(1000);
 
-        self.lock();
+        self.lock()?;
         while start.elapsed() < TIMEOUT {
             if self.magic_fw.load(Ordering::Relaxed) == PPL_MAGIC {
                 break;
             } else {
                 self.unlock();
                 delay::coarse_sleep(Duration::from_millis(10));
-                self.lock();
+                self.lock()?;
             }
         }
 
@@ -765,6 +1402,7 @@ fn new(
         uat_inner: Arc<UatInner>,
         cfg: &'static hw::HwConfig,
         is_kernel: bool,
+        client_bytes: Option<Arc<AtomicU64>>,
         id: u64,
         file_id: u64,
     ) -> Result<Vm> {
@@ -800,7 +1438,9 @@ fn new(
                     dev: dev.into(),
                     min_va,
                     max_va,
+                    mapped_bytes: 0,
                     is_kernel,
+                    client_bytes,
                     page_table,
                     mm,
                     uat_inner,
@@ -834,6 +1474,7 @@ pub(crate) fn map_in_range(
         let mut inner = self.inner.lock();
 
         let uat_inner = inner.uat_inner.clone();
+        let charge = inner.charge(size)?;
         let node = inner.mm.insert_node_in_range(
             MappingInner {
                 owner: self.inner.clone(),
@@ -841,6 +1482,7 @@ pub(crate) fn map_in_range(
                 prot,
                 sgt: Some(sgt),
                 mapped_size: size,
+                _charge: charge,
             },
             (size + if guard { UAT_PGSZ } else { 0 }) as u64, // Add guard page
             alignment,
@@ -867,6 +1509,7 @@ pub(crate) fn map_at(
         let mut inner = self.inner.lock();
 
         let uat_inner = inner.uat_inner.clone();
+        let charge = inner.charge(size)?;
         let node = inner.mm.reserve_node(
             MappingInner {
                 owner: self.inner.clone(),
@@ -874,6 +1517,7 @@ pub(crate) fn map_at(
                 prot,
                 sgt: Some(sgt),
                 mapped_size: size,
+                _charge: charge,
             },
             addr,
             (size + if guard { UAT_PGSZ } else { 0 }) as u64, // Add guard page
@@ -915,6 +1559,7 @@ pub(crate) fn map_io(&self, iova: u64, phys: usize, size: usize, prot: u32) -> R
                 prot,
                 sgt: None,
                 mapped_size: size,
+                _charge: MapCharge::default(),
             },
             iova,
             size as u64,
@@ -926,6 +1571,100 @@ pub(crate) fn map_io(&self, iova: u64, phys: usize, size: usize, prot: u32) -> R
         Ok(Mapping(node))
     }
 
+    /// Reserve a VA range in this Vm for sparse bindings, with no pages initially bound.
+    pub(crate) fn reserve_sparse(
+        &self,
+        iova: u64,
+        size: usize,
+        prot: u32,
+    ) -> Result<SparseMapping> {
+        if size == 0 || (iova as usize | size) & UAT_PGMSK != 0 {
+            return Err(EINVAL);
+        }
+        // Sparse ranges are only created by userspace, and unbinding relies on them being
+        // coherent.
+        check_user_prot(prot)?;
+
+        let mut inner = self.inner.lock();
+
+        let uat_inner = inner.uat_inner.clone();
+        let node = inner.mm.reserve_node(
+            MappingInner {
+                owner: self.inner.clone(),
+                uat_inner,
+                prot,
+                sgt: None,
+                mapped_size: size,
+                _charge: MapCharge::default(),
+            },
+            iova,
+            size as u64,
+            0,
+        )?;
+
+        mod_dev_dbg!(inner.dev, "MMU: sparse reserve {:#x}:{:#x}\n", iova, size);
+
+        let charge = inner.charge(0)?;
+
+        Ok(SparseMapping {
+            node,
+            bindings: Vec::new(),
+            charge,
+        })
+    }
+
+    /// Returns the protection flags of the mapping containing the given IOVA, if the page
+    /// containing it is currently mapped in this Vm.
+    ///
+    /// The flags are the ones the mapping was created with, not the ones in the page table, which
+    /// may have been changed to make a cached mapping uncached before unmapping it.
+    pub(crate) fn mapped_prot(&self, iova: u64) -> Option<u32> {
+        let mut inner = self.inner.lock();
+
+        let mapped_iova = inner.map_iova(iova as usize, 1).ok()?;
+        inner.page_table.iova_to_phys(mapped_iova)?;
+
+        inner
+            .mm
+            .with_node_at(iova, |mapping| mapping.map(|m| m.prot))
+    }
+
+    /// Dump the page table of this (user) Vm in human-readable form.
+    ///
+    /// This walks the actual page table rather than the mapping list, so it shows exactly what the
+    /// GPU sees. Each line describes a contiguous range of mapped pages with its physical address
+    /// and raw PTE attribute bits. The output is limited to `limit` bytes; the second return value
+    /// indicates whether it was truncated.
+    pub(crate) fn dump_page_table(&self, limit: usize) -> Result<(Vec<u8>, bool)> {
+        let mut dump = PtDump {
+            out: Vec::try_with_capacity(limit)?,
+            limit,
+            truncated: false,
+            run: None,
+        };
+
+        // Hold the Vm lock for the whole walk, so page table pages cannot be freed under us.
+        let inner = self.inner.lock();
+        if inner.is_kernel {
+            return Err(EINVAL);
+        }
+
+        dump.walk(inner.ttb(), 0, 0)?;
+        core::mem::drop(inner);
+
+        dump.flush().map_err(|_| ENOMEM)?;
+
+        Ok((dump.out, dump.truncated))
+    }
+
+    /// Returns the number of bytes currently mapped in this Vm's page table.
+    ///
+    /// This is tracked at the page table level, so it includes driver-owned mappings and is not
+    /// affected by remapping (e.g. when cached mappings are remapped uncached before unmapping).
+    pub(crate) fn mapped_bytes(&self) -> usize {
+        self.inner.lock().mapped_bytes
+    }
+
     /// Returns the unique ID of this Vm
     pub(crate) fn id(&self) -> u64 {
         self.id
@@ -953,7 +1692,9 @@ fn drop(&mut self) {
             let ttb = self.ttb() | TTBR_VALID | (idx as u64) << TTBR_ASID_SHIFT;
 
             let uat_inner = self.uat_inner.lock();
-            uat_inner.handoff().lock();
+            // If the firmware is wedged, clear the TTBs anyway. Leaving them pointing to page
+            // tables that are about to be freed would be worse than racing a dead firmware.
+            let locked = uat_inner.handoff().lock().is_ok();
             let handoff_cur = uat_inner.handoff().current_slot();
             let ttb_cur = uat_inner.ttbs()[idx].ttb0.load(Ordering::SeqCst);
             let inval = ttb_cur == ttb;
@@ -967,7 +1708,9 @@ fn drop(&mut self) {
                 uat_inner.ttbs()[idx].ttb0.store(0, Ordering::SeqCst);
                 uat_inner.ttbs()[idx].ttb1.store(0, Ordering::SeqCst);
             }
-            uat_inner.handoff().unlock();
+            if locked {
+                uat_inner.handoff().unlock();
+            }
             core::mem::drop(uat_inner);
 
             // In principle we dropped all the Mappings already, but we might as
@@ -1082,47 +1825,87 @@ pub(crate) fn ttb_base(&self) -> u64 {
         inner.ttbs_rgn.base
     }
 
+    /// Account for a new Vm binding against the `max_bound_vms` limit.
+    ///
+    /// Returns `EBUSY` if the limit is reached, so that userspace can back off instead of the
+    /// slot allocator thrashing (or blocking) on the remaining slots.
+    fn reserve_bound_vm(&self) -> Result {
+        let limit = {
+            let lock = crate::THIS_MODULE.kernel_param_lock();
+            *crate::max_bound_vms.read(&lock) as usize
+        };
+
+        let bound = self.inner.bound_vms.fetch_add(1, Ordering::Relaxed);
+        if limit != 0 && bound >= limit {
+            self.inner.bound_vms.fetch_sub(1, Ordering::Relaxed);
+            if !self.inner.bound_vms_warned.swap(true, Ordering::Relaxed) {
+                dev_warn!(
+                    self.dev,
+                    "MMU: Reached the limit of {} bound VMs ({} slots available)\n",
+                    limit,
+                    UAT_USER_CTX
+                );
+            }
+            return Err(EBUSY);
+        }
+        Ok(())
+    }
+
+    /// Allocates a slot for an unbound `Vm`, preferring the last used one, and points it at the
+    /// `Vm`'s page tables.
+    fn bind_slot(&self, inner: &mut VmInner, id: u64) -> Result {
+        let slot = self.slots.get(inner.bind_token)?;
+        if slot.changed() {
+            mod_pr_debug!("Vm Bind [{}]: bind_token={:?}\n", id, slot.token(),);
+            let idx = (slot.slot() as usize) + UAT_USER_CTX_START;
+            let ttb = inner.ttb() | TTBR_VALID | (idx as u64) << TTBR_ASID_SHIFT;
+
+            let uat_inner = self.inner.lock();
+
+            let ttb1 = if uat_inner.map_kernel_to_user {
+                uat_inner.kernel_ttb1 | TTBR_VALID | (idx as u64) << TTBR_ASID_SHIFT
+            } else {
+                0
+            };
+
+            let ttbs = uat_inner.ttbs();
+            uat_inner.handoff().lock()?;
+            if uat_inner.handoff().current_slot() == Some(idx as u32) {
+                pr_err!(
+                    "Vm::bind to slot {}, but it is currently in use by the ASC?\n",
+                    idx
+                );
+            }
+            ttbs[idx].ttb0.store(ttb, Ordering::Relaxed);
+            ttbs[idx].ttb1.store(ttb1, Ordering::Relaxed);
+            uat_inner.handoff().unlock();
+            core::mem::drop(uat_inner);
+
+            // Make sure all TLB entries from the previous owner of this ASID are gone
+            mem::tlbi_asid(idx as u8);
+            mem::sync();
+        }
+
+        inner.bind_token = Some(slot.token());
+        inner.binding = Some(slot);
+
+        Ok(())
+    }
+
     /// Binds a `Vm` to a slot, preferring the last used one.
+    ///
+    /// Returns `EBUSY` if binding it would exceed the `max_bound_vms` limit.
     pub(crate) fn bind(&self, vm: &Vm) -> Result<VmBind> {
         let mut inner = vm.inner.lock();
 
         if inner.binding.is_none() {
             assert_eq!(inner.active_users, 0);
 
-            let slot = self.slots.get(inner.bind_token)?;
-            if slot.changed() {
-                mod_pr_debug!("Vm Bind [{}]: bind_token={:?}\n", vm.id, slot.token(),);
-                let idx = (slot.slot() as usize) + UAT_USER_CTX_START;
-                let ttb = inner.ttb() | TTBR_VALID | (idx as u64) << TTBR_ASID_SHIFT;
-
-                let uat_inner = self.inner.lock();
-
-                let ttb1 = if uat_inner.map_kernel_to_user {
-                    uat_inner.kernel_ttb1 | TTBR_VALID | (idx as u64) << TTBR_ASID_SHIFT
-                } else {
-                    0
-                };
-
-                let ttbs = uat_inner.ttbs();
-                uat_inner.handoff().lock();
-                if uat_inner.handoff().current_slot() == Some(idx as u32) {
-                    pr_err!(
-                        "Vm::bind to slot {}, but it is currently in use by the ASC?\n",
-                        idx
-                    );
-                }
-                ttbs[idx].ttb0.store(ttb, Ordering::Relaxed);
-                ttbs[idx].ttb1.store(ttb1, Ordering::Relaxed);
-                uat_inner.handoff().unlock();
-                core::mem::drop(uat_inner);
-
-                // Make sure all TLB entries from the previous owner of this ASID are gone
-                mem::tlbi_asid(idx as u8);
-                mem::sync();
+            self.reserve_bound_vm()?;
+            if let Err(e) = self.bind_slot(&mut inner, vm.id) {
+                self.inner.bound_vms.fetch_sub(1, Ordering::Relaxed);
+                return Err(e);
             }
-
-            inner.bind_token = Some(slot.token());
-            inner.binding = Some(slot);
         }
 
         inner.active_users += 1;
@@ -1132,9 +1915,65 @@ pub(crate) fn bind(&self, vm: &Vm) -> Result<VmBind> {
         Ok(VmBind(vm.clone(), slot))
     }
 
+    /// Synchronously flush a range of kernel VAs from the coprocessor cache.
+    ///
+    /// The range must be page-aligned and smaller than `MAX_FLUSH_PAGES` pages.
+    pub(crate) fn flush_kernel_range(&self, iova: u64, size: u64) -> Result {
+        let pages = size as usize >> UAT_PGBIT;
+        if pages >= MAX_FLUSH_PAGES || (iova | size) & UAT_PGMSK as u64 != 0 {
+            return Err(EINVAL);
+        }
+
+        // Kernel mappings always flush on index 64
+        let flush_slot = UAT_NUM_CTX as u32;
+        let flush = self.inner.lock_flush(flush_slot);
+        flush.begin_flush(iova, size);
+
+        let cmd = fw::channels::FwCtlMsg {
+            addr: fw::types::U64(iova),
+            unk_8: 0,
+            slot: flush_slot,
+            page_count: pages as u16,
+            unk_12: 2, // ?
+        };
+
+        let ret = self.dev.data().gpu.fwctl(cmd);
+
+        flush.end_flush();
+        ret
+    }
+
+    /// Flush and unmap all mappings queued by [`Mapping::unmap_deferred()`].
+    ///
+    /// This blocks on the firmware cache flushes, so it must be called from a context where that
+    /// is acceptable.
+    pub(crate) fn process_deferred_unmaps(&self) {
+        let count = self.inner.deferred_unmaps.inner().process();
+
+        if count > 0 {
+            mod_dev_dbg!(self.dev, "MMU: Processed {} deferred unmaps\n", count);
+        }
+    }
+
     /// Creates a new `Vm` linked to this UAT.
-    pub(crate) fn new_vm(&self, id: u64, file_id: u64) -> Result<Vm> {
-        Vm::new(&self.dev, self.inner.clone(), self.cfg, false, id, file_id)
+    ///
+    /// If given, `client_bytes` is charged for all mappings in the `Vm`, in addition to the
+    /// global user mapping counter.
+    pub(crate) fn new_vm(
+        &self,
+        id: u64,
+        file_id: u64,
+        client_bytes: Option<Arc<AtomicU64>>,
+    ) -> Result<Vm> {
+        Vm::new(
+            &self.dev,
+            self.inner.clone(),
+            self.cfg,
+            false,
+            client_bytes,
+            id,
+            file_id,
+        )
     }
 
     /// Creates the reference-counted inner data for a new `Uat` instance.
@@ -1160,6 +1999,16 @@ fn make_inner(dev: &driver::AsahiDevice) -> Result<Arc<UatInner>> {
                 },
                 c_str!("uat_shared")
             ),
+            deferred_unmaps <- Work::new(
+                pin_init!(DeferredUnmaps {
+                    mappings <- Mutex::new_named(Vec::new(), c_str!("deferred_unmaps")),
+                    closed: AtomicBool::new(false),
+                }),
+                c_str!("deferred_unmaps_work"),
+                static_lock_class!()
+            ),
+            bound_vms: AtomicUsize::new(0),
+            bound_vms_warned: AtomicBool::new(false),
         }))
     }
 
@@ -1177,8 +2026,8 @@ pub(crate) fn new(
         let pagetables_rgn = Self::map_region(dev, c_str!("pagetables"), PAGETABLES_SIZE, true)?;
 
         dev_info!(dev, "MMU: Creating kernel page tables\n");
-        let kernel_lower_vm = Vm::new(dev, inner.clone(), cfg, false, 1, 0)?;
-        let kernel_vm = Vm::new(dev, inner.clone(), cfg, true, 0, 0)?;
+        let kernel_lower_vm = Vm::new(dev, inner.clone(), cfg, false, None, 1, 0)?;
+        let kernel_vm = Vm::new(dev, inner.clone(), cfg, true, None, 0, 0)?;
 
         dev_info!(dev, "MMU: Kernel page tables created\n");
 
@@ -1211,7 +2060,7 @@ pub(crate) fn new(
 
         dev_info!(dev, "MMU: Initializing TTBs\n");
 
-        inner.handoff().lock();
+        inner.handoff().lock()?;
 
         let ttbs = inner.ttbs();
 
@@ -1239,6 +2088,17 @@ pub(crate) fn new(
 
 impl Drop for Uat {
     fn drop(&mut self) {
+        // Deferred mappings hold a reference to the UatInner, so they must go first. Stop
+        // deferring and wait for the work first, since the work must not drop the last reference
+        // to the UatInner that owns it.
+        let work = &self.inner.deferred_unmaps;
+        {
+            let _guard = work.inner().mappings.lock();
+            work.inner().closed.store(true, Ordering::Relaxed);
+        }
+        work.cancel_sync();
+        self.process_deferred_unmaps();
+
         // Unmap what we mapped
         self.kpt0()[2].store(0, Ordering::Relaxed);
 
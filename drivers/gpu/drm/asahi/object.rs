@@ -389,6 +389,30 @@ pub(crate) fn weak_pointer(&self) -> GpuWeakPointer<T> {
         GpuWeakPointer(self.gpu_ptr.0, PhantomData)
     }
 
+    /// Dump the raw contents of this object to the kernel log, with their offsets.
+    ///
+    /// Lines that are entirely zero are skipped, to keep large structures readable.
+    pub(crate) fn hexdump(&self, name: &str) {
+        let size = mem::size_of::<T::Raw<'static>>();
+        // SAFETY: `self.raw` is valid for reads of the whole raw struct per the type invariant,
+        // and the backing memory is always initialized.
+        let bytes = unsafe { slice::from_raw_parts(self.raw as *const u8, size) };
+
+        dev_info!(
+            self.alloc.device(),
+            "{} @ {:#x} ({:#x} bytes):\n",
+            name,
+            self.gpu_ptr.0,
+            size
+        );
+        for (i, line) in bytes.chunks(16).enumerate() {
+            if line.iter().all(|&b| b == 0) {
+                continue;
+            }
+            dev_info!(self.alloc.device(), "  {:#06x}: {:02x?}\n", i * 16, line);
+        }
+    }
+
     /// Perform a mutation to the inner `Raw` data given a user-supplied callback.
     ///
     /// The callback gets a mutable reference to the `GpuStruct` type.
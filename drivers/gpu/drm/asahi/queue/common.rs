@@ -4,16 +4,132 @@
 //!
 //! Shared helpers used by the submission logic for multiple command types.
 
+use crate::audit;
+use crate::debug::*;
+use crate::driver::AsahiDevice;
 use crate::fw::microseq;
 use crate::fw::types::*;
+use crate::mmu;
 
 use kernel::io_buffer::IoBufferReader;
+use kernel::io_pgtable::prot;
 use kernel::prelude::*;
 use kernel::uapi;
 use kernel::user_ptr::UserSlicePtr;
 
 use core::mem::MaybeUninit;
 
+/// GPU access that a command needs to one of the addresses it references.
+#[derive(Copy, Clone, Debug)]
+pub(super) enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    /// Returns whether a mapping with the given protection flags allows this access by the GPU.
+    fn allowed_by(self, prot: u32) -> bool {
+        // Firmware-private mappings are never valid targets for user commands.
+        if prot & prot::PRIV != 0 {
+            return false;
+        }
+
+        let needed = match self {
+            Access::Read => prot::READ,
+            Access::Write => prot::WRITE,
+            Access::ReadWrite => prot::READ | prot::WRITE,
+        };
+
+        prot & needed == needed
+    }
+}
+
+/// Check that all the given GPU addresses from a command are mapped in the Vm with the access they
+/// need, if strict address validation is enabled.
+///
+/// Each address is given with the name of the field it came from, which is logged for the first
+/// invalid one. Zero addresses are considered unused and skipped. Only the page containing each
+/// address is checked, not the full extent of the data it points to.
+pub(super) fn validate_addresses(
+    dev: &AsahiDevice,
+    vm: &mmu::Vm,
+    id: u64,
+    addrs: &[(&str, u64, Access)],
+) -> Result {
+    if !debug_enabled(DebugFlags::StrictAddressValidation) {
+        return Ok(());
+    }
+
+    for &(name, addr, access) in addrs {
+        if addr == 0 {
+            continue;
+        }
+
+        match vm.mapped_prot(addr) {
+            None => {
+                dev_warn!(
+                    dev,
+                    "[Submission {}] Unmapped GPU address in {}: {:#x}\n",
+                    id,
+                    name,
+                    addr
+                );
+                return Err(EINVAL);
+            }
+            Some(prot) if !access.allowed_by(prot) => {
+                dev_warn!(
+                    dev,
+                    "[Submission {}] GPU address in {} lacks {:?} access: {:#x} (prot {:#x})\n",
+                    id,
+                    name,
+                    access,
+                    addr,
+                    prot
+                );
+                return Err(EINVAL);
+            }
+            Some(_) => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// Record a command and the nonzero GPU addresses it references in the audit log, if enabled.
+///
+/// Addresses beyond `audit::MAX_ADDRS` are not recorded.
+pub(super) fn audit_command(
+    dev: &AsahiDevice,
+    vm: &mmu::Vm,
+    queue_id: u64,
+    id: u64,
+    cmd_type: u32,
+    addrs: &[(&str, u64, Access)],
+) {
+    if !audit::enabled() {
+        return;
+    }
+
+    let mut record = uapi::drm_asahi_audit_record {
+        file_id: vm.file_id(),
+        vm_id: vm.id(),
+        submission_id: id,
+        queue_id,
+        pid: kernel::current!().group_leader().pid() as u32,
+        cmd_type,
+        ..Default::default()
+    };
+
+    let used = addrs.iter().filter(|(_, addr, _)| *addr != 0);
+    for (slot, &(_, addr, _)) in record.addrs.iter_mut().zip(used) {
+        *slot = addr;
+        record.addr_count += 1;
+    }
+
+    dev.data().gpu.audit_log().lock().record(record);
+}
+
 pub(super) fn build_attachments(pointer: u64, count: u32) -> Result<microseq::Attachments> {
     if count as usize > microseq::MAX_ATTACHMENTS {
         return Err(EINVAL);
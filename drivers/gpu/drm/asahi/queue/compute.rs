@@ -7,7 +7,7 @@
 //! This module is in charge of creating all of the firmware structures required to submit compute
 //! work to the GPU, based on the userspace command buffer.
 
-use super::common;
+use super::common::{self, Access};
 use crate::alloc::Allocator;
 use crate::debug::*;
 use crate::fw::types::*;
@@ -21,6 +21,7 @@
 use kernel::io_buffer::IoBufferReader;
 use kernel::prelude::*;
 use kernel::sync::Arc;
+use kernel::time;
 use kernel::uapi;
 use kernel::user_ptr::UserSlicePtr;
 
@@ -53,7 +54,12 @@ pub(super) fn submit_compute(
         let mut alloc = gpu.alloc();
         let kalloc = &mut *alloc;
 
-        mod_dev_dbg!(self.dev, "[Submission {}] Compute!\n", id);
+        mod_dev_dbg!(
+            self.dev,
+            "[Queue {}] [Submission {}] Compute!\n",
+            &*self.name,
+            id
+        );
 
         let mut cmdbuf_reader = unsafe {
             UserSlicePtr::new(
@@ -72,10 +78,34 @@ pub(super) fn submit_compute(
         }
         let cmdbuf = unsafe { cmdbuf.assume_init() };
 
-        if cmdbuf.flags != 0 {
+        if cmdbuf.flags
+            & !(uapi::ASAHI_COMPUTE_NO_PREEMPTION | uapi::ASAHI_COMPUTE_NORMALIZED_TIMESTAMPS)
+                as u64
+            != 0
+        {
             return Err(EINVAL);
         }
 
+        let addrs = [
+            ("encoder_ptr", cmdbuf.encoder_ptr, Access::Read),
+            (
+                "encoder_end",
+                cmdbuf.encoder_end.saturating_sub(1),
+                Access::Read,
+            ),
+            ("helper_arg", cmdbuf.helper_arg, Access::Read),
+            ("sampler_array", cmdbuf.sampler_array, Access::Read),
+        ];
+        common::validate_addresses(&self.dev, &self.vm, id, &addrs)?;
+        common::audit_command(
+            &self.dev,
+            &self.vm,
+            self.id,
+            id,
+            uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_COMPUTE,
+            &addrs,
+        );
+
         // This sequence number increases per new client/VM? assigned to some slot,
         // but it's unclear *which* slot...
         let slot_client_seq: u8 = (self.id & 0xff) as u8;
@@ -106,6 +136,14 @@ pub(super) fn submit_compute(
             .lock()
             .array_empty_tagged(preempt_size, b"CPMT")?;
 
+        mod_dev_dbg!(
+            self.dev,
+            "[Submission {}] Allocated compute preemption buffer ({:#x} bytes, {:#x} for state)\n",
+            id,
+            preempt_size,
+            preempt2_off
+        );
+
         mod_dev_dbg!(
             self.dev,
             "[Submission {}] Event #{} {:#x?} -> {:#x?}\n",
@@ -119,6 +157,8 @@ pub(super) fn submit_compute(
 
         let uuid = cmdbuf.cmd_id;
 
+        self.check_uuid(id, "Compute", uuid);
+
         mod_dev_dbg!(self.dev, "[Submission {}] UUID = {:#x?}\n", id, uuid);
 
         // TODO: check
@@ -379,10 +419,19 @@ pub(super) fn submit_compute(
 
         core::mem::drop(alloc);
 
+        // The firmware does not report whether a job was actually preempted, so report whether it
+        // could have been instead.
+        let preemptible = cmdbuf.flags & uapi::ASAHI_COMPUTE_NO_PREEMPTION as u64 == 0;
+        let ts_clock_hz = if cmdbuf.flags & uapi::ASAHI_COMPUTE_NORMALIZED_TIMESTAMPS as u64 != 0 {
+            Some(gpu.get_cfg().base_clock_hz)
+        } else {
+            None
+        };
+
         fence.add_command();
         comp_job.add_cb(comp, vm_bind.slot(), move |cmd, error| {
             if let Some(err) = error {
-                fence.set_error(err.into())
+                fence.fail(err.into())
             }
             if let Some(mut rw) = result_writer {
                 let mut result: uapi::drm_asahi_result_compute = Default::default();
@@ -392,12 +441,26 @@ pub(super) fn submit_compute(
                     result.ts_end = raw.end.load(Ordering::Relaxed);
                 });
 
+                if let Some(hz) = ts_clock_hz {
+                    result.ts_start = time::ticks_to_ns(result.ts_start, hz);
+                    result.ts_end = time::ticks_to_ns(result.ts_end, hz);
+                    result.flags |= uapi::DRM_ASAHI_RESULT_TIMESTAMPS_NS as u64;
+                }
+
                 if let Some(err) = error {
                     result.info = err.into();
                 } else {
                     result.info.status = uapi::drm_asahi_status_DRM_ASAHI_STATUS_COMPLETE;
                 }
 
+                if preemptible {
+                    result.flags |= uapi::DRM_ASAHI_RESULT_COMPUTE_PREEMPTIBLE as u64;
+                }
+
+                if rw.truncates::<uapi::drm_asahi_result_compute>() {
+                    result.flags |= uapi::DRM_ASAHI_RESULT_TRUNCATED as u64;
+                }
+
                 rw.write(result);
             }
 
@@ -8,10 +8,11 @@
 use kernel::dma_fence::*;
 use kernel::prelude::*;
 use kernel::{
-    c_str, dma_fence,
+    bindings, c_str, dma_fence,
     drm::gem::shmem::VMap,
     drm::sched,
     macros::versions,
+    str::CString,
     sync::{Arc, Mutex},
     uapi,
 };
@@ -24,28 +25,204 @@
 use crate::inner_weak_ptr;
 use crate::{alloc, buffer, channel, event, file, fw, gem, gpu, mmu, workqueue};
 
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 
 const DEBUG_CLASS: DebugFlags = DebugFlags::Queue;
 
 const WQ_SIZE: u32 = 0x500;
 
+/// Default DRM scheduler job timeout.
+const DEFAULT_TIMEOUT_MS: usize = 100000;
+/// Maximum DRM scheduler job timeout that can be set by userspace.
+const MAX_TIMEOUT_MS: u32 = 600000;
+
+/// Validate a userspace job timeout, mapping 0 to the default.
+fn job_timeout_ms(timeout_ms: u32) -> Result<usize> {
+    match timeout_ms {
+        0 => Ok(DEFAULT_TIMEOUT_MS),
+        t if t > MAX_TIMEOUT_MS => Err(EINVAL),
+        t => Ok(t as usize),
+    }
+}
+
 mod common;
 mod compute;
 mod render;
 
+/// Diagnostic statistics for a queue.
+///
+/// These are only used for reporting to userspace, so they use relaxed atomics.
+#[derive(Default)]
+pub(crate) struct QueueStats {
+    /// Number of times a job was blocked because the vertex queue was full.
+    pub(crate) blocked_vertex: AtomicU64,
+    /// Number of times a job was blocked because the fragment queue was full.
+    pub(crate) blocked_fragment: AtomicU64,
+    /// Number of times a job was blocked because the compute queue was full.
+    pub(crate) blocked_compute: AtomicU64,
+    /// Number of completed vertex passes with TVB usage recorded.
+    pub(crate) tvb_scenes: AtomicU64,
+    /// Sum of the TVB usage of all recorded vertex passes, in bytes.
+    pub(crate) tvb_usage_total: AtomicU64,
+    /// Peak TVB usage of any recorded vertex pass, in bytes.
+    pub(crate) tvb_usage_peak: AtomicU64,
+    /// Number of recorded vertex passes that overflowed the TVB.
+    pub(crate) tvb_overflows: AtomicU64,
+    /// Number of partial renders caused by TVB overflows, across all fragment passes.
+    pub(crate) tvb_partial_renders: AtomicU64,
+    /// Number of render commands submitted.
+    pub(crate) render_cmds: AtomicU64,
+    /// Number of render commands that requested empty tile processing.
+    pub(crate) empty_tiles: AtomicU64,
+    /// Number of render commands that requested empty tile processing with nothing to store.
+    pub(crate) empty_tiles_unneeded: AtomicU64,
+}
+
+/// A snapshot of the TVB usage statistics of a queue.
+pub(crate) struct TvbStats {
+    pub(crate) scenes: u64,
+    pub(crate) usage_total: u64,
+    pub(crate) usage_peak: u64,
+    pub(crate) overflows: u64,
+    pub(crate) partial_renders: u64,
+}
+
+impl QueueStats {
+    /// Record the TVB usage of a completed vertex pass.
+    pub(crate) fn record_tvb(&self, used_bytes: u64, overflowed: bool) {
+        self.tvb_scenes.fetch_add(1, Ordering::Relaxed);
+        self.tvb_usage_total
+            .fetch_add(used_bytes, Ordering::Relaxed);
+        self.tvb_usage_peak.fetch_max(used_bytes, Ordering::Relaxed);
+        if overflowed {
+            self.tvb_overflows.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Record the number of partial renders a completed fragment pass needed.
+    pub(crate) fn record_partial_renders(&self, count: u32) {
+        self.tvb_partial_renders
+            .fetch_add(count.into(), Ordering::Relaxed);
+    }
+
+    /// Read the TVB usage statistics.
+    pub(crate) fn tvb(&self) -> TvbStats {
+        self.read_tvb(|v| v.load(Ordering::Relaxed))
+    }
+
+    /// Read the TVB usage statistics and reset them to start a new measurement window.
+    ///
+    /// The counters are updated independently, so a pass completing concurrently with a reset
+    /// may be split across windows. That is fine for statistics used for tuning.
+    pub(crate) fn take_tvb(&self) -> TvbStats {
+        self.read_tvb(|v| v.swap(0, Ordering::Relaxed))
+    }
+
+    fn read_tvb(&self, get: impl Fn(&AtomicU64) -> u64) -> TvbStats {
+        TvbStats {
+            scenes: get(&self.tvb_scenes),
+            usage_total: get(&self.tvb_usage_total),
+            usage_peak: get(&self.tvb_usage_peak),
+            overflows: get(&self.tvb_overflows),
+            partial_renders: get(&self.tvb_partial_renders),
+        }
+    }
+}
+
 /// Trait implemented by all versioned queues.
 pub(crate) trait Queue: Send + Sync {
     fn submit(
         &mut self,
         id: u64,
+        flags: u32,
         in_syncs: Vec<file::SyncItem>,
         out_syncs: Vec<file::SyncItem>,
         result_buf: Option<gem::ObjectRef>,
         commands: Vec<uapi::drm_asahi_command>,
+        inflight: file::InflightSlot,
     ) -> Result;
+    /// Returns the diagnostic statistics for this queue.
+    fn stats(&self) -> &QueueStats;
+    /// Pauses the queue, holding any jobs that have not yet run in the DRM scheduler.
+    ///
+    /// Jobs that have already been submitted to the firmware cannot be recalled and will run to
+    /// completion as usual. Pausing an already paused queue is a no-op.
+    fn pause(&self) -> Result;
+    /// Resumes a paused queue, releasing held jobs in submission order.
+    ///
+    /// Resuming a queue that is not paused is a no-op.
+    fn resume(&self) -> Result;
+    /// Sets the DRM scheduler job timeout in milliseconds, or restores the default if 0.
+    ///
+    /// This only applies to subsequently timed jobs. A job that is already running keeps the
+    /// deadline it was given when it started.
+    fn set_timeout(&self, timeout_ms: u32) -> Result;
+    /// Changes the scheduling priority of the queue.
+    ///
+    /// Only new submissions are affected. Each firmware work queue switches over once the work
+    /// already submitted to it has completed, so jobs in flight finish at their old priority.
+    fn set_priority(&mut self, priority: u32) -> Result;
+    /// Kills the queue, cancelling all work that has not been submitted to the firmware yet.
+    ///
+    /// Jobs still held in the DRM scheduler fail with `ECANCELED` without running, and further
+    /// submissions are rejected. Work that the firmware already has cannot be recalled, so it runs
+    /// to completion, but its fences are errored with `ECANCELED` unless they already failed. The
+    /// GPU context is invalidated once that work completes and the queue is dropped.
+    fn kill(&mut self);
+}
+
+/// Number of recently submitted command UUIDs remembered per queue.
+const RECENT_UUIDS: usize = 32;
+
+/// Ring of recently submitted command UUIDs, used to catch userspace submitting the same command
+/// buffer twice when `DebugFlags::DetectUuidReuse` is enabled.
+///
+/// This is only updated from submission, with the queue lock held. The atomics just provide
+/// interior mutability.
+#[derive(Default)]
+struct RecentUuids {
+    uuids: [AtomicU32; RECENT_UUIDS],
+    pos: AtomicUsize,
 }
 
+impl RecentUuids {
+    /// Records a UUID, returning whether it was already among the recent ones.
+    fn check(&self, uuid: u32) -> bool {
+        let seen = self.uuids.iter().any(|u| u.load(Ordering::Relaxed) == uuid);
+
+        let pos = self.pos.load(Ordering::Relaxed);
+        self.uuids[pos].store(uuid, Ordering::Relaxed);
+        self.pos.store((pos + 1) % RECENT_UUIDS, Ordering::Relaxed);
+
+        seen
+    }
+}
+
+/// Fence context index used for job completion fences.
+const FENCE_CTX_JOB: u32 = 0;
+/// Fence context index used for queue pause fences.
+const FENCE_CTX_PAUSE: u32 = 1;
+
+/// Fence that blocks jobs in the DRM scheduler while a queue is paused.
+///
+/// It is signaled when the queue is resumed.
+pub(crate) struct PauseFence;
+
+#[vtable]
+impl dma_fence::FenceOps for PauseFence {
+    const USE_64BIT_SEQNO: bool = true;
+
+    fn get_driver_name<'a>(self: &'a FenceObject<Self>) -> &'a CStr {
+        c_str!("asahi")
+    }
+    fn get_timeline_name<'a>(self: &'a FenceObject<Self>) -> &'a CStr {
+        c_str!("queue_pause")
+    }
+}
+
+/// Pause state shared between a queue and its jobs. `Some` while the queue is paused.
+type PauseState = Mutex<Option<UserFence<PauseFence>>>;
+
 #[versions(AGX)]
 struct SubQueue {
     wq: Arc<workqueue::WorkQueue::ver>,
@@ -95,7 +272,7 @@ fn can_submit(&self) -> Option<Fence> {
 #[versions(AGX)]
 pub(crate) struct Queue {
     dev: AsahiDevRef,
-    _sched: sched::Scheduler<QueueJob::ver>,
+    sched: sched::Scheduler<QueueJob::ver>,
     entity: sched::Entity<QueueJob::ver>,
     vm: mmu::Vm,
     ualloc: Arc<Mutex<alloc::DefaultAllocator>>,
@@ -107,33 +284,93 @@ pub(crate) struct Queue {
     notifier_list: Arc<GpuObject<fw::event::NotifierList>>,
     notifier: Arc<GpuObject<fw::event::Notifier::ver>>,
     id: u64,
+    /// Debug name for log messages, defaults to the numeric queue ID.
+    name: CString,
+    /// Fence timeline name, shared with all job fences since they may outlive the queue.
+    timeline_name: Arc<CString>,
+    stats: Arc<QueueStats>,
+    paused: Arc<PauseState>,
+    /// Set once the queue is killed, shared with its jobs and job fences.
+    killed: Arc<AtomicBool>,
+    /// Power hints, updated by job fence deadlines.
+    power_hints: Arc<gpu::PowerHints>,
     fence_ctx: FenceContexts,
+    recent_uuids: RecentUuids,
+    /// Finished fence of the last submitted job, which sync-only jobs complete after.
+    last_fence: Option<Fence>,
     #[ver(V >= V13_0B4)]
     counter: AtomicU64,
 }
 
+/// Number of job fences with pending commands, across all queues. Only used for debugging.
+static PENDING_JOB_FENCES: AtomicU64 = AtomicU64::new(0);
+
 #[versions(AGX)]
-#[derive(Default)]
 pub(crate) struct JobFence {
     id: u64,
     pending: AtomicU64,
+    timeline_name: Arc<CString>,
+    /// Log this job's lifecycle regardless of the debug flags.
+    trace: bool,
+    /// Whether an error has been set on this fence.
+    errored: AtomicBool,
+    /// Whether the owning queue was killed.
+    killed: Arc<AtomicBool>,
+    /// Earliest deadline requested for this fence (`CLOCK_MONOTONIC` ns), or `i64::MAX` if none.
+    deadline: AtomicI64,
+    power_hints: Arc<gpu::PowerHints>,
 }
 
 #[versions(AGX)]
 impl JobFence::ver {
+    /// Set the fence error, unless one was already set.
+    ///
+    /// Command completion callbacks and queue kills may race, so only the first error is kept.
+    /// This must be called before the last command completes, since the fence is signaled then.
+    fn fail(self: &FenceObject<Self>, err: Error) {
+        if !self.errored.swap(true, Ordering::Relaxed) {
+            self.set_error(err);
+        }
+    }
+
     fn add_command(self: &FenceObject<Self>) {
-        self.pending.fetch_add(1, Ordering::Relaxed);
+        if self.pending.fetch_add(1, Ordering::Relaxed) == 0 {
+            PENDING_JOB_FENCES.fetch_add(1, Ordering::Relaxed);
+        }
     }
 
     fn command_complete(self: &FenceObject<Self>) {
-        let remain = self.pending.fetch_sub(1, Ordering::Relaxed) - 1;
-        mod_pr_debug!(
+        let prev = self.pending.fetch_sub(1, Ordering::Relaxed);
+        if prev == 0 {
+            // Undo the wraparound, this fence has already been signaled.
+            self.pending.store(0, Ordering::Relaxed);
+            if debug_enabled(DebugFlags::DebugFences) {
+                pr_warn!(
+                    "JobFence[{}]: Command completed with no commands pending\n",
+                    self.id
+                );
+            }
+            return;
+        }
+
+        let remain = prev - 1;
+        trace_pr_debug!(
+            self.trace,
             "JobFence[{}]: Command complete (remain: {})\n",
             self.id,
             remain
         );
         if remain == 0 {
-            mod_pr_debug!("JobFence[{}]: Signaling\n", self.id);
+            if self.killed.load(Ordering::Relaxed) {
+                self.fail(ECANCELED);
+            }
+            let live = PENDING_JOB_FENCES.fetch_sub(1, Ordering::Relaxed) - 1;
+            trace_pr_debug!(
+                self.trace,
+                "JobFence[{}]: Signaling ({} fences still pending)\n",
+                self.id,
+                live
+            );
             if self.signal().is_err() {
                 pr_err!("JobFence[{}]: Fence signal failed\n", self.id);
             }
@@ -141,6 +378,30 @@ fn command_complete(self: &FenceObject<Self>) {
     }
 }
 
+#[versions(AGX)]
+impl Drop for JobFence::ver {
+    fn drop(&mut self) {
+        let pending = *self.pending.get_mut();
+        if pending == 0 {
+            return;
+        }
+
+        let live = PENDING_JOB_FENCES.fetch_sub(1, Ordering::Relaxed) - 1;
+
+        // This is expected if a submission fails after commands were added to its jobs, since
+        // those commands never run. Anywhere else, a command completion was lost, and anything
+        // that had been waiting on this fence would have hung.
+        if debug_enabled(DebugFlags::DebugFences) {
+            pr_warn!(
+                "JobFence[{}]: Dropped with {} commands pending ({} fences still pending)\n",
+                self.id,
+                pending,
+                live
+            );
+        }
+    }
+}
+
 #[versions(AGX)]
 #[vtable]
 impl dma_fence::FenceOps for JobFence::ver {
@@ -150,7 +411,30 @@ fn get_driver_name<'a>(self: &'a FenceObject<Self>) -> &'a CStr {
         c_str!("asahi")
     }
     fn get_timeline_name<'a>(self: &'a FenceObject<Self>) -> &'a CStr {
-        c_str!("queue")
+        &self.timeline_name
+    }
+
+    /// Record a deadline hint for this fence.
+    ///
+    /// Consumers may set several deadlines on the same fence, and the earliest one wins: later
+    /// deadlines are ignored. There is no known firmware interface to boost the GPU performance
+    /// state for a specific job, so the deadline is used to keep the GPU powered until it passes
+    /// instead, which avoids a power-up delay for any work submitted to meet it.
+    fn set_deadline(self: &FenceObject<Self>, deadline: i64) {
+        let prev = self.deadline.fetch_min(deadline, Ordering::Relaxed);
+        if deadline >= prev || self.is_signaled() {
+            return;
+        }
+
+        // SAFETY: ktime_get() is always safe to call.
+        let now = unsafe { bindings::ktime_get() };
+        trace_pr_debug!(
+            self.trace,
+            "JobFence[{}]: Deadline set to {} ns from now\n",
+            self.id,
+            deadline - now
+        );
+        self.power_hints.request_deadline(deadline);
     }
 }
 
@@ -163,8 +447,17 @@ pub(crate) struct QueueJob {
     sj_frag: Option<SubQueueJob::ver>,
     sj_comp: Option<SubQueueJob::ver>,
     fence: UserFence<JobFence::ver>,
+    stats: Arc<QueueStats>,
+    paused: Arc<PauseState>,
+    killed: Arc<AtomicBool>,
+    /// Counts this job against the owning file's in-flight limit until it is freed.
+    _inflight: file::InflightSlot,
     did_run: bool,
     id: u64,
+    /// Log this job's lifecycle regardless of the debug flags.
+    trace: bool,
+    /// For sync-only jobs, the finished fence of the previously submitted job.
+    prev_fence: Option<Fence>,
 }
 
 #[versions(AGX)]
@@ -179,8 +472,15 @@ fn get_comp(&mut self) -> Result<&mut workqueue::Job::ver> {
         self.sj_comp.as_mut().ok_or(EINVAL)?.get()
     }
 
+    /// Returns whether no commands were added to this job (a sync-only submission).
+    fn is_empty(&self) -> bool {
+        [&self.sj_vtx, &self.sj_frag, &self.sj_comp]
+            .iter()
+            .all(|sj| sj.as_ref().map_or(true, |sj| sj.job.is_none()))
+    }
+
     fn commit(&mut self) -> Result {
-        mod_dev_dbg!(self.dev, "QueueJob: Committing\n");
+        trace_dev_dbg!(self.trace, self.dev, "QueueJob {}: Committing\n", self.id);
 
         self.sj_vtx.as_mut().map(|a| a.commit()).unwrap_or(Ok(()))?;
         self.sj_frag
@@ -194,35 +494,64 @@ fn commit(&mut self) -> Result {
 #[versions(AGX)]
 impl sched::JobImpl for QueueJob::ver {
     fn prepare(job: &mut sched::Job<Self>) -> Option<Fence> {
-        mod_dev_dbg!(job.dev, "QueueJob {}: Checking runnability\n", job.id);
+        trace_dev_dbg!(
+            job.trace,
+            job.dev,
+            "QueueJob {}: Checking runnability\n",
+            job.id
+        );
+
+        // Jobs on a killed queue never run, so don't hold them back.
+        if job.killed.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        // Check this first, so a paused queue never pushes anything else to the firmware. The
+        // scheduler only ever considers the head job of the entity, so all later jobs are held
+        // behind this one and released in order once the fence signals.
+        if let Some(fence) = job.paused.lock().as_ref() {
+            trace_dev_dbg!(
+                job.trace,
+                job.dev,
+                "QueueJob {}: Blocking due to queue paused\n",
+                job.id
+            );
+            return Some(Fence::from_fence(fence));
+        }
 
         if let Some(sj) = job.sj_vtx.as_ref() {
             if let Some(fence) = sj.can_submit() {
-                mod_dev_dbg!(
+                trace_dev_dbg!(
+                    job.trace,
                     job.dev,
                     "QueueJob {}: Blocking due to vertex queue full\n",
                     job.id
                 );
+                job.stats.blocked_vertex.fetch_add(1, Ordering::Relaxed);
                 return Some(fence);
             }
         }
         if let Some(sj) = job.sj_frag.as_ref() {
             if let Some(fence) = sj.can_submit() {
-                mod_dev_dbg!(
+                trace_dev_dbg!(
+                    job.trace,
                     job.dev,
                     "QueueJob {}: Blocking due to fragment queue full\n",
                     job.id
                 );
+                job.stats.blocked_fragment.fetch_add(1, Ordering::Relaxed);
                 return Some(fence);
             }
         }
         if let Some(sj) = job.sj_comp.as_ref() {
             if let Some(fence) = sj.can_submit() {
-                mod_dev_dbg!(
+                trace_dev_dbg!(
+                    job.trace,
                     job.dev,
                     "QueueJob {}: Blocking due to compute queue full\n",
                     job.id
                 );
+                job.stats.blocked_compute.fetch_add(1, Ordering::Relaxed);
                 return Some(fence);
             }
         }
@@ -231,7 +560,32 @@ fn prepare(job: &mut sched::Job<Self>) -> Option<Fence> {
 
     #[allow(unused_assignments)]
     fn run(job: &mut sched::Job<Self>) -> Result<Option<dma_fence::Fence>> {
-        mod_dev_dbg!(job.dev, "QueueJob {}: Running Job\n", job.id);
+        trace_dev_dbg!(job.trace, job.dev, "QueueJob {}: Running Job\n", job.id);
+
+        // Returning an error fails the job's finished fence without running it. Its commands were
+        // never submitted, so they are rolled back when the job is dropped.
+        if job.killed.load(Ordering::Relaxed) {
+            trace_dev_dbg!(
+                job.trace,
+                job.dev,
+                "QueueJob {}: Queue killed, cancelling\n",
+                job.id
+            );
+            return Err(ECANCELED);
+        }
+
+        // Sync-only jobs have no GPU work. Their dependencies are already satisfied, so they
+        // only need to wait for the previous job, to keep the queue's fences signaling in order.
+        if job.is_empty() {
+            trace_dev_dbg!(
+                job.trace,
+                job.dev,
+                "QueueJob {}: No commands, completing after the previous job\n",
+                job.id
+            );
+            job.did_run = true;
+            return Ok(job.prev_fence.clone());
+        }
 
         let dev = job.dev.data();
         let gpu = match dev
@@ -258,7 +612,7 @@ fn run(job: &mut sched::Job<Self>) -> Result<Option<dma_fence::Fence>> {
         if let Some(sj) = job.sj_frag.as_mut() {
             frag_job = sj.job.take();
             if let Some(wqjob) = frag_job.as_mut() {
-                mod_dev_dbg!(job.dev, "QueueJob {}: Submit fragment\n", job.id);
+                trace_dev_dbg!(job.trace, job.dev, "QueueJob {}: Submit fragment\n", job.id);
                 frag_sub = Some(wqjob.submit()?);
             }
         }
@@ -268,7 +622,7 @@ fn run(job: &mut sched::Job<Self>) -> Result<Option<dma_fence::Fence>> {
         if let Some(sj) = job.sj_vtx.as_mut() {
             vtx_job = sj.job.take();
             if let Some(wqjob) = vtx_job.as_mut() {
-                mod_dev_dbg!(job.dev, "QueueJob {}: Submit vertex\n", job.id);
+                trace_dev_dbg!(job.trace, job.dev, "QueueJob {}: Submit vertex\n", job.id);
                 vtx_sub = Some(wqjob.submit()?);
             }
         }
@@ -278,26 +632,36 @@ fn run(job: &mut sched::Job<Self>) -> Result<Option<dma_fence::Fence>> {
         if let Some(sj) = job.sj_comp.as_mut() {
             comp_job = sj.job.take();
             if let Some(wqjob) = comp_job.as_mut() {
-                mod_dev_dbg!(job.dev, "QueueJob {}: Submit compute\n", job.id);
+                trace_dev_dbg!(job.trace, job.dev, "QueueJob {}: Submit compute\n", job.id);
                 comp_sub = Some(wqjob.submit()?);
             }
         }
 
         // Now we fully commit to running the job
-        mod_dev_dbg!(job.dev, "QueueJob {}: Run fragment\n", job.id);
+        trace_dev_dbg!(job.trace, job.dev, "QueueJob {}: Run fragment\n", job.id);
         frag_sub.map(|a| gpu.run_job(a)).transpose()?;
 
-        mod_dev_dbg!(job.dev, "QueueJob {}: Run vertex\n", job.id);
+        trace_dev_dbg!(job.trace, job.dev, "QueueJob {}: Run vertex\n", job.id);
         vtx_sub.map(|a| gpu.run_job(a)).transpose()?;
 
-        mod_dev_dbg!(job.dev, "QueueJob {}: Run compute\n", job.id);
+        trace_dev_dbg!(job.trace, job.dev, "QueueJob {}: Run compute\n", job.id);
         comp_sub.map(|a| gpu.run_job(a)).transpose()?;
 
-        mod_dev_dbg!(job.dev, "QueueJob {}: Drop compute job\n", job.id);
+        trace_dev_dbg!(
+            job.trace,
+            job.dev,
+            "QueueJob {}: Drop compute job\n",
+            job.id
+        );
         core::mem::drop(comp_job);
-        mod_dev_dbg!(job.dev, "QueueJob {}: Drop vertex job\n", job.id);
+        trace_dev_dbg!(job.trace, job.dev, "QueueJob {}: Drop vertex job\n", job.id);
         core::mem::drop(vtx_job);
-        mod_dev_dbg!(job.dev, "QueueJob {}: Drop fragment job\n", job.id);
+        trace_dev_dbg!(
+            job.trace,
+            job.dev,
+            "QueueJob {}: Drop fragment job\n",
+            job.id
+        );
         core::mem::drop(frag_job);
 
         job.did_run = true;
@@ -319,7 +683,7 @@ fn timed_out(job: &mut sched::Job<Self>) -> sched::Status {
 #[versions(AGX)]
 impl Drop for QueueJob::ver {
     fn drop(&mut self) {
-        mod_dev_dbg!(self.dev, "QueueJob {}: Dropping\n", self.id);
+        trace_dev_dbg!(self.trace, self.dev, "QueueJob {}: Dropping\n", self.id);
     }
 }
 
@@ -327,19 +691,64 @@ struct ResultWriter {
     vmap: VMap<gem::DriverObject>,
     offset: usize,
     len: usize,
+    /// Keeps the result buffer from being purged while it is mapped. Must be dropped last.
+    _pin: gem::PurgePin,
+}
+
+/// Copy a result struct into a result window of possibly different size.
+///
+/// If the window is smaller, the result is truncated. If it is larger (userspace built against a
+/// newer UAPI), the tail is zeroed. Returns whether the result was truncated.
+fn copy_result(dst: &mut [u8], src: &[u8]) -> bool {
+    let len = src.len().min(dst.len());
+
+    dst[..len].copy_from_slice(&src[..len]);
+    dst[len..].fill(0);
+
+    src.len() > dst.len()
 }
 
 impl ResultWriter {
+    /// Returns whether a result of type `T` does not fit in the result window.
+    ///
+    /// Callers use this to flag the result as truncated before writing it, so userspace can tell
+    /// that the fields past the end of its window were lost.
+    fn truncates<T>(&self) -> bool {
+        self.len < core::mem::size_of::<T>()
+    }
+
+    /// Write a result struct to the result window, truncating it to the window size.
+    ///
+    /// The window was bounds-checked against the buffer at submission time, so this never writes
+    /// outside of it. With `DebugFlags::CheckResultSize`, truncation is reported, since it means
+    /// userspace gets an incomplete result.
     fn write<T>(&mut self, mut value: T) {
         let p: *mut u8 = &mut value as *mut _ as *mut u8;
         // SAFETY: We know `p` points to a type T of that size, and UAPI types must have
         // no padding and all bit patterns valid.
         let slice = unsafe { core::slice::from_raw_parts_mut(p, core::mem::size_of::<T>()) };
-        let len = slice.len().min(self.len);
-        self.vmap.as_mut_slice()[self.offset..self.offset + len].copy_from_slice(&slice[..len]);
+        let end = self.offset + self.len;
+
+        if debug_enabled(DebugFlags::CheckResultSize) {
+            assert!(end <= self.vmap.as_mut_slice().len());
+        }
+
+        let truncated = copy_result(&mut self.vmap.as_mut_slice()[self.offset..end], slice);
+
+        if truncated && debug_enabled(DebugFlags::CheckResultSize) {
+            pr_warn!(
+                "Result at offset {:#x} truncated ({} < {} bytes for {})\n",
+                self.offset,
+                self.len,
+                slice.len(),
+                core::any::type_name::<T>()
+            );
+        }
     }
 }
 
+/// Lock class name for the fence contexts. This must be static since lockdep keeps a reference
+/// to it, so per-queue names are reported through the fence timeline name instead.
 static QUEUE_NAME: &CStr = c_str!("asahi_fence");
 static QUEUE_CLASS_KEY: kernel::sync::LockClassKey = kernel::static_lock_class!();
 
@@ -354,12 +763,23 @@ pub(crate) fn new(
         ualloc: Arc<Mutex<alloc::DefaultAllocator>>,
         ualloc_priv: Arc<Mutex<alloc::DefaultAllocator>>,
         event_manager: Arc<event::EventManager>,
+        power_hints: Arc<gpu::PowerHints>,
         mgr: &buffer::BufferManager::ver,
         id: u64,
         priority: u32,
         caps: u32,
+        name: Option<CString>,
+        initial_tvb_blocks: Option<usize>,
+        timeout_ms: u32,
     ) -> Result<Queue::ver> {
-        mod_dev_dbg!(dev, "[Queue {}] Creating queue\n", id);
+        let timeout_ms = job_timeout_ms(timeout_ms)?;
+
+        let name = match name {
+            Some(name) => name,
+            None => CString::try_from_fmt(fmt!("{}", id))?,
+        };
+
+        mod_dev_dbg!(dev, "[Queue {}] Creating queue\n", &*name);
 
         let data = dev.data();
 
@@ -386,7 +806,7 @@ pub(crate) fn new(
                 },
             )?)?;
 
-        let sched = sched::Scheduler::new(dev, WQ_SIZE, 0, 100000, c_str!("asahi_sched"))?;
+        let sched = sched::Scheduler::new(dev, WQ_SIZE, 0, timeout_ms, c_str!("asahi_sched"))?;
         // Priorities are handled by the AGX scheduler, there is no meaning within a
         // per-queue scheduler.
         let entity = sched::Entity::new(&sched, sched::Priority::Normal)?;
@@ -405,7 +825,7 @@ pub(crate) fn new(
 
         let mut ret = Queue::ver {
             dev: dev.into(),
-            _sched: sched,
+            sched,
             entity,
             vm,
             ualloc,
@@ -421,17 +841,25 @@ pub(crate) fn new(
             notifier_list: Arc::try_new(notifier_list)?,
             notifier,
             id,
-            fence_ctx: FenceContexts::new(1, QUEUE_NAME, QUEUE_CLASS_KEY)?,
+            name,
+            timeline_name: Arc::try_new(CString::try_from_fmt(fmt!("queue-{}", id))?)?,
+            stats: Arc::try_new(Default::default())?,
+            paused: Arc::pin_init(Mutex::new(None))?,
+            killed: Arc::try_new(AtomicBool::new(false))?,
+            power_hints,
+            fence_ctx: FenceContexts::new(2, QUEUE_NAME, QUEUE_CLASS_KEY)?,
+            recent_uuids: Default::default(),
+            last_fence: None,
             #[ver(V >= V13_0B4)]
             counter: AtomicU64::new(0),
         };
 
         // Rendering structures
         if caps & uapi::drm_asahi_queue_cap_DRM_ASAHI_QUEUE_CAP_RENDER != 0 {
-            let tvb_blocks = {
+            let tvb_blocks = initial_tvb_blocks.unwrap_or_else(|| {
                 let lock = crate::THIS_MODULE.kernel_param_lock();
                 *crate::initial_tvb_size.read(&lock)
-            };
+            });
 
             ret.buffer.as_ref().unwrap().ensure_blocks(tvb_blocks)?;
 
@@ -488,24 +916,80 @@ pub(crate) fn new(
             });
         }
 
-        mod_dev_dbg!(dev, "[Queue {}] Queue created\n", id);
+        mod_dev_dbg!(dev, "[Queue {}] Queue created\n", &*ret.name);
         Ok(ret)
     }
+
+    /// Warn if a command UUID was recently submitted on this queue, if enabled.
+    ///
+    /// This is purely diagnostic: reusing a UUID may be intentional, so it is never rejected.
+    fn check_uuid(&self, id: u64, kind: &str, uuid: u32) {
+        if uuid == 0 || !debug_enabled(DebugFlags::DetectUuidReuse) {
+            return;
+        }
+
+        if self.recent_uuids.check(uuid) {
+            dev_warn!(
+                self.dev,
+                "[Queue {}] Submission {}: {} command UUID {:#x} was recently submitted\n",
+                &*self.name,
+                id,
+                kind,
+                uuid
+            );
+        }
+    }
 }
 
 const SQ_RENDER: usize = uapi::drm_asahi_subqueue_DRM_ASAHI_SUBQUEUE_RENDER as usize;
 const SQ_COMPUTE: usize = uapi::drm_asahi_subqueue_DRM_ASAHI_SUBQUEUE_COMPUTE as usize;
 const SQ_COUNT: usize = uapi::drm_asahi_subqueue_DRM_ASAHI_SUBQUEUE_COUNT as usize;
 
+/// Keep only the latest item of each fence context, given the `(context, seqno)` of each item.
+///
+/// Fences on the same context signal in order, so waiting on the latest one is enough. The
+/// remaining items are sorted by context.
+fn dedup_latest<T>(items: &mut Vec<T>, key: impl Fn(&T) -> (u64, u64)) {
+    items.sort_unstable_by(|a, b| {
+        let (ctx_a, seq_a) = key(a);
+        let (ctx_b, seq_b) = key(b);
+        ctx_a.cmp(&ctx_b).then(seq_b.cmp(&seq_a))
+    });
+    items.dedup_by(|a, b| key(a).0 == key(b).0);
+}
+
+/// Look up the event a barrier waits on, given the events of each subqueue in this submission.
+///
+/// Index 0 is the last event on the subqueue before this submission, which is `None` if the
+/// subqueue was idle. Every later index refers to a command in this submission, so it always has
+/// an event.
+fn barrier_event<T: Copy>(
+    events: &[Vec<Option<T>>],
+    queue_idx: usize,
+    index: u32,
+) -> Result<Option<T>> {
+    let event = *events
+        .get(queue_idx)
+        .and_then(|ev| ev.get(index as usize))
+        .ok_or(EINVAL)?;
+
+    if event.is_none() && index != 0 {
+        return Err(EINVAL);
+    }
+    Ok(event)
+}
+
 #[versions(AGX)]
 impl Queue for Queue::ver {
     fn submit(
         &mut self,
         id: u64,
+        flags: u32,
         in_syncs: Vec<file::SyncItem>,
         out_syncs: Vec<file::SyncItem>,
         result_buf: Option<gem::ObjectRef>,
         commands: Vec<uapi::drm_asahi_command>,
+        inflight: file::InflightSlot,
     ) -> Result {
         let dev = self.dev.data();
         let gpu = match dev
@@ -521,23 +1005,48 @@ fn submit(
             }
         };
 
-        mod_dev_dbg!(self.dev, "[Submission {}] Submit job\n", id);
+        let trace = submission_traced(id);
+
+        trace_dev_dbg!(
+            trace,
+            self.dev,
+            "[Queue {}] [Submission {}] Submit job\n",
+            &*self.name,
+            id
+        );
 
         if gpu.is_crashed() {
             dev_err!(
                 self.dev,
-                "[Submission {}] GPU is crashed, cannot submit\n",
+                "[Queue {}] [Submission {}] GPU is crashed, cannot submit\n",
+                &*self.name,
                 id
             );
             return Err(ENODEV);
         }
 
-        // Empty submissions are not legal
-        if commands.is_empty() {
+        if self.killed.load(Ordering::Relaxed) {
+            return Err(ECANCELED);
+        }
+
+        // Submissions without commands just chain fences, so they must have some.
+        if commands.is_empty() && in_syncs.is_empty() && out_syncs.is_empty() {
             return Err(EINVAL);
         }
 
-        let op_guard = if !in_syncs.is_empty() {
+        // Only start the op (and kick the firmware) early if we actually have to wait on
+        // something, or if userspace explicitly asked for it. Otherwise, `run()` will start the
+        // op itself. The dependencies are still added to the job below, so a fence signaling
+        // after this check is handled by the scheduler as usual. Either way, the guard is owned
+        // by the job, so the op is always ended exactly once.
+        // Sync-only submissions never need the GPU.
+        let op_guard = if commands.is_empty() {
+            None
+        } else if flags & uapi::ASAHI_SUBMIT_EARLY_WAKEUP != 0
+            || in_syncs
+                .iter()
+                .any(|sync| sync.fence.as_ref().map_or(false, |f| !f.is_signaled()))
+        {
             Some(gpu.start_op()?)
         } else {
             None
@@ -552,15 +1061,21 @@ fn submit(
         let vm_bind = gpu.bind_vm(&self.vm)?;
         let vm_slot = vm_bind.slot();
 
-        mod_dev_dbg!(self.dev, "[Submission {}] Creating job\n", id);
+        trace_dev_dbg!(trace, self.dev, "[Submission {}] Creating job\n", id);
 
         let fence: UserFence<JobFence::ver> = self
             .fence_ctx
             .new_fence::<JobFence::ver>(
-                0,
+                FENCE_CTX_JOB,
                 JobFence::ver {
                     id,
                     pending: Default::default(),
+                    timeline_name: self.timeline_name.clone(),
+                    trace,
+                    errored: AtomicBool::new(false),
+                    killed: self.killed.clone(),
+                    deadline: AtomicI64::new(i64::MAX),
+                    power_hints: self.power_hints.clone(),
                 },
             )?
             .into();
@@ -582,18 +1097,41 @@ fn submit(
                 .as_mut()
                 .map(|a| a.new_job(Fence::from_fence(&fence))),
             fence,
+            stats: self.stats.clone(),
+            paused: self.paused.clone(),
+            killed: self.killed.clone(),
+            _inflight: inflight,
             did_run: false,
             id,
+            trace,
+            prev_fence: if commands.is_empty() {
+                self.last_fence.clone()
+            } else {
+                None
+            },
         })?;
 
-        mod_dev_dbg!(
+        // Flatten fence arrays and chains, and drop fences superseded by a later one on the same
+        // context, so the scheduler only has to track one dependency per context.
+        let mut deps = Vec::new();
+        for sync in in_syncs.iter() {
+            sync.fence
+                .as_ref()
+                .expect("in_sync missing fence")
+                .unwrap_into(&mut deps)?;
+        }
+        dedup_latest(&mut deps, |f| (f.context(), f.seqno()));
+
+        trace_dev_dbg!(
+            trace,
             self.dev,
-            "[Submission {}] Adding {} in_syncs\n",
+            "[Submission {}] Adding {} dependencies from {} in_syncs\n",
             id,
+            deps.len(),
             in_syncs.len()
         );
-        for sync in in_syncs {
-            job.add_dependency(sync.fence.expect("in_sync missing fence"))?;
+        for fence in deps {
+            job.add_dependency(fence)?;
         }
 
         let mut last_render = None;
@@ -603,29 +1141,89 @@ fn submit(
             match cmd.cmd_type {
                 uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_RENDER => last_render = Some(i),
                 uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_COMPUTE => last_compute = Some(i),
+                uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_BARRIER => {
+                    if cmd.flags != 0
+                        || cmd.cmd_buffer != 0
+                        || cmd.cmd_buffer_size != 0
+                        || cmd.result_offset != 0
+                        || cmd.result_size != 0
+                    {
+                        return Err(EINVAL);
+                    }
+                }
                 _ => return Err(EINVAL),
             }
         }
 
-        mod_dev_dbg!(
+        // Barrier commands apply to the command that follows them, so one can't come last.
+        if commands.last().map(|c| c.cmd_type)
+            == Some(uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_BARRIER)
+        {
+            return Err(EINVAL);
+        }
+
+        // Waits from barrier commands, not yet attached to a command.
+        let mut pending_barriers = [uapi::DRM_ASAHI_BARRIER_NONE as u32; SQ_COUNT];
+
+        trace_dev_dbg!(
+            trace,
             self.dev,
             "[Submission {}] Submitting {} commands\n",
             id,
             commands.len()
         );
         for (i, cmd) in commands.into_iter().enumerate() {
-            for (queue_idx, index) in cmd.barriers.iter().enumerate() {
+            if cmd.cmd_type == uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_BARRIER {
+                // The firmware Barrier command doesn't advance the queue's event stamp, so it
+                // cannot be submitted on its own. Instead, fold the waits into the next command.
+                // Events on a subqueue complete in order, so waiting on the later one suffices.
+                for (queue_idx, index) in cmd.barriers.iter().enumerate() {
+                    if *index == uapi::DRM_ASAHI_BARRIER_NONE as u32 {
+                        continue;
+                    }
+                    barrier_event(&events, queue_idx, *index)?;
+                    let pending = &mut pending_barriers[queue_idx];
+                    if *pending == uapi::DRM_ASAHI_BARRIER_NONE as u32 || *pending < *index {
+                        *pending = *index;
+                    }
+                }
+                trace_dev_dbg!(
+                    trace,
+                    self.dev,
+                    "[Submission {}] Barrier command: {:?}\n",
+                    id,
+                    pending_barriers
+                );
+                continue;
+            }
+
+            let mut barriers = cmd.barriers;
+            for (barrier, pending) in barriers.iter_mut().zip(pending_barriers.iter_mut()) {
+                if *barrier == uapi::DRM_ASAHI_BARRIER_NONE as u32
+                    || (*pending != uapi::DRM_ASAHI_BARRIER_NONE as u32 && *pending > *barrier)
+                {
+                    *barrier = *pending;
+                }
+                *pending = uapi::DRM_ASAHI_BARRIER_NONE as u32;
+            }
+
+            for (queue_idx, index) in barriers.iter().enumerate() {
                 if *index == uapi::DRM_ASAHI_BARRIER_NONE as u32 {
                     continue;
                 }
-                if let Some(event) = events[queue_idx].get(*index as usize).ok_or(EINVAL)? {
+                if let Some(event) = barrier_event(&events, queue_idx, *index)? {
                     let mut alloc = gpu.alloc();
                     let queue_job = match cmd.cmd_type {
                         uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_RENDER => job.get_vtx()?,
                         uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_COMPUTE => job.get_comp()?,
                         _ => return Err(EINVAL),
                     };
-                    mod_dev_dbg!(self.dev, "[Submission {}] Create Explicit Barrier\n", id);
+                    trace_dev_dbg!(
+                        trace,
+                        self.dev,
+                        "[Submission {}] Create Explicit Barrier\n",
+                        id
+                    );
                     let barrier = alloc.private.new_init(
                         kernel::init::zeroed::<fw::workqueue::Barrier>(),
                         |_inner, _p| {
@@ -642,10 +1240,13 @@ fn submit(
                             })
                         },
                     )?;
-                    mod_dev_dbg!(self.dev, "[Submission {}] Add Explicit Barrier\n", id);
+                    trace_dev_dbg!(
+                        trace,
+                        self.dev,
+                        "[Submission {}] Add Explicit Barrier\n",
+                        id
+                    );
                     queue_job.add(barrier, vm_slot)?;
-                } else {
-                    assert!(*index == 0);
                 }
             }
 
@@ -666,10 +1267,12 @@ fn submit(
                         {
                             return Err(EINVAL);
                         }
+                        let pin = buf.pin_pages()?;
                         Some(ResultWriter {
                             vmap: buf.gem.vmap()?,
                             offset: cmd.result_offset.try_into()?,
                             len: cmd.result_size.try_into()?,
+                            _pin: pin,
                         })
                     } else {
                         None
@@ -718,16 +1321,22 @@ fn submit(
             }
         }
 
-        mod_dev_dbg!(self.dev, "Queue: Committing job\n");
+        trace_dev_dbg!(trace, self.dev, "[Submission {}] Committing job\n", id);
         job.commit()?;
 
-        mod_dev_dbg!(self.dev, "Queue: Arming job\n");
+        trace_dev_dbg!(trace, self.dev, "[Submission {}] Arming job\n", id);
         let job = job.arm();
         let out_fence = job.fences().finished();
-        mod_dev_dbg!(self.dev, "Queue: Pushing job\n");
+        trace_dev_dbg!(trace, self.dev, "[Submission {}] Pushing job\n", id);
         job.push();
 
-        mod_dev_dbg!(self.dev, "Queue: Adding {} out_syncs\n", out_syncs.len());
+        trace_dev_dbg!(
+            trace,
+            self.dev,
+            "[Submission {}] Adding {} out_syncs\n",
+            id,
+            out_syncs.len()
+        );
         for mut sync in out_syncs {
             if let Some(chain) = sync.chain_fence.take() {
                 sync.syncobj
@@ -737,13 +1346,105 @@ fn submit(
             }
         }
 
+        self.last_fence = Some(out_fence);
+
         Ok(())
     }
+
+    fn stats(&self) -> &QueueStats {
+        &self.stats
+    }
+
+    fn pause(&self) -> Result {
+        let mut paused = self.paused.lock();
+        if paused.is_some() || self.killed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        mod_dev_dbg!(self.dev, "[Queue {}] Pausing\n", &*self.name);
+        *paused = Some(
+            self.fence_ctx
+                .new_fence::<PauseFence>(FENCE_CTX_PAUSE, PauseFence)?
+                .into(),
+        );
+        Ok(())
+    }
+
+    fn resume(&self) -> Result {
+        // Take the fence out before signaling it, so that jobs re-checked by the scheduler
+        // from the fence callback see the queue as running.
+        let fence = self.paused.lock().take();
+
+        if let Some(fence) = fence {
+            mod_dev_dbg!(self.dev, "[Queue {}] Resuming\n", &*self.name);
+            fence.signal()?;
+        }
+        Ok(())
+    }
+
+    fn set_timeout(&self, timeout_ms: u32) -> Result {
+        let timeout_ms = job_timeout_ms(timeout_ms)?;
+
+        mod_dev_dbg!(
+            self.dev,
+            "[Queue {}] Setting job timeout to {} ms\n",
+            &*self.name,
+            timeout_ms
+        );
+        self.sched.set_timeout(timeout_ms)
+    }
+
+    fn set_priority(&mut self, priority: u32) -> Result {
+        if priority >= workqueue::NUM_PRIORITIES {
+            return Err(EINVAL);
+        }
+
+        mod_dev_dbg!(
+            self.dev,
+            "[Queue {}] Setting priority to {}\n",
+            &*self.name,
+            priority
+        );
+
+        for sq in [&self.q_vtx, &self.q_frag, &self.q_comp]
+            .into_iter()
+            .flatten()
+        {
+            sq.wq.set_priority(priority)?;
+        }
+        Ok(())
+    }
+
+    fn kill(&mut self) {
+        if self.killed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        mod_dev_dbg!(self.dev, "[Queue {}] Killing queue\n", &*self.name);
+
+        // Release any held jobs, so the scheduler cancels them now instead of on resume.
+        if self.resume().is_err() {
+            dev_err!(
+                self.dev,
+                "[Queue {}] Failed to resume killed queue\n",
+                &*self.name
+            );
+        }
+    }
 }
 
 #[versions(AGX)]
 impl Drop for Queue::ver {
     fn drop(&mut self) {
-        mod_dev_dbg!(self.dev, "[Queue {}] Dropping queue\n", self.id);
+        mod_dev_dbg!(self.dev, "[Queue {}] Dropping queue\n", &*self.name);
+
+        // Release any held jobs, so that the entity can be torn down.
+        if self.resume().is_err() {
+            dev_err!(
+                self.dev,
+                "[Queue {}] Failed to resume queue on drop\n",
+                &*self.name
+            );
+        }
     }
 }
@@ -7,7 +7,7 @@
 //! This module is in charge of creating all of the firmware structures required to submit 3D
 //! rendering work to the GPU, based on the userspace command buffer.
 
-use super::common;
+use super::common::{self, Access};
 use crate::alloc::Allocator;
 use crate::debug::*;
 use crate::fw::types::*;
@@ -24,6 +24,7 @@
 use kernel::new_mutex;
 use kernel::prelude::*;
 use kernel::sync::Arc;
+use kernel::time;
 use kernel::uapi;
 use kernel::user_ptr::UserSlicePtr;
 
@@ -35,6 +36,65 @@
 /// cluster.
 const TILECTL_DISABLE_CLUSTERING: u32 = 1u32 << 0;
 
+/// Granule of the depth/stencil layer strides. The strides encode the layer stride in units of
+/// this size (minus one) starting at bit 14.
+const ZLS_STRIDE_GRANULE: u64 = 0x4000;
+
+/// Checks that a depth/stencil layer stride can hold at least `layer_size` bytes.
+///
+/// The driver does not know the exact layout (twiddling, compression) userspace picked, so this is
+/// only a lower bound based on the uncompressed size of one layer. Unused buffers are ignored.
+fn zls_stride_valid(ptr: u64, stride: u64, layer_size: u64) -> bool {
+    ptr == 0 || ((stride >> 14) + 1) * ZLS_STRIDE_GRANULE >= layer_size
+}
+
+/// ISP_BGOBJVALS bit that the macOS driver always sets for the background load on G13, but never
+/// in the value userspace provides for the store. Its meaning is unknown, and G14 and later don't
+/// use it.
+const BGOBJVALS_G13_LOAD: u64 = 0x400;
+
+/// Number of macrotiles per axis in the hardware tiling grid.
+const MTILE_GRID: u32 = 4;
+
+/// Maximum value of a macrotile split point in the te_mtile1/te_mtile2 encodings (9 bits).
+const MTILE_SPLIT_MAX: u32 = 0x1ff;
+
+/// Returns the number of macrotiles to use along an axis `tiles` tiles long.
+///
+/// Overrides that don't evenly divide the hardware grid, or that would make the split points
+/// overflow their register fields, are ignored.
+fn mtiles_for_axis(tiles: u32, mtiles_override: u32) -> u32 {
+    match mtiles_override {
+        1 | 2
+            if (MTILE_GRID - 1) * align(div_ceil(tiles, mtiles_override), 4) <= MTILE_SPLIT_MAX =>
+        {
+            mtiles_override
+        }
+        _ => MTILE_GRID,
+    }
+}
+
+/// Restricts a packed vertex core mask to the cores of a single cluster.
+///
+/// The cluster is picked by rotating through the clusters that have cores in `mask` using `seq`,
+/// so consecutive single-cluster jobs are spread over the whole GPU.
+fn single_cluster_mask(mask: [u32; 2], num_cores: u32, num_clusters: u32, seq: u64) -> [u32; 2] {
+    let packed = mask[0] as u64 | (mask[1] as u64) << 32;
+    let cluster_bits = (1u64 << num_cores) - 1;
+    let cluster_mask = |c: u32| packed & (cluster_bits << (c * num_cores));
+
+    let nonempty = || (0..num_clusters).filter(|&c| cluster_mask(c) != 0);
+    let count = nonempty().count() as u64;
+    if count == 0 {
+        return mask;
+    }
+
+    let single = nonempty()
+        .nth((seq % count) as usize)
+        .map_or(packed, cluster_mask);
+    [single as u32, (single >> 32) as u32]
+}
+
 struct RenderResult {
     result: uapi::drm_asahi_result_render,
     vtx_complete: bool,
@@ -63,6 +123,10 @@ fn commit(&mut self) {
             self.result.info.status = uapi::drm_asahi_status_DRM_ASAHI_STATUS_COMPLETE;
         }
 
+        if self.writer.truncates::<uapi::drm_asahi_result_render>() {
+            self.result.flags |= uapi::DRM_ASAHI_RESULT_TRUNCATED as u64;
+        }
+
         self.writer.write(self.result);
     }
 }
@@ -73,16 +137,13 @@ impl super::Queue::ver {
     fn get_tiling_params(
         cmdbuf: &uapi::drm_asahi_cmd_render,
         num_clusters: u32,
+        max_layers: u32,
     ) -> Result<buffer::TileInfo> {
         let width: u32 = cmdbuf.fb_width;
         let height: u32 = cmdbuf.fb_height;
         let layers: u32 = cmdbuf.layers;
 
-        if width > 65536 || height > 65536 {
-            return Err(EINVAL);
-        }
-
-        if layers == 0 || layers > 2048 {
+        if layers == 0 || layers > max_layers {
             return Err(EINVAL);
         }
 
@@ -106,9 +167,17 @@ fn get_tiling_params(
         let tiles_y = (height + tile_height - 1) / tile_height;
         let tiles = tiles_x * tiles_y;
 
-        let mtiles_x = 4u32;
-        let mtiles_y = 4u32;
-        let mtiles = mtiles_x * mtiles_y;
+        // The macrotile grid is always 4x4, but fewer (larger) macrotiles per axis can be used for
+        // experiments, by sizing the macrotiles so the outer split points fall past the edge of the
+        // framebuffer. Memory is still allocated for the full grid.
+        let (mtiles_x, mtiles_y) = {
+            let lock = crate::THIS_MODULE.kernel_param_lock();
+            (
+                mtiles_for_axis(tiles_x, *crate::mtiles_x.read(&lock)),
+                mtiles_for_axis(tiles_y, *crate::mtiles_y.read(&lock)),
+            )
+        };
+        let mtiles = MTILE_GRID * MTILE_GRID;
 
         let tiles_per_mtile_x = align(div_ceil(tiles_x, mtiles_x), 4);
         let tiles_per_mtile_y = align(div_ceil(tiles_y, mtiles_y), 4);
@@ -205,7 +274,12 @@ pub(super) fn submit_render(
             return Err(EINVAL);
         }
 
-        mod_dev_dbg!(self.dev, "[Submission {}] Render!\n", id);
+        mod_dev_dbg!(
+            self.dev,
+            "[Queue {}] [Submission {}] Render!\n",
+            &*self.name,
+            id
+        );
 
         let mut cmdbuf_reader = unsafe {
             UserSlicePtr::new(
@@ -230,20 +304,26 @@ pub(super) fn submit_render(
                 | uapi::ASAHI_RENDER_SYNC_TVB_GROWTH
                 | uapi::ASAHI_RENDER_PROCESS_EMPTY_TILES
                 | uapi::ASAHI_RENDER_NO_VERTEX_CLUSTERING
-                | uapi::ASAHI_RENDER_MSAA_ZS) as u64
+                | uapi::ASAHI_RENDER_MSAA_ZS
+                | uapi::ASAHI_RENDER_NORMALIZED_TIMESTAMPS
+                | uapi::ASAHI_RENDER_NO_TVB_GROWTH
+                | uapi::ASAHI_RENDER_SINGLE_CLUSTER) as u64
             != 0
         {
             return Err(EINVAL);
         }
 
+        // This also bounds the tiling parameter calculations in get_tiling_params()
+        let max_dim = self.dev.data().gpu.get_cfg().limits.max_framebuffer_dim;
         if cmdbuf.fb_width == 0
             || cmdbuf.fb_height == 0
-            || cmdbuf.fb_width > 16384
-            || cmdbuf.fb_height > 16384
+            || cmdbuf.fb_width > max_dim
+            || cmdbuf.fb_height > max_dim
         {
             mod_dev_dbg!(
                 self.dev,
-                "[Submission {}] Invalid dimensions {}x{}\n",
+                "[Queue {}] [Submission {}] Invalid dimensions {}x{}\n",
+                &*self.name,
                 id,
                 cmdbuf.fb_width,
                 cmdbuf.fb_height
@@ -251,10 +331,116 @@ pub(super) fn submit_render(
             return Err(EINVAL);
         }
 
+        // The layer strides are only used for layered rendering
+        if cmdbuf.layers > 1 {
+            let pixels =
+                cmdbuf.fb_width as u64 * cmdbuf.fb_height as u64 * (cmdbuf.samples as u64).max(1);
+            // Z16 and S8 are the smallest formats
+            let depth_size = pixels * 2;
+            let stencil_size = pixels;
+
+            let depth = [
+                (cmdbuf.depth_buffer_load, cmdbuf.depth_buffer_load_stride),
+                (cmdbuf.depth_buffer_store, cmdbuf.depth_buffer_store_stride),
+                (
+                    cmdbuf.depth_buffer_partial,
+                    cmdbuf.depth_buffer_partial_stride,
+                ),
+            ];
+            let stencil = [
+                (
+                    cmdbuf.stencil_buffer_load,
+                    cmdbuf.stencil_buffer_load_stride,
+                ),
+                (
+                    cmdbuf.stencil_buffer_store,
+                    cmdbuf.stencil_buffer_store_stride,
+                ),
+                (
+                    cmdbuf.stencil_buffer_partial,
+                    cmdbuf.stencil_buffer_partial_stride,
+                ),
+            ];
+            let strides = depth
+                .iter()
+                .map(|&(ptr, stride)| (ptr, stride, depth_size))
+                .chain(
+                    stencil
+                        .iter()
+                        .map(|&(ptr, stride)| (ptr, stride, stencil_size)),
+                );
+
+            for (ptr, stride, size) in strides {
+                if !zls_stride_valid(ptr, stride, size) {
+                    mod_dev_dbg!(
+                        self.dev,
+                        "[Queue {}] [Submission {}] ZLS stride {:#x} too small for {}x{}x{}\n",
+                        &*self.name,
+                        id,
+                        stride,
+                        cmdbuf.fb_width,
+                        cmdbuf.fb_height,
+                        cmdbuf.samples
+                    );
+                    return Err(EINVAL);
+                }
+            }
+        }
+
+        // The load (clear) pipeline address and binding go together, and the no-textures flag
+        // describes the load pipeline, so it makes no sense without one.
+        let has_load_pipeline = cmdbuf.load_pipeline != 0;
+        if has_load_pipeline != (cmdbuf.load_pipeline_bind != 0)
+            || (cmdbuf.flags & uapi::ASAHI_RENDER_NO_CLEAR_PIPELINE_TEXTURES as u64 != 0
+                && !has_load_pipeline)
+        {
+            mod_dev_dbg!(
+                self.dev,
+                "[Queue {}] [Submission {}] Invalid load pipeline {:#x}/{:#x} (flags {:#x})\n",
+                &*self.name,
+                id,
+                cmdbuf.load_pipeline,
+                cmdbuf.load_pipeline_bind,
+                cmdbuf.flags
+            );
+            return Err(EINVAL);
+        }
+
+        // Z/S reloading needs something to reload from. This flag's exact semantics are not
+        // fully understood, so allow overriding the check for experimentation.
+        if cmdbuf.flags & uapi::ASAHI_RENDER_SET_WHEN_RELOADING_Z_OR_S as u64 != 0
+            && cmdbuf.depth_buffer_load == 0
+            && cmdbuf.stencil_buffer_load == 0
+            && !debug_enabled(debug::DebugFlags::AllowUnknownOverrides)
+        {
+            mod_dev_dbg!(
+                self.dev,
+                "[Queue {}] [Submission {}] Z/S reload requested without a Z/S load buffer\n",
+                &*self.name,
+                id
+            );
+            return Err(EINVAL);
+        }
+
         let mut unks: uapi::drm_asahi_cmd_render_unknowns = Default::default();
+        let mut core_mask_ext: Option<uapi::drm_asahi_cmd_render_core_mask> = None;
 
         let mut ext_ptr = cmdbuf.extensions;
         while ext_ptr != 0 {
+            // All extension structs share a common header with 64-bit fields, so they all have
+            // the same alignment. Reject misaligned pointers before reading anything.
+            let ext_align = core::mem::align_of::<uapi::drm_asahi_cmd_render_unknowns>();
+            if ext_ptr % ext_align as u64 != 0 {
+                mod_dev_dbg!(
+                    self.dev,
+                    "[Queue {}] [Submission {}] Misaligned extension pointer {:#x}\n",
+                    &*self.name,
+                    id,
+                    ext_ptr
+                );
+                return Err(EINVAL);
+            }
+
             let ext_type = u32::from_ne_bytes(
                 unsafe { UserSlicePtr::new(ext_ptr as usize as *mut _, 4) }
                     .read_all()?
@@ -283,6 +469,29 @@ pub(super) fn submit_render(
 
                     ext_ptr = unks.next;
                 }
+                uapi::ASAHI_RENDER_EXT_CORE_MASK => {
+                    let mut ext: uapi::drm_asahi_cmd_render_core_mask = Default::default();
+                    let mut ext_reader = unsafe {
+                        UserSlicePtr::new(
+                            ext_ptr as usize as *mut _,
+                            core::mem::size_of::<uapi::drm_asahi_cmd_render_core_mask>(),
+                        )
+                        .reader()
+                    };
+                    unsafe {
+                        ext_reader.read_raw(
+                            &mut ext as *mut _ as *mut u8,
+                            core::mem::size_of::<uapi::drm_asahi_cmd_render_core_mask>(),
+                        )?;
+                    }
+
+                    if ext.pad != 0 || core_mask_ext.is_some() {
+                        return Err(EINVAL);
+                    }
+
+                    ext_ptr = ext.next;
+                    core_mask_ext = Some(ext);
+                }
                 _ => return Err(EINVAL),
             }
         }
@@ -291,6 +500,105 @@ pub(super) fn submit_render(
             return Err(EINVAL);
         }
 
+        // Newer GPUs do not take a core mask for vertex jobs, so manual placement is not
+        // supported there.
+        #[ver(G >= G14)]
+        if core_mask_ext.is_some() {
+            return Err(EINVAL);
+        }
+
+        let addrs = [
+            ("encoder_ptr", cmdbuf.encoder_ptr, Access::Read),
+            ("vertex_helper_arg", cmdbuf.vertex_helper_arg, Access::Read),
+            (
+                "fragment_helper_arg",
+                cmdbuf.fragment_helper_arg,
+                Access::Read,
+            ),
+            (
+                "vertex_sampler_array",
+                cmdbuf.vertex_sampler_array,
+                Access::Read,
+            ),
+            (
+                "fragment_sampler_array",
+                cmdbuf.fragment_sampler_array,
+                Access::Read,
+            ),
+            ("scissor_array", cmdbuf.scissor_array, Access::Read),
+            ("depth_bias_array", cmdbuf.depth_bias_array, Access::Read),
+            (
+                "visibility_result_buffer",
+                cmdbuf.visibility_result_buffer,
+                Access::ReadWrite,
+            ),
+            ("depth_buffer_load", cmdbuf.depth_buffer_load, Access::Read),
+            (
+                "depth_buffer_store",
+                cmdbuf.depth_buffer_store,
+                Access::Write,
+            ),
+            (
+                "depth_buffer_partial",
+                cmdbuf.depth_buffer_partial,
+                Access::ReadWrite,
+            ),
+            (
+                "depth_meta_buffer_load",
+                cmdbuf.depth_meta_buffer_load,
+                Access::Read,
+            ),
+            (
+                "depth_meta_buffer_store",
+                cmdbuf.depth_meta_buffer_store,
+                Access::Write,
+            ),
+            (
+                "depth_meta_buffer_partial",
+                cmdbuf.depth_meta_buffer_partial,
+                Access::ReadWrite,
+            ),
+            (
+                "stencil_buffer_load",
+                cmdbuf.stencil_buffer_load,
+                Access::Read,
+            ),
+            (
+                "stencil_buffer_store",
+                cmdbuf.stencil_buffer_store,
+                Access::Write,
+            ),
+            (
+                "stencil_buffer_partial",
+                cmdbuf.stencil_buffer_partial,
+                Access::ReadWrite,
+            ),
+            (
+                "stencil_meta_buffer_load",
+                cmdbuf.stencil_meta_buffer_load,
+                Access::Read,
+            ),
+            (
+                "stencil_meta_buffer_store",
+                cmdbuf.stencil_meta_buffer_store,
+                Access::Write,
+            ),
+            (
+                "stencil_meta_buffer_partial",
+                cmdbuf.stencil_meta_buffer_partial,
+                Access::ReadWrite,
+            ),
+        ];
+        common::validate_addresses(&self.dev, &self.vm, id, &addrs)?;
+        common::audit_command(
+            &self.dev,
+            &self.vm,
+            self.id,
+            id,
+            uapi::drm_asahi_cmd_type_DRM_ASAHI_CMD_RENDER,
+            &addrs,
+        );
+
         let dev = self.dev.data();
         let gpu = match dev.gpu.as_any().downcast_ref::<gpu::GpuManager::ver>() {
             Some(gpu) => gpu,
@@ -300,18 +608,57 @@ pub(super) fn submit_render(
             }
         };
 
+        // The requested mask must be a non-empty subset of the active cores.
+        #[ver(G < G14)]
+        let core_masks = {
+            let active = gpu.core_masks_packed();
+            let active = [*active.first().unwrap_or(&0), *active.get(1).unwrap_or(&0)];
+            match core_mask_ext {
+                None => active,
+                Some(ext) => {
+                    let mask = ext.core_mask;
+                    if mask.iter().all(|&m| m == 0)
+                        || mask.iter().zip(active.iter()).any(|(&m, &a)| m & !a != 0)
+                    {
+                        mod_dev_dbg!(
+                            self.dev,
+                            "[Queue {}] [Submission {}] Invalid core mask {:#x?} (active: {:#x?})\n",
+                            &*self.name,
+                            id,
+                            mask,
+                            active
+                        );
+                        return Err(EINVAL);
+                    }
+                    mask
+                }
+            }
+        };
+
         let nclusters = gpu.get_dyncfg().id.num_clusters;
 
         // Can be set to false to disable clustering (for simpler jobs), but then the
         // core masks below should be adjusted to cover a single rolling cluster.
         let mut clustering = nclusters > 1;
 
+        // Forces single-cluster execution including the core masks, for debugging.
+        let single_cluster = cmdbuf.flags & uapi::ASAHI_RENDER_SINGLE_CLUSTER as u64 != 0;
+
         if debug_enabled(debug::DebugFlags::DisableClustering)
             || cmdbuf.flags & uapi::ASAHI_RENDER_NO_VERTEX_CLUSTERING as u64 != 0
+            || single_cluster
         {
             clustering = false;
         }
 
+        // G14 has no vertex core masks, so there is nothing more to restrict there.
+        #[ver(G < G14)]
+        let core_masks = if single_cluster {
+            single_cluster_mask(core_masks, gpu.get_dyncfg().id.num_cores, nclusters, id)
+        } else {
+            core_masks
+        };
+
         #[ver(G != G14)]
         let mut tiling_control = {
             let render_cfg = gpu.get_cfg().render;
@@ -330,31 +677,54 @@ pub(super) fn submit_render(
         // but it's unclear *which* slot...
         let slot_client_seq: u8 = (self.id & 0xff) as u8;
 
-        let tile_info = Self::get_tiling_params(&cmdbuf, if clustering { nclusters } else { 1 })?;
+        let tile_info = Self::get_tiling_params(
+            &cmdbuf,
+            if clustering { nclusters } else { 1 },
+            gpu.get_cfg().limits.max_framebuffer_layers,
+        )?;
 
         let buffer = self.buffer.as_ref().ok_or(EINVAL)?;
 
         let notifier = self.notifier.clone();
 
-        let tvb_autogrown = buffer.auto_grow()?;
+        // With NO_TVB_GROWTH, userspace sized the TVB itself and wants overflows reported as
+        // errors instead. The overflow statistics are left alone, so they still drive growth for
+        // later submissions that do allow it.
+        let no_grow = cmdbuf.flags & uapi::ASAHI_RENDER_NO_TVB_GROWTH as u64 != 0;
+        if no_grow && (buffer.block_count() as usize) < tile_info.min_tvb_blocks {
+            cls_dev_dbg!(
+                TVBStats,
+                &self.dev,
+                "[Queue {}] [Submission {}] TVB too small ({} < {} blocks) and growth disabled\n",
+                &*self.name,
+                id,
+                buffer.block_count(),
+                tile_info.min_tvb_blocks,
+            );
+            return Err(ENOSPC);
+        }
+
+        let tvb_autogrown = !no_grow && buffer.auto_grow()?;
         if tvb_autogrown {
             let new_size = buffer.block_count() as usize;
             cls_dev_dbg!(
                 TVBStats,
                 &self.dev,
-                "[Submission {}] TVB grew to {} bytes ({} blocks) due to overflows\n",
+                "[Queue {}] [Submission {}] TVB grew to {} bytes ({} blocks) due to overflows\n",
+                &*self.name,
                 id,
                 new_size * buffer::BLOCK_SIZE,
                 new_size,
             );
         }
 
-        let tvb_grown = buffer.ensure_blocks(tile_info.min_tvb_blocks)?;
+        let tvb_grown = !no_grow && buffer.ensure_blocks(tile_info.min_tvb_blocks)?;
         if tvb_grown {
             cls_dev_dbg!(
                 TVBStats,
                 &self.dev,
-                "[Submission {}] TVB grew to {} bytes ({} blocks) due to dimensions ({}x{})\n",
+                "[Queue {}] [Submission {}] TVB grew to {} bytes ({} blocks) due to dimensions ({}x{})\n",
+                &*self.name,
                 id,
                 tile_info.min_tvb_blocks * buffer::BLOCK_SIZE,
                 tile_info.min_tvb_blocks,
@@ -395,6 +765,9 @@ pub(super) fn submit_render(
         let uuid_3d = cmdbuf.cmd_3d_id;
         let uuid_ta = cmdbuf.cmd_ta_id;
 
+        self.check_uuid(id, "Vertex", uuid_ta);
+        self.check_uuid(id, "Fragment", uuid_3d);
+
         mod_dev_dbg!(
             self.dev,
             "[Submission {}] Vert UUID = {:#x?}\n",
@@ -442,12 +815,34 @@ pub(super) fn submit_render(
         if cmdbuf.layers > 1 {
             tile_config |= 1;
         }
+        self.stats.render_cmds.fetch_add(1, Ordering::Relaxed);
         if cmdbuf.flags & uapi::ASAHI_RENDER_PROCESS_EMPTY_TILES as u64 != 0 {
             tile_config |= 0x10000;
+            self.stats.empty_tiles.fetch_add(1, Ordering::Relaxed);
+
+            // Empty tiles only need to run the end-of-tile work, so if nothing is stored at the
+            // end of the pass, processing them just burns GPU time. Keep honoring the flag, since
+            // we can't see everything the store pipeline does, but let userspace know.
+            if cmdbuf.store_pipeline == 0
+                && cmdbuf.depth_buffer_store == 0
+                && cmdbuf.stencil_buffer_store == 0
+            {
+                self.stats
+                    .empty_tiles_unneeded
+                    .fetch_add(1, Ordering::Relaxed);
+                mod_dev_dbg!(
+                    self.dev,
+                    "[Queue {}] [Submission {}] Empty tile processing requested with no stores\n",
+                    &*self.name,
+                    id
+                );
+            }
         }
 
         let mut utile_config =
             ((tile_info.utile_width / 16) << 12) | ((tile_info.utile_height / 16) << 14);
+        // The AGX GPUs we support only do 1x, 2x and 4x MSAA, like Metal reports on them. The
+        // encoding for 8x (if any) is unknown.
         utile_config |= match cmdbuf.samples {
             1 => 0,
             2 => 1,
@@ -462,6 +857,14 @@ pub(super) fn submit_render(
             | (if unk1 { 0 } else { 0x20_00000000 })
             | ((utile_config as u64 & 0xf000) << 28);
 
+        // Timestamps are converted with the same clock for both commands, so the ordering
+        // between the vertex and fragment timestamps is preserved.
+        let ts_clock_hz = if cmdbuf.flags & uapi::ASAHI_RENDER_NORMALIZED_TIMESTAMPS as u64 != 0 {
+            Some(gpu.get_cfg().base_clock_hz)
+        } else {
+            None
+        };
+
         let frag_result = result_writer
             .map(|writer| {
                 let mut result = RenderResult {
@@ -473,12 +876,20 @@ pub(super) fn submit_render(
                     writer,
                 };
 
+                if ts_clock_hz.is_some() {
+                    result.result.flags |= uapi::DRM_ASAHI_RESULT_TIMESTAMPS_NS as u64;
+                }
                 if tvb_autogrown {
                     result.result.flags |= uapi::DRM_ASAHI_RESULT_RENDER_TVB_GROW_OVF as u64;
                 }
                 if tvb_grown {
                     result.result.flags |= uapi::DRM_ASAHI_RESULT_RENDER_TVB_GROW_MIN as u64;
                 }
+                // The firmware does not report whether a job was actually preempted, so report
+                // whether it could have been instead.
+                if cmdbuf.flags & uapi::ASAHI_RENDER_NO_PREEMPTION as u64 == 0 {
+                    result.result.flags |= uapi::DRM_ASAHI_RESULT_RENDER_PREEMPTIBLE as u64;
+                }
                 result.result.tvb_size_bytes = buffer.size() as u64;
 
                 Arc::pin_init(new_mutex!(result, "render result"))
@@ -523,7 +934,19 @@ pub(super) fn submit_render(
         if unks.flags & uapi::ASAHI_RENDER_UNK_SET_LOAD_BGOBJVALS as u64 == 0 {
             unks.load_bgobjvals = cmdbuf.isp_bgobjvals.into();
             #[ver(G < G14)]
-            unks.load_bgobjvals |= 0x400;
+            unks.load_bgobjvals |= BGOBJVALS_G13_LOAD;
+        }
+        // ISP_BGOBJVALS is a 32-bit register, and the firmware fields holding it are 32 bits
+        // wide. Don't let an override silently truncate on some paths and not others.
+        if unks.load_bgobjvals > u32::MAX as u64 {
+            mod_dev_dbg!(
+                self.dev,
+                "[Queue {}] [Submission {}] Invalid load_bgobjvals override {:#x}\n",
+                &*self.name,
+                id,
+                unks.load_bgobjvals
+            );
+            return Err(EINVAL);
         }
         if unks.flags & uapi::ASAHI_RENDER_UNK_SET_FRG_UNK_38 as u64 == 0 {
             unks.frg_unk_38 = 0;
@@ -1063,15 +1486,22 @@ pub(super) fn submit_render(
         mod_dev_dbg!(self.dev, "[Submission {}] Add Frag\n", id);
         fence.add_command();
 
+        let stats = self.stats.clone();
         frag_job.add_cb(frag, vm_bind.slot(), move |cmd, error| {
             if let Some(err) = error {
-                fence.set_error(err.into());
+                fence.fail(err.into());
             }
+            stats.record_partial_renders(cmd.with(|raw, _inner| raw.tvb_overflow_count));
             if let Some(mut res) = frag_result.as_ref().map(|a| a.lock()) {
                 cmd.timestamps.with(|raw, _inner| {
                     res.result.fragment_ts_start = raw.frag.start.load(Ordering::Relaxed);
                     res.result.fragment_ts_end = raw.frag.end.load(Ordering::Relaxed);
                 });
+                if let Some(hz) = ts_clock_hz {
+                    res.result.fragment_ts_start =
+                        time::ticks_to_ns(res.result.fragment_ts_start, hz);
+                    res.result.fragment_ts_end = time::ticks_to_ns(res.result.fragment_ts_end, hz);
+                }
                 cmd.with(|raw, _inner| {
                     res.result.num_tvb_overflows = raw.tvb_overflow_count;
                 });
@@ -1252,8 +1682,6 @@ pub(super) fn submit_render(
             },
             |inner, _ptr| {
                 let vm_slot = vm_bind.slot();
-                #[ver(G < G14)]
-                let core_masks = gpu.core_masks_packed();
 
                 try_init!(fw::vertex::raw::RunVertex::ver {
                     tag: fw::workqueue::CommandType::RunVertex,
@@ -1292,10 +1720,7 @@ pub(super) fn submit_render(
                         #[ver(G < G14)]
                         unk_60: U64(0x0), // fixed
                         #[ver(G < G14)]
-                        core_mask: Array::new([
-                            *core_masks.first().unwrap_or(&0),
-                            *core_masks.get(1).unwrap_or(&0),
-                        ]),
+                        core_mask: Array::new(core_masks),
                         preempt_buf1: inner.scene.preempt_buf_1_pointer(),
                         preempt_buf2: inner.scene.preempt_buf_2_pointer(),
                         unk_80: U64(0x1), // fixed
@@ -1517,18 +1942,35 @@ pub(super) fn submit_render(
 
         mod_dev_dbg!(self.dev, "[Submission {}] Add Vertex\n", id);
         fence.add_command();
+        let stats = self.stats.clone();
         vtx_job.add_cb(vtx, vm_bind.slot(), move |cmd, error| {
             if let Some(err) = error {
-                fence.set_error(err.into())
+                fence.fail(err.into())
+            }
+            let used_bytes = cmd.scene.used_bytes() as u64;
+            let overflowed = cmd.scene.overflowed();
+            stats.record_tvb(used_bytes, overflowed);
+            // The firmware still completes the render with partial renders, but userspace asked
+            // for the overflow to fail the job instead.
+            if overflowed && no_grow && error.is_none() {
+                fence.fail(ENOSPC);
             }
             if let Some(mut res) = vtx_result.as_ref().map(|a| a.lock()) {
                 cmd.timestamps.with(|raw, _inner| {
                     res.result.vertex_ts_start = raw.vtx.start.load(Ordering::Relaxed);
                     res.result.vertex_ts_end = raw.vtx.end.load(Ordering::Relaxed);
                 });
-                res.result.tvb_usage_bytes = cmd.scene.used_bytes() as u64;
-                if cmd.scene.overflowed() {
+                if let Some(hz) = ts_clock_hz {
+                    res.result.vertex_ts_start = time::ticks_to_ns(res.result.vertex_ts_start, hz);
+                    res.result.vertex_ts_end = time::ticks_to_ns(res.result.vertex_ts_end, hz);
+                }
+                res.result.tvb_usage_bytes = used_bytes;
+                if overflowed {
                     res.result.flags |= uapi::DRM_ASAHI_RESULT_RENDER_TVB_OVERFLOWED as u64;
+                    if no_grow {
+                        res.result.flags |=
+                            uapi::DRM_ASAHI_RESULT_RENDER_TVB_OVERFLOW_NO_GROW as u64;
+                    }
                 }
                 res.vtx_error = error;
                 res.vtx_complete = true;
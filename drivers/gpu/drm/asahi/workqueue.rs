@@ -38,6 +38,9 @@
 
 const MAX_JOB_SLOTS: u32 = 127;
 
+/// Number of work queue priorities supported by the firmware. Valid priorities are below this.
+pub(crate) const NUM_PRIORITIES: u32 = raw::PRIORITY.len() as u32;
+
 /// An enum of possible errors that might cause a piece of work to fail execution.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub(crate) enum WorkError {
@@ -157,6 +160,7 @@ pub(crate) trait GenSubmittedWork: Send + Sync {
     fn value(&self) -> event::EventValue;
     fn wptr(&self) -> u32;
     fn set_wptr(&mut self, wptr: u32);
+    fn vm_slot(&self) -> u32;
     fn mark_error(&mut self, error: WorkError);
     fn complete(&mut self);
     fn get_fence(&self) -> dma_fence::Fence;
@@ -181,6 +185,10 @@ fn set_wptr(&mut self, wptr: u32) {
         self.wptr = wptr;
     }
 
+    fn vm_slot(&self) -> u32 {
+        self.vm_slot
+    }
+
     fn complete(&mut self) {
         if let Some(cb) = self.callback.take() {
             cb(&mut self.object, self.error);
@@ -217,6 +225,7 @@ struct WorkQueueInner {
     last_completed: Option<event::EventValue>,
     event: Option<(event::Event, event::EventValue)>,
     priority: u32,
+    next_priority: Option<u32>,
     commit_seq: u64,
     submit_seq: u64,
     event_seq: u64,
@@ -638,6 +647,7 @@ pub(crate) fn new(
             last_token: None,
             event: None,
             priority,
+            next_priority: None,
             pending_jobs: 0,
             commit_seq: 0,
             submit_seq: 0,
@@ -674,10 +684,44 @@ pub(crate) fn event_info(&self) -> Option<QueueEventInfo::ver> {
         })
     }
 
+    /// Change the priority of this work queue.
+    ///
+    /// The firmware pipe a queue runs on cannot change while it has work outstanding, so the new
+    /// priority is applied when the next job is created on an idle work queue. Jobs created
+    /// before then keep the old priority.
+    pub(crate) fn set_priority(&self, priority: u32) -> Result {
+        if priority >= NUM_PRIORITIES {
+            return Err(EINVAL);
+        }
+
+        let mut inner = self.inner.lock();
+        inner.next_priority = if priority == inner.priority {
+            None
+        } else {
+            Some(priority)
+        };
+        Ok(())
+    }
+
     pub(crate) fn new_job(self: &Arc<Self>, fence: dma_fence::Fence) -> Result<Job::ver> {
         let mut inner = self.inner.lock();
 
         if inner.event.is_none() {
+            if let Some(priority) = inner.next_priority.take() {
+                mod_pr_debug!(
+                    "WorkQueue({:?}): Changing priority {} -> {}\n",
+                    inner.pipe_type,
+                    inner.priority,
+                    priority
+                );
+                inner
+                    .info
+                    .with_mut(|raw, _inner| raw.priority = raw::PRIORITY[priority as usize]);
+                inner.priority = priority;
+                // Have the firmware pick up the new queue info on the next submission.
+                inner.new = true;
+            }
+
             mod_pr_debug!("WorkQueue({:?}): Grabbing event\n", inner.pipe_type);
             let event = inner.event_manager.get(inner.last_token, self.clone())?;
             let cur = event.current();
@@ -729,6 +773,7 @@ pub(crate) trait WorkQueue {
     fn signal(&self) -> bool;
     fn mark_error(&self, value: event::EventValue, error: WorkError);
     fn fail_all(&self, error: WorkError);
+    fn has_vm_slot(&self, value: event::EventValue, vm_slot: u32) -> bool;
 }
 
 #[versions(AGX)]
@@ -858,6 +903,17 @@ fn mark_error(&self, value: event::EventValue, error: WorkError) {
         }
     }
 
+    /// Check whether any of this queue's pending work up to a certain stamp value belongs to the
+    /// given VM slot.
+    fn has_vm_slot(&self, value: event::EventValue, vm_slot: u32) -> bool {
+        self.inner
+            .lock()
+            .pending
+            .iter()
+            .take_while(|cmd| cmd.value() <= value)
+            .any(|cmd| cmd.vm_slot() == vm_slot)
+    }
+
     /// Mark all of this queue's work as having failed, and complete it.
     fn fail_all(&self, error: WorkError) {
         // If anything is marked completed, we can consider it successful
@@ -69,6 +69,52 @@ fn signal(&self) -> Result {
     fn set_error(&self, err: Error) {
         unsafe { bindings::dma_fence_set_error(self.raw(), err.to_errno()) };
     }
+
+    /// Returns whether this fence has already been signaled.
+    ///
+    /// A fence that is not signaled may become signaled at any time after this returns.
+    fn is_signaled(&self) -> bool {
+        // SAFETY: raw() returns a valid pointer per the trait invariant.
+        unsafe { bindings::dma_fence_is_signaled(self.raw()) }
+    }
+
+    /// Returns the fence context this fence belongs to.
+    fn context(&self) -> u64 {
+        // SAFETY: raw() returns a valid pointer per the trait invariant.
+        unsafe { (*self.raw()).context }
+    }
+
+    /// Returns the sequence number of this fence within its context.
+    fn seqno(&self) -> u64 {
+        // SAFETY: raw() returns a valid pointer per the trait invariant.
+        unsafe { (*self.raw()).seqno }
+    }
+
+    /// Appends the individual fences contained in this fence to `out`.
+    ///
+    /// `dma_fence_array` and `dma_fence_chain` containers are flattened recursively, following
+    /// `dma_fence_unwrap_for_each()`. Any other fence is appended as is.
+    fn unwrap_into(&self, out: &mut Vec<Fence>) -> Result {
+        let mut cursor = core::mem::MaybeUninit::<bindings::dma_fence_unwrap>::uninit();
+        let mut ret = Ok(());
+
+        // SAFETY: raw() returns a valid pointer per the trait invariant. The cursor is initialized
+        // by dma_fence_unwrap_first(), and holds its own reference to the chain node being walked,
+        // which is dropped once dma_fence_unwrap_next() returns NULL. The iteration is therefore
+        // always run to completion, even if pushing fails. The returned fences are borrowed from
+        // their container, so we take our own reference to each of them.
+        unsafe {
+            let mut fence = bindings::dma_fence_unwrap_first(self.raw(), cursor.as_mut_ptr());
+            while !fence.is_null() {
+                if ret.is_ok() {
+                    ret = out.try_push(Fence::get_raw(fence)).map_err(|_| ENOMEM);
+                }
+                fence = bindings::dma_fence_unwrap_next(cursor.as_mut_ptr());
+            }
+        }
+
+        ret
+    }
 }
 
 /// A generic DMA Fence Object
@@ -167,6 +213,13 @@ fn fence_value_str(self: &FenceObject<Self>, _output: &mut dyn Write) {}
     /// the specific fence passed to this function should not matter, drivers should only use it to
     /// look up the corresponding timeline structures.
     fn timeline_value_str(self: &FenceObject<Self>, _output: &mut dyn Write) {}
+
+    /// Notifies the driver that some consumer would like the fence to be signaled by `deadline`,
+    /// given as a `CLOCK_MONOTONIC` timestamp in nanoseconds.
+    ///
+    /// This may be called multiple times with different deadlines, in any order. It is only a
+    /// hint, so it is up to the driver to track which deadline matters.
+    fn set_deadline(self: &FenceObject<Self>, _deadline: i64) {}
 }
 
 unsafe extern "C" fn get_driver_name_cb<T: FenceOps>(
@@ -268,6 +321,17 @@ fn timeline_value_str(self: &FenceObject<Self>, _output: &mut dyn Write) {}
     unsafe { *string.add(size - 1) = 0 };
 }
 
+unsafe extern "C" fn set_deadline_cb<T: FenceOps>(
+    fence: *mut bindings::dma_fence,
+    deadline: bindings::ktime_t,
+) {
+    // SAFETY: All of our fences are FenceObject<T>.
+    let p = crate::container_of!(fence, FenceObject<T>, fence) as *mut FenceObject<T>;
+
+    // SAFETY: The caller is responsible for passing a valid dma_fence subtype
+    T::set_deadline(unsafe { &mut *p }, deadline);
+}
+
 // Allow FenceObject<Self> to be used as a self argument, for ergonomics
 impl<T: FenceOps> core::ops::Receiver for FenceObject<T> {}
 
@@ -311,7 +375,11 @@ impl<T: FenceOps> FenceObject<T> {
         } else {
             None
         },
-        set_deadline: None,
+        set_deadline: if T::HAS_SET_DEADLINE {
+            Some(set_deadline_cb::<T>)
+        } else {
+            None
+        },
     };
 }
 
@@ -23,6 +23,10 @@
 pub trait DriverObject: gem::BaseDriverObject<Object<Self>> {
     /// Parent `Driver` for this object.
     type Driver: drv::Driver;
+
+    /// Called when the last reference to an object is dropped, before its driver data is dropped
+    /// and its pages are freed.
+    fn free(_obj: &Object<Self>) {}
 }
 
 // FIXME: This is terrible and I don't know how to avoid it
@@ -120,6 +124,9 @@ unsafe impl init::Zeroable for bindings::drm_gem_shmem_object {}
         as *mut bindings::drm_gem_shmem_object;
     let p = crate::container_of!(shmem, Object<T>, obj) as *mut Object<T>;
 
+    // SAFETY: p is valid and not freed yet
+    T::free(unsafe { &*p });
+
     // SAFETY: p is never used after this
     unsafe {
         core::ptr::drop_in_place(&mut (*p).inner);
@@ -210,6 +217,47 @@ pub fn vmap(&self) -> Result<VMap<T>> {
         })
     }
 
+    /// Sets the purgeability of this object's pages (`madv` > 0 means purgeable).
+    ///
+    /// Returns `false` if the pages have already been purged, in which case the state no longer
+    /// changes.
+    pub fn madvise(&self, madv: i32) -> bool {
+        // SAFETY: drm_gem_shmem_madvise is thread-safe.
+        unsafe { bindings::drm_gem_shmem_madvise(self.mut_shmem(), madv) != 0 }
+    }
+
+    /// Returns whether this object was imported from a dma-buf exported by another device.
+    ///
+    /// The pages of imported objects belong to the exporter. Kernel mappings of them go through
+    /// the exporter, which may not support them.
+    pub fn is_imported(&self) -> bool {
+        !self.obj.base.import_attach.is_null()
+    }
+
+    /// Returns whether this object's pages can be purged (like drm_gem_shmem_is_purgeable()).
+    ///
+    /// This is only a snapshot. Callers must ensure that no new kernel mappings or scatter-gather
+    /// tables are created before the object is purged.
+    pub fn is_purgeable(&self) -> bool {
+        let shmem = &self.obj;
+
+        shmem.madv > 0
+            && shmem.vmap_use_count == 0
+            && !shmem.sgt.is_null()
+            && shmem.base.dma_buf.is_null()
+            && shmem.base.import_attach.is_null()
+    }
+
+    /// Frees this object's pages, which must be purgeable (see `is_purgeable()`).
+    ///
+    /// Any existing scatter-gather table becomes invalid. Returns `false` if the pages lock was
+    /// contended and nothing was purged.
+    pub fn purge(&self) -> bool {
+        // SAFETY: drm_gem_shmem_purge is thread-safe and only trylocks the pages lock, so it is
+        // safe to call from memory reclaim.
+        unsafe { bindings::drm_gem_shmem_purge(self.mut_shmem()) }
+    }
+
     /// Set the write-combine flag for this object.
     ///
     /// Should be called before any mappings are made.
@@ -283,6 +283,30 @@ pub fn with_inner<RetVal>(&self, cb: impl FnOnce(&mut A) -> RetVal) -> RetVal {
         let mut guard = self.mm.lock();
         cb(&mut guard.1)
     }
+
+    /// Operate on the user `T` type data of the node containing the given address, taking the
+    /// allocator lock.
+    ///
+    /// `cb` is passed `None` if no node contains `addr`.
+    pub fn with_node_at<RetVal>(&self, addr: u64, cb: impl FnOnce(Option<&T>) -> RetVal) -> RetVal {
+        let guard = self.mm.lock();
+        let mm = guard.0.get();
+
+        // SAFETY: We hold the lock and the drm_mm is valid.
+        let node = unsafe { bindings::__drm_mm_interval_first(mm, addr, addr) };
+        // SAFETY: The drm_mm is valid, and this just computes a field address.
+        let head = unsafe { core::ptr::addr_of_mut!((*mm).head_node) };
+
+        // __drm_mm_interval_first() returns the head node if there is no match.
+        if node.is_null() || node == head {
+            return cb(None);
+        }
+
+        // SAFETY: All nodes in this allocator are embedded in a `NodeData<A, T>`, and they cannot
+        // be removed (and freed) while we hold the lock.
+        let data = unsafe { &*crate::container_of!(node, NodeData<A, T>, node) };
+        cb(Some(&data.inner))
+    }
 }
 
 impl<A: AllocInner<T>, T> Drop for MmInner<A, T> {
@@ -10,6 +10,7 @@
     error::{to_result, Result},
     prelude::*,
     sync::{Arc, UniqueArc},
+    types::Opaque,
 };
 use alloc::boxed::Box;
 use core::marker::PhantomData;
@@ -238,7 +239,7 @@ impl<T: JobImpl> Entity<T> {
     pub fn new(sched: &Scheduler<T>, priority: Priority) -> Result<Self> {
         let mut entity: Box<MaybeUninit<EntityInner<T>>> = Box::try_new_zeroed()?;
 
-        let mut sched_ptr = &sched.0.sched as *const _ as *mut _;
+        let mut sched_ptr = sched.0.sched.get();
 
         // SAFETY: The Box is allocated above and valid.
         unsafe {
@@ -285,7 +286,7 @@ pub fn new_job(&self, inner: T) -> Result<PendingJob<'_, T>> {
 
 /// DRM scheduler inner data
 pub struct SchedulerInner<T: JobImpl> {
-    sched: bindings::drm_gpu_scheduler,
+    sched: Opaque<bindings::drm_gpu_scheduler>,
     _p: PhantomData<T>,
 }
 
@@ -293,7 +294,7 @@ impl<T: JobImpl> Drop for SchedulerInner<T> {
     fn drop(&mut self) {
         // SAFETY: The scheduler is valid. This assumes drm_sched_fini() will take care of
         // freeing all in-progress jobs.
-        unsafe { bindings::drm_sched_fini(&mut self.sched) };
+        unsafe { bindings::drm_sched_fini(self.sched.get()) };
     }
 }
 
@@ -325,7 +326,7 @@ pub fn new(
         // SAFETY: The drm_sched pointer is valid and pinned as it was just allocated above.
         to_result(unsafe {
             bindings::drm_sched_init(
-                addr_of_mut!((*sched.as_mut_ptr()).sched),
+                Opaque::raw_get(addr_of_mut!((*sched.as_mut_ptr()).sched)),
                 &Self::OPS,
                 hw_submission,
                 hang_limit,
@@ -340,4 +341,22 @@ pub fn new(
         // SAFETY: All fields of SchedulerInner are now initialized.
         Ok(Scheduler(unsafe { sched.assume_init() }.into()))
     }
+
+    /// Changes the job timeout.
+    ///
+    /// The new timeout takes effect the next time the timeout timer is armed, which happens when
+    /// the running job completes or a job is started on an idle scheduler. A job that is already
+    /// being timed keeps its current deadline.
+    pub fn set_timeout(&self, timeout_ms: usize) -> Result {
+        // SAFETY: msecs_to_jiffies() has no preconditions.
+        let jiffies = unsafe { bindings::msecs_to_jiffies(timeout_ms.try_into()?) };
+        let timeout: core::ffi::c_long = jiffies.try_into()?;
+
+        // SAFETY: The scheduler is valid, and it lives in an `Opaque` so it may be mutated through
+        // a shared reference. The C scheduler reads `timeout` locklessly when arming the timer, so
+        // a single-copy atomic store is all that is required (like WRITE_ONCE()).
+        unsafe { core::ptr::write_volatile(addr_of_mut!((*self.0.sched.get()).timeout), timeout) };
+
+        Ok(())
+    }
 }
@@ -0,0 +1,130 @@
+// SPDX-License-Identifier: GPL-2.0
+
+//! Memory shrinkers
+//!
+//! C header: [`include/linux/shrinker.h`](../../../../include/linux/shrinker.h)
+
+use crate::{bindings, error::to_result, prelude::*, str::CStr, types::Opaque};
+
+use core::marker::PhantomPinned;
+
+/// Returned by `scan_objects` when no progress can be made right now (`SHRINK_STOP`).
+const SHRINK_STOP: core::ffi::c_ulong = !0;
+/// Returned by `count_objects` when there is nothing to free (`SHRINK_EMPTY`).
+const SHRINK_EMPTY: core::ffi::c_ulong = !0 - 1;
+
+/// Wraps the kernel's `struct shrink_control`, passed to shrinker callbacks.
+pub struct ShrinkControl(*mut bindings::shrink_control);
+
+impl ShrinkControl {
+    /// Returns the number of objects the core would like to have scanned.
+    pub fn nr_to_scan(&self) -> usize {
+        // SAFETY: The pointer is valid for the duration of the callback.
+        unsafe { (*self.0).nr_to_scan as usize }
+    }
+
+    /// Sets the number of objects that were actually scanned.
+    pub fn set_nr_scanned(&mut self, nr: usize) {
+        // SAFETY: The pointer is valid for the duration of the callback.
+        unsafe { (*self.0).nr_scanned = nr as _ };
+    }
+}
+
+/// Callbacks for a cache of objects that can be freed under memory pressure.
+///
+/// Both callbacks are called from memory reclaim, so they must not allocate memory or take any
+/// locks that are held while allocating memory elsewhere.
+pub trait Shrinker: Send + Sync {
+    /// Returns the number of objects that could be freed right now.
+    fn count(&self, sc: &ShrinkControl) -> usize;
+
+    /// Tries to free up to `sc.nr_to_scan()` objects.
+    ///
+    /// Returns the number of objects that were freed, or `None` if no progress can be made without
+    /// risking a deadlock.
+    fn scan(&self, sc: &mut ShrinkControl) -> Option<usize>;
+}
+
+/// A registered shrinker.
+///
+/// The shrinker is unregistered when this object is dropped.
+pub struct Registration<T: Shrinker> {
+    shrinker: Opaque<bindings::shrinker>,
+    inner: T,
+    _pin: PhantomPinned,
+}
+
+unsafe extern "C" fn count_objects_cb<T: Shrinker>(
+    shrinker: *mut bindings::shrinker,
+    sc: *mut bindings::shrink_control,
+) -> core::ffi::c_ulong {
+    // SAFETY: All shrinkers registered with this callback are embedded in a `Registration<T>`.
+    // `Opaque` is `repr(transparent)`, so the field has the same address as its contents.
+    let reg = unsafe { &*(crate::container_of!(shrinker, Registration<T>, shrinker)) };
+
+    match reg.inner.count(&ShrinkControl(sc)) {
+        0 => SHRINK_EMPTY,
+        count => count as core::ffi::c_ulong,
+    }
+}
+
+unsafe extern "C" fn scan_objects_cb<T: Shrinker>(
+    shrinker: *mut bindings::shrinker,
+    sc: *mut bindings::shrink_control,
+) -> core::ffi::c_ulong {
+    // SAFETY: All shrinkers registered with this callback are embedded in a `Registration<T>`.
+    let reg = unsafe { &*(crate::container_of!(shrinker, Registration<T>, shrinker)) };
+
+    match reg.inner.scan(&mut ShrinkControl(sc)) {
+        Some(freed) => freed as core::ffi::c_ulong,
+        None => SHRINK_STOP,
+    }
+}
+
+impl<T: Shrinker> Registration<T> {
+    /// Registers a new shrinker with the given name, wrapping `inner`.
+    pub fn new(inner: T, name: &'static CStr) -> Result<Pin<Box<Self>>> {
+        let reg = Pin::from(Box::try_new(Self {
+            shrinker: Opaque::zeroed(),
+            inner,
+            _pin: PhantomPinned,
+        })?);
+
+        let shrinker = reg.shrinker.get();
+
+        // SAFETY: The shrinker is zero-initialized and not registered yet, so nothing else can
+        // access it. It is pinned, so its address stays valid until it is unregistered on drop.
+        unsafe {
+            (*shrinker).count_objects = Some(count_objects_cb::<T>);
+            (*shrinker).scan_objects = Some(scan_objects_cb::<T>);
+            (*shrinker).seeks = bindings::DEFAULT_SEEKS as _;
+
+            to_result(bindings::register_shrinker(
+                shrinker,
+                crate::c_str!("%s").as_char_ptr(),
+                name.as_char_ptr(),
+            ))?;
+        }
+
+        Ok(reg)
+    }
+
+    /// Returns the wrapped object.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: Shrinker> Drop for Registration<T> {
+    fn drop(&mut self) {
+        // SAFETY: The shrinker was initialized in `new()`. unregister_shrinker() ignores
+        // shrinkers that failed to register, and waits for any running callbacks to complete.
+        unsafe { bindings::unregister_shrinker(self.shrinker.get()) };
+    }
+}
+
+// SAFETY: The shrinker is only accessed by the C side through its own locking, and `T` is
+// required to be `Send + Sync`.
+unsafe impl<T: Shrinker> Send for Registration<T> {}
+// SAFETY: See above.
+unsafe impl<T: Shrinker> Sync for Registration<T> {}
@@ -223,6 +223,12 @@ pub fn boot(&mut self) -> Result {
         to_result(unsafe { bindings::apple_rtkit_boot(self.rtk) })
     }
 
+    /// Shuts down the RTKit coprocessor, waiting for it to acknowledge the power state change.
+    pub fn shutdown(&mut self) -> Result {
+        // SAFETY: `rtk` is valid per the type invariant.
+        to_result(unsafe { bindings::apple_rtkit_shutdown(self.rtk) })
+    }
+
     /// Starts a non-system endpoint.
     pub fn start_endpoint(&mut self, endpoint: u8) -> Result {
         // SAFETY: `rtk` is valid per the type invariant.
@@ -187,6 +187,22 @@ pub fn lock(&self) -> Guard<'_, T, B> {
     }
 }
 
+impl<T: ?Sized> Lock<T, mutex::MutexBackend> {
+    /// Tries to acquire the lock without blocking.
+    ///
+    /// Returns `None` if the lock is currently held.
+    pub fn try_lock(&self) -> Option<Guard<'_, T, mutex::MutexBackend>> {
+        // SAFETY: The constructor of the type calls `init`, so the existence of the object proves
+        // that `init` was called.
+        if unsafe { bindings::mutex_trylock(self.state.get()) } == 0 {
+            return None;
+        }
+
+        // SAFETY: The lock was just acquired.
+        Some(unsafe { Guard::new(self, ()) })
+    }
+}
+
 /// A lock guard.
 ///
 /// Allows mutual exclusion primitives that implement the `Backend` trait to automatically unlock
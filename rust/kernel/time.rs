@@ -9,6 +9,38 @@
 use core::marker::PhantomData;
 use core::time::Duration;
 
+/// The number of nanoseconds per second.
+pub const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+/// Converts a count of ticks of a clock running at `clock_hz` to nanoseconds.
+///
+/// The whole seconds and the remainder are scaled separately, so the intermediate results fit in
+/// 64 bits. Results that do not fit in a `u64` saturate at `u64::MAX`. A `clock_hz` of zero
+/// returns `ticks` unchanged.
+///
+/// # Examples
+///
+/// ```
+/// use kernel::time::ticks_to_ns;
+///
+/// assert_eq!(ticks_to_ns(24_000_000, 24_000_000), 1_000_000_000);
+/// assert_eq!(ticks_to_ns(3, 24_000_000), 125);
+/// assert_eq!(ticks_to_ns(u64::MAX, 1), u64::MAX);
+/// ```
+pub fn ticks_to_ns(ticks: u64, clock_hz: u32) -> u64 {
+    let hz = clock_hz as u64;
+
+    if hz == 0 {
+        return ticks;
+    }
+
+    // `ticks % hz < 2^32`, so the product is less than `2^32 * 10^9 < 2^64`.
+    let frac = (ticks % hz) * NSEC_PER_SEC / hz;
+    (ticks / hz)
+        .saturating_mul(NSEC_PER_SEC)
+        .saturating_add(frac)
+}
+
 /// Represents a clock, that is, a unique time source.
 pub trait Clock: Sized {}
 
@@ -148,3 +180,48 @@ fn now() -> Instant<Self> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ticks_to_ns, NSEC_PER_SEC};
+
+    #[test]
+    fn test_ticks_to_ns_examples() {
+        // Keep these in sync with `ticks_to_ns`'s `# Examples` section.
+
+        assert_eq!(ticks_to_ns(24_000_000, 24_000_000), NSEC_PER_SEC);
+        assert_eq!(ticks_to_ns(3, 24_000_000), 125);
+        assert_eq!(ticks_to_ns(u64::MAX, 1), u64::MAX);
+    }
+
+    #[test]
+    fn test_ticks_to_ns_edges() {
+        assert_eq!(ticks_to_ns(0, 24_000_000), 0);
+        assert_eq!(ticks_to_ns(1234, 0), 1234);
+        assert_eq!(ticks_to_ns(1, u32::MAX), 0);
+        assert_eq!(ticks_to_ns(u64::MAX, u32::MAX), 4_294_967_297_000_000_000);
+        assert_eq!(
+            ticks_to_ns(u64::MAX - 1, u32::MAX),
+            4_294_967_296_999_999_999
+        );
+    }
+
+    #[test]
+    fn test_ticks_to_ns_saturates() {
+        let limit = u64::MAX / NSEC_PER_SEC;
+        assert_eq!(ticks_to_ns(limit + 1, 1), u64::MAX);
+        assert_eq!(ticks_to_ns(u64::MAX, 1000), u64::MAX);
+        assert_eq!(ticks_to_ns(limit, 1), limit * NSEC_PER_SEC);
+    }
+
+    #[test]
+    fn test_ticks_to_ns_monotonic() {
+        let hz = 24_000_000;
+        let mut last = 0;
+        for ticks in (0..u64::MAX).step_by(usize::MAX / 4096) {
+            let ns = ticks_to_ns(ticks, hz);
+            assert!(ns >= last);
+            last = ns;
+        }
+    }
+}
@@ -0,0 +1,106 @@
+// SPDX-License-Identifier: GPL-2.0
+
+//! Work queues
+//!
+//! C header: [`include/linux/workqueue.h`](../../../../include/linux/workqueue.h)
+
+use crate::{bindings, init::PinnedDrop, prelude::*, sync::LockClassKey, types::Opaque};
+
+use core::convert::Infallible;
+use core::marker::PhantomPinned;
+
+/// Callback for a [`Work`] item.
+pub trait WorkItem: Send + Sync {
+    /// Runs the work. This is called in process context, so it may sleep.
+    fn run(&self);
+}
+
+/// A work item that runs [`WorkItem::run()`] on the system work queue.
+///
+/// Queueing a work item that is already pending does nothing, so `run()` is called at least once
+/// after each call to [`Work::queue()`]. Dropping a `Work` cancels it and waits for it to finish
+/// running, so `run()` must never drop the last reference to its own `Work`.
+#[pin_data(PinnedDrop)]
+pub struct Work<T: WorkItem> {
+    #[pin]
+    work: Opaque<bindings::work_struct>,
+    #[pin]
+    inner: T,
+    #[pin]
+    _pin: PhantomPinned,
+}
+
+unsafe extern "C" fn work_func_cb<T: WorkItem>(work: *mut bindings::work_struct) {
+    // SAFETY: All work items initialized with this callback are embedded in a `Work<T>`.
+    // `Opaque` is `repr(transparent)`, so the field has the same address as its contents.
+    let this = unsafe { &*(crate::container_of!(work, Work<T>, work)) };
+
+    this.inner.run();
+}
+
+impl<T: WorkItem> Work<T> {
+    /// Constructs a new work item initialiser, wrapping `inner`.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new<E: From<Infallible>>(
+        inner: impl PinInit<T, E>,
+        name: &'static CStr,
+        key: LockClassKey,
+    ) -> impl PinInit<Self, E> {
+        try_pin_init!(Self {
+            // SAFETY: `slot` is valid while the closure is called and both `name` and `key` have
+            // static lifetimes so they live indefinitely.
+            work <- Opaque::ffi_init(|slot| unsafe {
+                bindings::init_work_with_key(
+                    slot,
+                    Some(work_func_cb::<T>),
+                    false,
+                    name.as_char_ptr(),
+                    key.as_ptr(),
+                )
+            }),
+            inner <- inner,
+            _pin: PhantomPinned,
+        }? E)
+    }
+
+    /// Queues the work on the system work queue.
+    ///
+    /// Returns `false` if the work was already pending.
+    pub fn queue(&self) -> bool {
+        // SAFETY: The work was initialized in `new()`, and it is pinned, so its address stays
+        // valid until it is cancelled on drop.
+        unsafe {
+            bindings::queue_work_on(
+                bindings::WORK_CPU_UNBOUND as _,
+                bindings::system_wq,
+                self.work.get(),
+            )
+        }
+    }
+
+    /// Cancels the work if it is pending, and waits for it to finish if it is running.
+    ///
+    /// The work may be queued again afterwards. Returns `true` if the work was pending.
+    pub fn cancel_sync(&self) -> bool {
+        // SAFETY: The work was initialized in `new()`.
+        unsafe { bindings::cancel_work_sync(self.work.get()) }
+    }
+
+    /// Returns the wrapped object.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+}
+
+#[pinned_drop]
+impl<T: WorkItem> PinnedDrop for Work<T> {
+    fn drop(self: Pin<&mut Self>) {
+        self.cancel_sync();
+    }
+}
+
+// SAFETY: The work is only accessed by the C side through its own locking, and `T` is required
+// to be `Send + Sync`.
+unsafe impl<T: WorkItem> Send for Work<T> {}
+// SAFETY: See above.
+unsafe impl<T: WorkItem> Sync for Work<T> {}
@@ -58,6 +58,73 @@ fn drop(&mut self) {
     }
 }
 
+/// A cursor over the entries of an `XArray` within an index range, in ascending index order.
+///
+/// The `XArray` lock is held for the whole lifetime of the cursor, so entries cannot be added or
+/// removed while it exists. The lock is released when the cursor is dropped, which may happen at
+/// any point of the iteration.
+pub struct Range<'a, T: ForeignOwnable> {
+    xa: Pin<&'a XArray<T>>,
+    /// Index of the last entry returned, or the start of the range before the first lookup.
+    index: core::ffi::c_ulong,
+    /// Last index in the range (inclusive), as expected by `xa_find()`.
+    last: core::ffi::c_ulong,
+    started: bool,
+    done: bool,
+}
+
+impl<'a, T: ForeignOwnable> Range<'a, T> {
+    /// Returns the next entry in the range along with its index, or `None` once the range is
+    /// exhausted.
+    ///
+    /// The returned reference borrows the cursor, since it is only valid while the lock is held.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<(usize, T::Borrowed<'_>)> {
+        if self.done {
+            return None;
+        }
+
+        let xa = self.xa.xa.get();
+        // SAFETY: `xa` is valid by the type invariant of `XArray`, and we hold its lock.
+        let p = unsafe {
+            if self.started {
+                bindings::xa_find_after(
+                    xa,
+                    &mut self.index,
+                    self.last,
+                    bindings::BINDINGS_XA_PRESENT,
+                )
+            } else {
+                self.started = true;
+                bindings::xa_find(
+                    xa,
+                    &mut self.index,
+                    self.last,
+                    bindings::BINDINGS_XA_PRESENT,
+                )
+            }
+        };
+
+        if p.is_null() {
+            self.done = true;
+            return None;
+        }
+
+        // SAFETY: Non-NULL entries are always valid `ForeignOwnable` pointers (see `replace()`),
+        // and the entry cannot be removed while we hold the lock, which the borrow of `self`
+        // outlives.
+        Some((self.index as usize, unsafe { T::borrow(p) }))
+    }
+}
+
+impl<'a, T: ForeignOwnable> Drop for Range<'a, T> {
+    fn drop(&mut self) {
+        // SAFETY: The XArray we have a reference to owns the C xarray object, and we locked it in
+        // `XArray::range()`.
+        unsafe { bindings::xa_unlock(self.xa.xa.get()) };
+    }
+}
+
 /// Represents a reserved slot in an `XArray`, which does not yet have a value but has an assigned
 /// index and may not be allocated by any other user. If the Reservation is dropped without
 /// being filled, the entry is marked as available again.
@@ -185,6 +252,44 @@ pub fn get(self: Pin<&Self>, index: usize) -> Option<Guard<'_, T>> {
         })
     }
 
+    /// Returns a cursor over the entries with indices in `[start, end)`, in ascending order.
+    ///
+    /// Like a `Guard`, the cursor blocks all other actions on the `XArray` until it is dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use kernel::{prelude::*, xarray::{flags, XArray}};
+    /// # fn test() -> Result {
+    /// let xa = Box::pin(XArray::<Box<u32>>::new(flags::ALLOC));
+    /// for index in [3, 10, 7, 200] {
+    ///     xa.as_ref().set(index, Box::try_new(index as u32)?)?;
+    /// }
+    ///
+    /// let mut found = Vec::new();
+    /// let mut range = xa.as_ref().range(5, 200);
+    /// while let Some((index, value)) = range.next() {
+    ///     assert_eq!(index as u32, *value);
+    ///     found.try_push(index)?;
+    /// }
+    /// assert_eq!(found, [7, 10]);
+    /// # Ok(())
+    /// # }
+    /// # assert!(test().is_ok());
+    /// ```
+    pub fn range(self: Pin<&Self>, start: usize, end: usize) -> Range<'_, T> {
+        // SAFETY: `self.xa` is always valid by the type invariant.
+        unsafe { bindings::xa_lock(self.xa.get()) };
+
+        Range {
+            xa: self,
+            index: start as _,
+            last: end.saturating_sub(1) as _,
+            started: false,
+            done: start >= end,
+        }
+    }
+
     /// Removes and returns an entry, returning it if it existed.
     pub fn remove(self: Pin<&Self>, index: usize) -> Option<T> {
         let p = unsafe { bindings::xa_erase(self.xa.get(), index.try_into().ok()?) };